@@ -2,6 +2,7 @@
 #![allow(named_arguments_used_positionally)]
 
 extern crate hyper_native_tls;
+extern crate constant_time_eq;
 extern crate percent_encoding;
 extern crate trivial_colours;
 extern crate serde_json;
@@ -9,13 +10,18 @@ extern crate mime_guess;
 extern crate tabwriter;
 extern crate arrayvec;
 extern crate walkdir;
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+extern crate openssl;
 extern crate blake3;
 extern crate brotli;
 extern crate flate2;
 extern crate rfsapi;
+extern crate pwhash;
+extern crate argon2;
 #[cfg(target_os = "windows")]
 extern crate winapi;
 extern crate ctrlc;
+extern crate hyper;
 extern crate serde;
 extern crate cidr;
 #[macro_use]
@@ -23,6 +29,8 @@ extern crate clap;
 extern crate iron;
 extern crate libc;
 extern crate time;
+extern crate zstd;
+extern crate toml;
 extern crate xml;
 
 mod options;
@@ -31,17 +39,15 @@ pub mod ops;
 pub mod util;
 
 pub struct Error(pub String);
-pub use options::{LogLevel, Options};
+pub use options::{LogLevel, Options, Listen, ListenAddress, Htpasswd, Mount, VHost, ProxySource};
 
-use std::mem;
 use libc::exit;
-use iron::Iron;
-use std::net::IpAddr;
+use std::net::{SocketAddr, IpAddr};
 use std::time::Duration;
+use std::thread;
 use tabwriter::TabWriter;
 use std::io::{Write, stdout};
 use std::sync::{Mutex, Condvar};
-use hyper_native_tls::NativeTlsServer;
 
 
 fn main() {
@@ -60,55 +66,113 @@ fn actual_main() -> i32 {
 
 fn result_main() -> Result<(), Error> {
     let mut opts = Options::parse();
+    if opts.print_config {
+        print!("{}", opts.config_toml());
+        return Ok(());
+    }
     if opts.generate_tls {
         opts.tls_data = Some(ops::generate_tls_data(&opts.temp_directory)?);
     }
-    for path in mem::take(&mut opts.generate_path_auth) {
-        opts.path_auth_data.insert(path, Some(ops::generate_auth_data()));
+    for path in &opts.generate_path_auth {
+        opts.path_auth_data.insert(path.clone(), vec![ops::generate_auth_data()]);
     }
 
+    let served_opts = opts.chrooted().map_err(|err| Error(format!("Chrooting: {}", err)))?;
+
     let handler: &_ = Box::leak(Box::new(ops::SimpleChain::<ops::PruneChain, _> {
-        handler: ops::PruneChain::new(&opts),
-        after: opts.request_bandwidth.map(ops::LimitBandwidthMiddleware::new),
+        handler: ops::PruneChain::new(&served_opts),
+        after: Some(ops::LimitBandwidthMiddleware::new(opts.request_bandwidth)),
     }));
-    let mut responder = if let Some(p) = opts.port {
-        if let Some(&((_, ref id), ref pw)) = opts.tls_data.as_ref() {
-                Iron::new(handler).https((opts.bind_address, p),
-                                         NativeTlsServer::new(id, pw).map_err(|err| Error(format!("Opening TLS certificate: {}", err)))?)
-            } else {
-                Iron::new(handler).http((opts.bind_address, p))
-            }
-            .map_err(|_| Error(format!("Starting server: port taken")))
-    } else {
-        ops::try_ports(handler, opts.bind_address, util::PORT_SCAN_LOWEST, util::PORT_SCAN_HIGHEST, &opts.tls_data)
-    }?;
+    if let Some(audit_log) = opts.audit_log.as_ref() {
+        // Opened now, before chrooting, so the path's the same as given
+        let audit_log = ops::AuditLog::open(audit_log).map_err(|err| Error(format!("Opening audit log: {}", err)))?;
+        let _ = handler.handler.handler.audit_log.set(audit_log);
+    }
+    let tls = match opts.tls_data.as_ref() {
+        Some(&((_, ref id), ref pw)) => {
+            let hosts = opts.vhosts.iter().flat_map(|(host, vhost)| vhost.tls_data.as_ref().map(|((_, id), pw)| (&host[..], id.as_path(), &pw[..])));
+            Some(ops::tls_server(id, pw, hosts).map_err(|err| Error(format!("Opening TLS certificate: {}", err)))?)
+        }
+        None => None,
+    };
+    let activated = util::activated_sockets().map_err(|err| Error(format!("Taking activated sockets: {}", err)))?;
+    let mut sockets = activated.into_iter()
+        .map(|(socket, name)| ops::bind_activated(socket, name.as_deref(), &tls))
+        .chain(opts.listen.iter().map(|l| ops::bind(l, &tls)))
+        .collect::<Result<Vec<_>, _>>()?;
+    let just_port = sockets.is_empty();
+    if just_port {
+        let socket = if let Some(p) = opts.port {
+            ops::bind_port(SocketAddr::new(opts.bind_address, p), &tls).map_err(|_| Error(format!("Starting server: port taken")))
+        } else {
+            ops::try_ports(opts.bind_address, opts.port_range.0, opts.port_range.1, &tls)
+        }?;
+        sockets.push(socket);
+    }
+
+    // Nothing's served until the handler's paths, which are relative to the chroot() directory, resolve under it, and it's not running as root
+    util::drop_privileges(opts.user.as_deref(), opts.group.as_deref(), opts.chroot.as_ref().map(|r| r.1.as_path()), &served_opts.temp_directory.1)
+        .map_err(|err| Error(format!("Dropping privileges: {}", err)))?;
+    if util::running_as_root() && !opts.allow_root {
+        return Err(Error("Refusing to serve as root: pass --user to switch to another user, or --allow-root".to_string()));
+    }
+    handler.handler.handler.load_encoded_index();
+    let responders = sockets.into_iter()
+        .map(|(socket, desc)| socket.serve(handler).map(|responder| (responder, desc.clone())).map_err(|err| Error(format!("Starting server on {}: {}", desc, err))))
+        .collect::<Result<Vec<_>, _>>()?;
+    let opts = served_opts;
 
     if opts.loglevel < options::LogLevel::NoStartup {
         if opts.log_colour {
             print!("{}", trivial_colours::Reset);
         }
-        print!("Hosting \"{}\" on port {}", opts.hosted_directory.0, responder.socket.port());
-        if responder.socket.ip() != IpAddr::from([0, 0, 0, 0]) {
-            print!(" under address {}", responder.socket.ip());
+        if just_port {
+            print!("Hosting \"{}\" on port {}", opts.hosted_directory.0, responders[0].0.socket.port());
+            if responders[0].0.socket.ip() != IpAddr::from([0, 0, 0, 0]) {
+                print!(" under address {}", responders[0].0.socket.ip());
+            }
+        } else {
+            print!("Hosting \"{}\" on {}", opts.hosted_directory.0, util::CommaList(responders.iter().map(|(_, desc)| desc)));
         }
         print!(" with");
-        if let Some(&((ref id, _), _)) = opts.tls_data.as_ref() {
+        if let Some(&((ref id, _), _)) = opts.tls_data.as_ref().filter(|_| responders.iter().any(|(_, desc)| desc.starts_with("https://"))) {
             print!(" TLS certificate from \"{}\"", id);
         } else {
             print!("out TLS");
         }
-        if !opts.path_auth_data.is_empty() {
-            print!(" and basic authentication");
+        if !opts.path_auth_data.is_empty() || !opts.htpasswd.is_empty() {
+            print!(" and basic authentication{}", if opts.auth_writes_only { " for changes" } else { "" });
         } else {
             print!(" and no authentication");
         }
         println!("...");
 
+        for mount in &opts.mounts {
+            println!("Also hosting \"{}\" under /{} ({}, {}listings{}).",
+                     mount.directory.0,
+                     mount.prefix,
+                     if mount.allow_writes { "read-write" } else { "read-only" },
+                     if mount.generate_listings { "" } else { "no " },
+                     if mount.sandbox_symlinks { ", sandboxed symlinks" } else { "" });
+        }
+
+        for (host, vhost) in &opts.vhosts {
+            print!("Also hosting \"{}\" for {}", vhost.hosted_directory.0, host);
+            if let Some(&((ref id, _), _)) = vhost.tls_data.as_ref() {
+                print!(" with TLS certificate from \"{}\"", id);
+            }
+            println!(".");
+        }
+
         if let Some(band) = opts.request_bandwidth {
             println!("Requests limited to {}B/s.", band);
         }
 
-        for (ext, mime_type) in opts.mime_type_overrides {
+        if let Some(audit_log) = opts.audit_log.as_ref() {
+            println!("Recording authentication in \"{}\".", audit_log.display());
+        }
+
+        for (ext, mime_type) in &opts.mime_type_overrides {
             match &ext.to_string_lossy()[..] {
                 "" => println!("Serving files with no extension as {}.", mime_type),
                 ext => println!("Serving files with .{} extension as {}.", ext, mime_type),
@@ -129,25 +193,46 @@ fn result_main() -> Result<(), Error> {
             }
             out.flush().unwrap();
         }
+
+        if !opts.path_permissions.is_empty() || opts.vhosts.values().any(|v| !v.path_permissions.is_empty()) {
+            println!("Permissions:");
+
+            let mut out = TabWriter::new(stdout());
+            writeln!(out, "Path\tUsername\tPermissions").unwrap();
+            let vhost_perms = opts.vhosts.iter().flat_map(|(host, v)| v.path_permissions.iter().map(move |(path, users)| (&host[..], path, users)));
+            for (host, path, users) in opts.path_permissions.iter().map(|(path, users)| ("", path, users)).chain(vhost_perms) {
+                for (user, perms) in users {
+                    writeln!(out, "{}/{}\t{}\t{}", host, path, user, perms).unwrap();
+                }
+            }
+            out.flush().unwrap();
+        }
     }
-    if !opts.path_auth_data.is_empty() && opts.loglevel < options::LogLevel::NoAuth {
+    if (!opts.path_auth_data.is_empty() || !opts.htpasswd.is_empty() || opts.vhosts.values().any(|v| !v.path_auth_data.is_empty() || !v.htpasswd.is_empty())) &&
+       opts.loglevel < options::LogLevel::NoAuth {
         println!("Basic authentication credentials:");
 
         let mut out = TabWriter::new(stdout());
         writeln!(out, "Path\tUsername\tPassword").unwrap();
 
-        for (path, creds) in &opts.path_auth_data {
-            if let Some(ad) = creds {
+        let vhost_creds = opts.vhosts.iter().flat_map(|(host, v)| v.path_auth_data.iter().map(move |(path, creds)| (&host[..], path, creds)));
+        for (host, path, creds) in opts.path_auth_data.iter().map(|(path, creds)| ("", path, creds)).chain(vhost_creds) {
+            for ad in creds {
                 let mut itr = ad.split(':');
-                write!(out, "/{}\t{}\t", path, itr.next().unwrap()).unwrap();
+                write!(out, "{}/{}\t{}\t", host, path, itr.next().unwrap()).unwrap();
                 if let Some(p) = itr.next() {
-                    write!(out, "{}", p).unwrap();
+                    write!(out, "{}", if util::is_password_hash(p) { "(hashed)" } else { p }).unwrap();
                 }
                 writeln!(out).unwrap();
-            } else {
-                writeln!(out, "/{}\t\t", path).unwrap();
+            }
+            if creds.is_empty() {
+                writeln!(out, "{}/{}\t\t", host, path).unwrap();
             }
         }
+        let vhost_htpasswd = opts.vhosts.iter().flat_map(|(host, v)| v.htpasswd.iter().map(move |(path, htp)| (&host[..], path, htp)));
+        for (host, path, ((file, _), accounts)) in opts.htpasswd.iter().map(|(path, htp)| ("", path, htp)).chain(vhost_htpasswd) {
+            writeln!(out, "{}/{}\t{} user{}\tfrom \"{}\"", host, path, accounts.len(), if accounts.len() == 1 { "" } else { "s" }, file).unwrap();
+        }
 
         out.flush().unwrap();
     }
@@ -155,10 +240,39 @@ fn result_main() -> Result<(), Error> {
         println!("Ctrl-C to stop.");
         println!();
     }
-    let Options { encoded_prune: opts_encoded_prune, temp_directory: opts_temp_directory, generate_tls: opts_generate_tls, .. } = opts;
+    handler.handler.prune();
+    handler.handler.handler.start_encoders();
+    handler.handler.handler.start_watcher();
+    let mut live_opts = opts.clone();
+    util::on_sighup(move || handler.handler.handler.reload(&mut live_opts, handler.after.as_ref().unwrap()))
+        .map_err(|err| Error(format!("Setting up SIGHUP handler: {}", err)))?;
+    let Options { encoded_prune: opts_encoded_prune,
+                  temp_directory: opts_temp_directory,
+                  generate_tls: opts_generate_tls,
+                  listen: opts_listen,
+                  shutdown_timeout: opts_shutdown_timeout,
+                  .. } = opts;
 
     static END_HANDLER: Condvar = Condvar::new();
-    ctrlc::set_handler(|| END_HANDLER.notify_one()).unwrap();
+    fn end_handler() {
+        if ops::shutting_down() {
+            ops::abandon_shutdown();
+        } else {
+            END_HANDLER.notify_one();
+        }
+    }
+    ctrlc::set_handler(end_handler).unwrap();
+    util::on_sigterm(end_handler).map_err(|err| Error(format!("Setting up SIGTERM handler: {}", err)))?;
+    if let Some(interval) = util::sd_watchdog_interval() {
+        thread::Builder::new()
+            .name("sd-watchdog".to_string())
+            .spawn(move || loop {
+                let _ = util::sd_notify("WATCHDOG=1");
+                thread::sleep(interval / 2);
+            })
+            .map_err(|err| Error(format!("Starting watchdog thread: {}", err)))?;
+    }
+    let _ = util::sd_notify("READY=1");
     if opts_encoded_prune.is_some() {
         loop {
             if !END_HANDLER.wait_timeout(Mutex::new(()).lock().unwrap(), Duration::from_secs(handler.handler.prune_interval)).unwrap().1.timed_out() {
@@ -171,7 +285,12 @@ fn result_main() -> Result<(), Error> {
         drop(END_HANDLER.wait(Mutex::new(()).lock().unwrap()).unwrap());
     }
 
-    responder.close().unwrap();
-    handler.handler.handler.clean_temp_dirs(&opts_temp_directory, opts_generate_tls);
+    let _ = util::sd_notify("STOPPING=1");
+    handler.handler.handler.shut_down(Duration::from_secs(opts_shutdown_timeout));
+    for (mut responder, _) in responders {
+        responder.close().unwrap();
+    }
+    handler.handler.log_stats();
+    handler.handler.handler.clean_temp_dirs(&opts_temp_directory, opts_generate_tls, &opts_listen);
     Ok(())
 }
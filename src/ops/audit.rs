@@ -0,0 +1,77 @@
+//! Audit log of authentication successes and failures, one JSON object per line, appended to a file.
+
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use self::super::HttpHandler;
+use std::sync::Mutex;
+use std::path::Path;
+use iron::Request;
+use serde_json;
+use time;
+
+
+/// An open audit log
+pub struct AuditLog(Mutex<File>);
+
+/// What became of a request's credentials
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+pub enum AuditEvent {
+    /// Valid credentials
+    Success,
+    /// Unknown user or wrong password
+    Invalid,
+    /// Credentials needed, but none supplied
+    Missing,
+    /// Valid credentials, but without the permissions needed
+    Forbidden,
+    /// Credentials not checked, since the client's locked out after too many invalid ones
+    LockedOut,
+}
+
+impl AuditLog {
+    /// Open the log at `path` for appending, creating it if needed
+    pub fn open(path: &Path) -> io::Result<AuditLog> {
+        Ok(AuditLog(Mutex::new(OpenOptions::new().create(true).append(true).open(path)?)))
+    }
+}
+
+impl AuditEvent {
+    fn name(self) -> &'static str {
+        match self {
+            AuditEvent::Success => "success",
+            AuditEvent::Invalid => "invalid",
+            AuditEvent::Missing => "missing",
+            AuditEvent::Forbidden => "forbidden",
+            AuditEvent::LockedOut => "locked-out",
+        }
+    }
+}
+
+
+impl HttpHandler {
+    /// Record `event` for the request by `user` in the audit log, if there is one.
+    ///
+    /// The line is written in one go, so concurrent requests' don't interleave; failing to write is logged, but doesn't fail the request.
+    pub(super) fn audit(&self, req: &Request, event: AuditEvent, user: Option<&str>) {
+        let audit_log = match self.audit_log.get() {
+            Some(audit_log) => audit_log,
+            None => return,
+        };
+
+        let mut line = serde_json::json!({
+            "time": time::now_utc().rfc3339().to_string(),
+            "event": event.name(),
+            "address": self.remote_addresses(req).client(),
+            "user": user,
+            "method": req.method.to_string(),
+            "path": req.url.as_ref().path(),
+        })
+            .to_string();
+        line.push('\n');
+
+        if let Err(err) = audit_log.0.lock().expect("Audit log lock poisoned").write_all(line.as_bytes()) {
+            log!(self.log, "{red}Writing to audit log failed{reset}: {}", err);
+        }
+    }
+}
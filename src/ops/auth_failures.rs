@@ -0,0 +1,91 @@
+//! Tracking clients' invalid credentials, to lock them out for longer and longer the more they keep guessing.
+//!
+//! At most `MAX_TRACKED_CLIENTS` clients are remembered, and each is forgotten once it's gone as long as the longest lockout
+//! without failing (or being locked out); past that, those that would be forgotten soonest make room.
+
+
+use std::time::{Duration, Instant};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+
+/// How many clients' failures to remember at most
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+
+/// Invalid credentials by client address
+pub struct AuthFailures {
+    /// How many invalid credentials in a row to allow before locking a client out; 0 for never
+    threshold: u32,
+    /// (first, longest) lockout
+    lockout: (Duration, Duration),
+    /// Client address, or IPv6 /64 network -> (invalid credentials in a row, locked out until, forget at)
+    clients: Mutex<HashMap<String, (u32, Instant, Instant)>>,
+}
+
+impl AuthFailures {
+    /// Lock clients out after `threshold` invalid credentials in a row (never if 0),
+    /// for `lockout.0` seconds at first, doubling with each further one, up to `lockout.1` seconds
+    pub fn new(threshold: u32, lockout: (u64, u64)) -> AuthFailures {
+        AuthFailures {
+            threshold: threshold,
+            lockout: (Duration::from_secs(lockout.0), Duration::from_secs(lockout.1)),
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// How much longer `client` is locked out for, if it is
+    pub fn locked_out(&self, client: &str) -> Option<Duration> {
+        if self.threshold == 0 {
+            return None;
+        }
+
+        let clients = self.clients.lock().expect("Auth failures lock poisoned");
+        let left = clients.get(client)?.1.saturating_duration_since(Instant::now());
+        if left.is_zero() { None } else { Some(left) }
+    }
+
+    /// Count an invalid credential from `client`, returning how many it's sent in a row and how long it's now locked out for,
+    /// if this locked it out
+    pub fn failed(&self, client: &str) -> Option<(u32, Duration)> {
+        if self.threshold == 0 {
+            return None;
+        }
+
+        let now = Instant::now();
+        let mut clients = self.clients.lock().expect("Auth failures lock poisoned");
+        if clients.len() >= MAX_TRACKED_CLIENTS && !clients.contains_key(client) {
+            clients.retain(|_, &mut (_, _, forget_at)| forget_at > now);
+            if clients.len() >= MAX_TRACKED_CLIENTS {
+                let soonest = clients.iter().min_by_key(|&(_, &(_, _, forget_at))| forget_at).map(|(client, _)| client.clone());
+                if let Some(soonest) = soonest {
+                    clients.remove(&soonest);
+                }
+            }
+        }
+
+        let (failures, locked_until, forget_at) = clients.entry(client.to_string()).or_insert((0, now, now));
+        if *forget_at <= now {
+            *failures = 0;
+        }
+        *failures = failures.saturating_add(1);
+        let lockout = if *failures >= self.threshold {
+            let doublings = (*failures - self.threshold).min(31);
+            Some(self.lockout.0.saturating_mul(1 << doublings).min(self.lockout.1))
+        } else {
+            None
+        };
+        *locked_until = now + lockout.unwrap_or_default();
+        *forget_at = *locked_until + self.lockout.1;
+        lockout.map(|lockout| (*failures, lockout))
+    }
+
+    /// Forget `client`'s invalid credentials, once it's supplied valid ones
+    pub fn succeeded(&self, client: &str) {
+        if self.threshold == 0 {
+            return;
+        }
+
+        self.clients.lock().expect("Auth failures lock poisoned").remove(client);
+    }
+}
@@ -1,4 +1,6 @@
-use iron::{AfterMiddleware, IronResult, Response, Handler, Request};
+use iron::{AfterMiddleware, IronResult, Response, Handler, Request, headers};
+use self::super::shutdown::{ActiveRequest, shutting_down};
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
 use std::num::{NonZeroUsize, NonZeroU64};
 use std::io::{Result as IoResult, Write};
 use iron::response::WriteBody;
@@ -21,33 +23,48 @@ impl<H: Send + Sync + 'static, Am: AfterMiddleware> Handler for &'static SimpleC
     where &'static H: Handler
 {
     fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let active = ActiveRequest::start();
         let resp = (&self.handler).handle(req)?;
-        match self.after.as_ref() {
-            Some(am) => am.after(req, resp),
-            None => Ok(resp),
+        let mut resp = match self.after.as_ref() {
+            Some(am) => am.after(req, resp)?,
+            None => resp,
+        };
+        if shutting_down() {
+            resp.headers.set(headers::Connection::close());
         }
+        Ok(active.until_written(resp))
     }
 }
 
 
 
-#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug)]
 pub struct LimitBandwidthMiddleware {
-    pub bandwidth: NonZeroU64,
+    /// 0 for unlimited
+    pub bandwidth: AtomicU64,
 }
 
 impl LimitBandwidthMiddleware {
-    pub fn new(bandwidth: NonZeroU64) -> LimitBandwidthMiddleware {
-        LimitBandwidthMiddleware { bandwidth: bandwidth }
+    pub fn new(bandwidth: Option<NonZeroU64>) -> LimitBandwidthMiddleware {
+        LimitBandwidthMiddleware { bandwidth: AtomicU64::new(bandwidth.map(NonZeroU64::get).unwrap_or(0)) }
+    }
+
+    /// Change the limit for subsequent requests
+    pub fn set(&self, bandwidth: Option<NonZeroU64>) {
+        self.bandwidth.store(bandwidth.map(NonZeroU64::get).unwrap_or(0), AtomicOrdering::Relaxed);
     }
 }
 
 impl AfterMiddleware for LimitBandwidthMiddleware {
     fn after(&self, _: &mut Request, res: Response) -> IronResult<Response> {
+        let bandwidth = match NonZeroU64::new(self.bandwidth.load(AtomicOrdering::Relaxed)) {
+            Some(bandwidth) => bandwidth,
+            None => return Ok(res),
+        };
         Ok(Response {
             body: res.body.map(|body| {
                 Box::new(LimitBandwidthWriteBody {
-                    bandwidth: self.bandwidth,
+                    bandwidth: bandwidth,
                     underlying: body,
                 }) as Box<dyn WriteBody>
             }),
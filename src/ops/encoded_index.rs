@@ -0,0 +1,145 @@
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use std::io::{self, BufWriter, BufReader, BufRead, Write};
+use self::super::super::util::HumanReadableSize;
+use time::{precise_time_ns, get_time};
+use std::collections::{HashMap, HashSet};
+use iron::headers::Encoding;
+use self::super::{HttpHandler, CacheT};
+use std::path::PathBuf;
+use std::fs::{self, File};
+use std::str::FromStr;
+use blake3;
+
+
+/// Name of the index of encoded filesystem files, stored alongside them
+const INDEX_FILE: &str = "index";
+
+
+impl HttpHandler {
+    /// Reload the encoded filesystem file cache written by `save_encoded_index()` on a previous run.
+    ///
+    /// Entries whose encoded files are missing or changed are dropped, as are encoded files not in the index,
+    /// so this has to be called before any requests are served.
+    pub fn load_encoded_index(&self) {
+        let dir = match self.encoded_temp_dir.as_ref() {
+            Some(&(_, ref dir)) if self.encoded_persist => dir,
+            _ => return,
+        };
+        let index = match File::open(dir.join(INDEX_FILE)) {
+            Ok(index) => index,
+            Err(_) => {
+                // Nothing to validate against
+                let _ = fs::remove_dir_all(dir);
+                return;
+            }
+        };
+
+        let now = precise_time_ns();
+        let now_wall = wall_time_ns();
+        let mut referenced = HashSet::new();
+        let mut loaded = 0usize;
+        {
+            // In the same order as prune_fs() takes them
+            let mut cache_files = self.cache_fs_files.write().expect("Filesystem file cache write lock poisoned");
+            let mut cache = self.cache_fs.write().expect("Filesystem cache write lock poisoned");
+            let mut cache_paths = self.cache_fs_paths.write().expect("Filesystem path cache write lock poisoned");
+            for line in BufReader::new(index).lines().map_while(Result::ok) {
+                if let Some(etag_line) = line.strip_prefix("etag ") {
+                    let mut fields = etag_line.splitn(3, ' ');
+                    if let (Some(etag), Some(Ok(hash)), path) = (fields.next(), fields.next().map(blake3::Hash::from_hex), fields.next()) {
+                        cache_files.insert(etag.to_string(), hash);
+                        if let Some(path) = path {
+                            cache_paths.insert(PathBuf::from(path), etag.to_string());
+                        }
+                    }
+                    continue;
+                }
+                let mut fields = line.splitn(6, ' ');
+                let (hash, enc, size, atime, fname) = match (fields.next(), fields.next(), fields.next(), fields.next(), fields.next(), fields.next()) {
+                    (Some("file"), Some(hash), Some(enc), Some(size), Some(atime), Some(fname)) => (hash, enc, size, atime, fname),
+                    _ => continue,
+                };
+                let (hash, enc, size) = match (blake3::Hash::from_hex(hash), Encoding::from_str(enc), u64::from_str(size)) {
+                    (Ok(hash), Ok(enc), Ok(size)) => (hash, enc, size),
+                    _ => continue,
+                };
+
+                if fname == "-" {
+                    cache.insert((hash, enc.0), ((PathBuf::new(), false, 0), AtomicU64::new(u64::MAX)));
+                    continue;
+                }
+                let atime = match u64::from_str(atime) {
+                    Ok(atime) => now.saturating_sub(now_wall.saturating_sub(atime)),
+                    Err(_) => continue,
+                };
+                let path = dir.join(fname);
+                if path.parent() != Some(dir) || path.metadata().map(|m| !m.is_file() || m.len() != size).unwrap_or(true) {
+                    continue;
+                }
+
+                self.cache_fs_size.fetch_add(size, AtomicOrdering::Relaxed);
+                if let Some(((_, _, old_size), _)) = cache.insert((hash, enc.0), ((path, true, size), AtomicU64::new(atime))) {
+                    self.cache_fs_size.fetch_sub(old_size, AtomicOrdering::Relaxed);
+                }
+                self.cache_fs_lru.insert(atime, (hash, enc.0));
+                referenced.insert(fname.to_string());
+                loaded += 1;
+            }
+
+            let hashes = cache.keys().map(|&(hash, _)| hash).collect::<HashSet<_>>();
+            cache_files.retain(|_, hash| hashes.contains(hash));
+            cache_paths.retain(|_, etag| cache_files.contains_key(etag));
+        }
+
+        for f in dir.read_dir().into_iter().flatten().flatten() {
+            let fname = f.file_name();
+            if fname != INDEX_FILE && !fname.to_str().map(|n| referenced.contains(n)).unwrap_or(false) {
+                let _ = fs::remove_file(f.path());
+            }
+        }
+
+        log!(self.log,
+             "Loaded {} encoded files ({}) from {magenta}{}{reset}",
+             loaded,
+             HumanReadableSize(self.cache_fs_size.load(AtomicOrdering::Relaxed)),
+             self.encoded_temp_dir.as_ref().unwrap().0);
+    }
+
+    /// Write the encoded filesystem file cache index, to be picked up by `load_encoded_index()` on the next run.
+    pub fn save_encoded_index(&self, cache_files: &HashMap<String, blake3::Hash>, cache: &CacheT<(PathBuf, bool, u64)>) -> io::Result<()> {
+        let dir = match self.encoded_temp_dir.as_ref() {
+            Some(&(_, ref dir)) if dir.exists() => dir,
+            _ => return Ok(()),
+        };
+
+        let now = precise_time_ns();
+        let now_wall = wall_time_ns();
+        let tmp_p = dir.join(format!("{}.tmp", INDEX_FILE));
+        let mut out = BufWriter::new(File::create(&tmp_p)?);
+        for (&(hash, enc), &((ref path, worth_it, size), ref atime)) in cache.iter() {
+            let enc = Encoding(enc, String::new(), false);
+            match path.file_name().and_then(|f| f.to_str()) {
+                Some(fname) if worth_it => {
+                    let atime = now_wall.saturating_sub(now.saturating_sub(atime.load(AtomicOrdering::Relaxed)));
+                    writeln!(out, "file {} {} {} {} {}", hash.to_hex(), enc, size, atime, fname)?
+                }
+                _ => writeln!(out, "file {} {} 0 - -", hash.to_hex(), enc)?,
+            }
+        }
+        let paths = self.cache_fs_paths.read().expect("Filesystem path cache read lock poisoned");
+        let paths = paths.iter().flat_map(|(path, etag)| path.to_str().filter(|p| !p.contains('\n')).map(|p| (&etag[..], p))).collect::<HashMap<_, _>>();
+        for (etag, hash) in cache_files {
+            match paths.get(&etag[..]) {
+                Some(path) => writeln!(out, "etag {} {} {}", etag, hash.to_hex(), path)?,
+                None => writeln!(out, "etag {} {}", etag, hash.to_hex())?,
+            }
+        }
+        out.into_inner()?.sync_all()?;
+        fs::rename(tmp_p, dir.join(INDEX_FILE))
+    }
+}
+
+fn wall_time_ns() -> u64 {
+    let now = get_time();
+    (now.sec as u64) * 1000 * 1000 * 1000 + (now.nsec as u64)
+}
@@ -0,0 +1,220 @@
+use self::super::super::util::{encoding_extension, extension_is_blacklisted, is_descendant_of, encode_file, file_etag, file_hash, file_length,
+                               is_actually_file, ENCODING_QUEUE_SIZE, SUPPORTED_ENCODINGS, MIN_ENCODING_GAIN, MAX_ENCODING_SIZE, MIN_ENCODING_SIZE};
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use std::sync::mpsc::{self, SyncSender};
+use iron::headers::{EncodingType, Encoding};
+use std::path::{PathBuf, Path};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::panic::{self, AssertUnwindSafe};
+use time::precise_time_ns;
+use self::super::HttpHandler;
+use walkdir::WalkDir;
+use std::ffi::OsStr;
+use std::{fs, thread};
+use blake3;
+
+
+/// A filesystem file to encode in the background
+pub struct EncodeJob {
+    pub path: PathBuf,
+    pub etag: String,
+    pub encoding: Encoding,
+}
+
+impl HttpHandler {
+    /// Spawn the encoder threads, and, if requested, queue all files in the hosted directory.
+    ///
+    /// Until this is called, filesystem files are always served as identity.
+    pub fn start_encoders(&'static self) {
+        if self.encoded_temp_dir.is_none() {
+            return;
+        }
+
+        let (queue, jobs) = mpsc::sync_channel(ENCODING_QUEUE_SIZE);
+        let jobs = Arc::new(Mutex::new(jobs));
+        for i in 0..self.encoder_threads {
+            let jobs = jobs.clone();
+            thread::Builder::new()
+                .name(format!("encoder-{}", i))
+                .spawn(move || loop {
+                    // Taken in its own statement, so the lock's released before encoding and the other threads can take jobs meanwhile
+                    let job = jobs.lock().unwrap_or_else(PoisonError::into_inner).recv();
+                    match job {
+                        // A job that panics only loses itself, not the thread
+                        Ok(job) => drop(panic::catch_unwind(AssertUnwindSafe(|| self.handle_encode_job(job)))),
+                        Err(_) => break,
+                    }
+                })
+                .expect("Failed to spawn encoder thread");
+        }
+
+        if self.encode_prewarm {
+            let queue = queue.clone();
+            thread::Builder::new().name("encoder-prewarm".to_string()).spawn(move || self.prewarm_encoded(queue)).expect("Failed to spawn prewarm thread");
+        }
+        let _ = self.encoder_queue.set(queue);
+    }
+
+    /// Queue the file to be encoded, unless it already is, or the queue is full.
+    ///
+    /// Returns whether it was queued.
+    pub fn queue_encode(&self, req_p: &Path, etag: &str, encoding: &Encoding) -> bool {
+        let queue = match self.encoder_queue.get() {
+            Some(queue) => queue,
+            None => return false,
+        };
+
+        let key = (etag.to_string(), encoding.0);
+        if !self.encoding_in_progress.lock().expect("Encoding in progress lock poisoned").insert(key.clone()) {
+            return false;
+        }
+        let job = EncodeJob {
+            path: req_p.to_path_buf(),
+            etag: etag.to_string(),
+            encoding: encoding.clone(),
+        };
+        if queue.try_send(job).is_err() {
+            self.encoding_in_progress.lock().expect("Encoding in progress lock poisoned").remove(&key);
+            return false;
+        }
+        true
+    }
+
+    fn prewarm_encoded(&self, queue: SyncSender<EncodeJob>) {
+        let start = precise_time_ns();
+        let mut queued = 0usize;
+        for mount in self.sites().flat_map(|site| &site.mounts) {
+            for f in WalkDir::new(&mount.directory.1).follow_links(self.follow_symlinks).into_iter().flatten() {
+                let path = f.path();
+                if !is_actually_file(&f.file_type(), path) || (mount.sandbox_symlinks && f.path_is_symlink() && !is_descendant_of(path, &mount.directory.1)) ||
+                   path.extension().map(extension_is_blacklisted).unwrap_or(false) {
+                    continue;
+                }
+                let metadata = match f.metadata() {
+                    Ok(metadata) => metadata,
+                    Err(_) => continue,
+                };
+                let flen = file_length(&metadata, &path);
+                if flen <= MIN_ENCODING_SIZE || flen >= MAX_ENCODING_SIZE {
+                    continue;
+                }
+
+                let etag = file_etag(&metadata);
+                for &enc in SUPPORTED_ENCODINGS {
+                    if !self.encoding_in_progress.lock().expect("Encoding in progress lock poisoned").insert((etag.clone(), enc)) {
+                        continue;
+                    }
+                    let job = EncodeJob {
+                        path: path.to_path_buf(),
+                        etag: etag.clone(),
+                        encoding: Encoding(enc, String::new(), false),
+                    };
+                    if queue.send(job).is_err() {
+                        return;
+                    }
+                    queued += 1;
+                }
+            }
+        }
+
+        log!(self.log, "Queued {} encoding jobs in {}ms", queued, (precise_time_ns() - start) / 1000 / 1000);
+    }
+
+    fn handle_encode_job(&self, job: EncodeJob) {
+        let EncodeJob { path: req_p, etag, encoding } = job;
+        // Dropped last, even if encoding panics, so the file can be queued again
+        struct DropInProgress<'a>(&'a HttpHandler, (String, EncodingType));
+        impl<'a> Drop for DropInProgress<'a> {
+            fn drop(&mut self) {
+                self.0.encoding_in_progress.lock().unwrap_or_else(PoisonError::into_inner).remove(&self.1);
+            }
+        }
+        let _in_progress = DropInProgress(self, (etag.clone(), encoding.0));
+
+        // Recorded before hashing, so a change after this point is seen by invalidate_path()
+        self.cache_fs_paths.write().expect("Filesystem path cache write lock poisoned").insert(req_p.clone(), etag.clone());
+        let hash = self.cache_fs_files.read().expect("Filesystem file cache read lock poisoned").get(&etag).cloned();
+        let hash = match hash {
+            Some(hash) => Some(hash),
+            None => {
+                match file_hash(&req_p) {
+                    Ok(h) => {
+                        self.cache_fs_files.write().expect("Filesystem file cache write lock poisoned").insert(etag.clone(), h);
+                        Some(h)
+                    }
+                    Err(_) => None,
+                }
+            }
+        };
+
+        if let Some(hash) = hash.filter(|_| self.path_etag_current(&req_p, &etag)) {
+            let cache_key = (hash, encoding.0);
+            if !self.cache_fs.read().expect("Filesystem cache read lock poisoned").contains_key(&cache_key) {
+                self.encode_file_cached(&req_p, cache_key, &encoding);
+
+                if !self.path_etag_current(&req_p, &etag) {
+                    // Changed while encoding, so only this encoding might not match the hash; the others, maybe of other files, are still good
+                    self.drop_encoded(Some(cache_key));
+                }
+            }
+        }
+    }
+
+    /// Check that `req_p` wasn't invalidated since it was recorded with `etag`, forgetting the hash for `etag` if it was
+    fn path_etag_current(&self, req_p: &Path, etag: &str) -> bool {
+        let current = self.cache_fs_paths.read().expect("Filesystem path cache read lock poisoned").get(req_p).map(|e| e == etag).unwrap_or(false);
+        if !current {
+            self.cache_fs_files.write().expect("Filesystem file cache write lock poisoned").remove(etag);
+        }
+        current
+    }
+
+    fn encode_file_cached(&self, req_p: &Path, cache_key: (blake3::Hash, EncodingType), encoding: &Encoding) {
+        self.create_temp_dir(&self.encoded_temp_dir);
+
+        let mut resp_p = self.encoded_temp_dir.as_ref().unwrap().1.join(cache_key.0.to_hex().as_str());
+        match (req_p.extension(), encoding_extension(&encoding)) {
+            (Some(ext), Some(enc)) => {
+                let mut new_ext = ext.as_encoded_bytes().to_vec();
+                new_ext.push(b'.');
+                new_ext.extend_from_slice(enc.as_bytes());
+                resp_p.set_extension(unsafe { OsStr::from_encoded_bytes_unchecked(&new_ext) })
+            }
+            (None, Some(enc)) => resp_p.set_extension(enc),
+            (_, None) => unsafe { std::hint::unreachable_unchecked() },
+        };
+
+        let resp_meta = if encode_file(&req_p, &resp_p, &encoding, &self.compression_levels) {
+            resp_p.metadata().ok()
+        } else {
+            None
+        };
+        if let Some(resp_meta) = resp_meta {
+            let resp_p_len = file_length(&resp_meta, &resp_p);
+            let gain = match req_p.metadata() {
+                Ok(req_meta) => (file_length(&req_meta, &req_p) as f64) / (resp_p_len as f64),
+                Err(_) => 0f64,
+            };
+            if gain < MIN_ENCODING_GAIN || resp_p_len > self.encoded_filesystem_limit {
+                let mut cache = self.cache_fs.write().expect("Filesystem cache write lock poisoned");
+                cache.insert(cache_key, ((PathBuf::new(), false, 0), AtomicU64::new(u64::MAX)));
+                let _ = fs::remove_file(resp_p);
+            } else {
+                log!(self.log,
+                     "Encoded {magenta}{}{reset} as {} for {:.1}% ratio",
+                     req_p.display(),
+                     encoding,
+                     gain * 100f64);
+
+                let atime = precise_time_ns();
+                let mut cache = self.cache_fs.write().expect("Filesystem cache write lock poisoned");
+                self.cache_fs_size.fetch_add(resp_p_len, AtomicOrdering::Relaxed);
+                cache.insert(cache_key, ((resp_p, true, resp_p_len), AtomicU64::new(atime)));
+                self.cache_fs_lru.insert(atime, cache_key);
+            }
+        } else {
+            let _ = fs::remove_file(resp_p);
+            log!(self.log, "Failed to encode {magenta}{}{reset} as {}", req_p.display(), encoding);
+        }
+    }
+}
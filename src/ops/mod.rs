@@ -1,37 +1,43 @@
+use constant_time_eq::constant_time_eq;
 use blake3;
 use serde_json;
-use std::net::IpAddr;
+use std::net::{SocketAddr, Ipv6Addr, IpAddr};
 use serde::Serialize;
-use std::sync::RwLock;
-use std::{fmt, str, mem};
-use cidr::{Cidr, IpCidr};
-use time::precise_time_ns;
+use std::sync::mpsc::SyncSender;
+use std::sync::{Arc, Condvar, Mutex, OnceLock, PoisonError, RwLock};
+use std::{cmp, fmt, iter, str, mem};
+use time::{precise_time_ns, Tm};
 use arrayvec::ArrayString;
 use std::fs::{self, File};
 use std::default::Default;
 use iron::modifiers::Header;
+use iron::response::{BodyReader, WriteBody};
 use std::path::{PathBuf, Path};
 use std::ffi::{OsString, OsStr};
 use std::fmt::Write as FmtWrite;
-use iron::headers::EncodingType;
+use iron::headers::{EncodingType, Encoding};
 use iron::url::Url as GenericUrl;
 use mime_guess::get_mime_type_opt;
-use hyper_native_tls::NativeTlsServer;
 use std::hash::{BuildHasher, RandomState};
-use std::collections::{BTreeMap, HashMap};
-use self::super::{LogLevel, Options, Error};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::borrow::Cow;
+use std::time::Duration;
+use self::super::{LogLevel, Options, Listen, ListenAddress, Htpasswd, Mount, ProxySource, Error};
 use std::process::{ExitStatus, Command, Child, Stdio};
 use rfsapi::{RawFsApiHeader, FilesetData, RawFileData};
 use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
-use iron::{headers, status, method, IronResult, Listening, Response, Headers, Request, Handler, Iron};
-use std::io::{self, ErrorKind as IoErrorKind, BufReader, SeekFrom, Write, Error as IoError, Read, Seek};
+use iron::{headers, status, method, IronResult, Listening, Response, Protocol, Headers, Request, Handler, Iron};
+use hyper::net::{NetworkListener, HttpsListener, HttpListener};
+use hyper::version::HttpVersion;
+use std::io::{self, ErrorKind as IoErrorKind, BufReader, Write, Error as IoError, Read, Seek};
 use iron::mime::{Mime, Attr as MimeAttr, Value as MimeAttrValue, SubLevel as MimeSubLevel, TopLevel as MimeTopLevel};
-use self::super::util::{HumanReadableSize, WwwAuthenticate, NoDoubleQuotes, NoHtmlLiteral, XLastModified, DisplayThree, CommaList, XOcMTime, MsAsS, Maybe, Dav,
-                        url_path, file_etag, file_hash, set_mtime_f, is_symlink, encode_str, error_html, encode_file, file_length, file_binary, client_mobile,
-                        percent_decode, escape_specials, file_icon_suffix, is_actually_file, is_descendant_of, response_encoding, detect_file_as_dir,
-                        encoding_extension, file_time_modified, file_time_modified_p, dav_level_1_methods, get_raw_fs_metadata, encode_tail_if_trimmed,
-                        extension_is_blacklisted, directory_listing_html, directory_listing_mobile_html, is_nonexistent_descendant_of, USER_AGENT, MAX_SYMLINKS,
-                        INDEX_EXTENSIONS, MIN_ENCODING_GAIN, MAX_ENCODING_SIZE, MIN_ENCODING_SIZE};
+use self::super::util::{HumanReadableSize, WwwAuthenticate, RetryAfter, NoDoubleQuotes, NoHtmlLiteral, XLastModified, DisplayThree, CommaList, XOcMTime, MsAsS, Maybe, Dav,
+                        url_path, file_etag, set_mtime_f, is_symlink, encode_str, error_html, file_length, file_binary, client_mobile, percent_decode,
+                        escape_specials, file_icon_suffix, is_actually_file, is_descendant_of, response_encoding, detect_file_as_dir, file_time_modified,
+                        file_time_modified_p, password_matches, is_password_hash, VerifiedPasswords, DUMMY_PASSWORD_HASH, dav_level_1_methods, tcp_listener, UNIX_SOCKET_PEER, ActivatedSocket, get_raw_fs_metadata, encode_tail_if_trimmed, extension_is_blacklisted,
+                        directory_listing_html, directory_listing_mobile_html, is_nonexistent_descendant_of, USER_AGENT, MAX_SYMLINKS, satisfiable_byte_ranges,
+                        multipart_boundary, precompressed_extension, is_precompressed_sibling, MultipartByteRanges, EncodedFileStream, byte_range_content_range, seek_byte_range, INDEX_EXTENSIONS, MAX_ENCODING_SIZE,
+                        MIN_ENCODING_SIZE, MAX_BYTE_RANGES, CompressionLevels};
 
 macro_rules! log {
     ($logcfg:expr, $fmt:expr) => {
@@ -112,14 +118,43 @@ macro_rules! log {
 
 mod prune;
 mod webdav;
+mod encoder;
+mod encoded_index;
+mod watcher;
+mod reload;
 mod bandwidth;
-
-pub use self::prune::PruneChain;
+mod tls;
+mod shutdown;
+mod permissions;
+mod audit;
+mod auth_failures;
+#[cfg(not(target_os = "windows"))]
+mod unix;
+
+pub use self::prune::{PruneChain, LruIndex, CacheStats};
+pub use self::encoder::EncodeJob;
 pub use self::bandwidth::{LimitBandwidthMiddleware, SimpleChain};
+pub use self::tls::{TlsServer, tls_server};
+pub use self::shutdown::{ShutdownListener, ActiveRequest, shutting_down, abandon_shutdown};
+pub use self::permissions::Permissions;
+pub use self::audit::{AuditLog, AuditEvent};
+pub use self::auth_failures::AuthFailures;
+#[cfg(not(target_os = "windows"))]
+pub use self::unix::{UnixSocketListener, UnixSocketStream, remove_stale_socket};
 
 
 type CacheT<Cnt> = HashMap<(blake3::Hash, EncodingType), (Cnt, AtomicU64)>;
 
+/// Result of evaluating the [RFC 7232](https://tools.ietf.org/html/rfc7232) preconditions of a request
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+enum Precondition {
+    Passed,
+    /// 304, only for GET and HEAD
+    NotModified,
+    /// 412
+    Failed,
+}
+
 #[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub enum WebDavLevel {
     No,
@@ -127,103 +162,273 @@ pub enum WebDavLevel {
     All,
 }
 
+/// Credentials and headers for the default or a virtual host
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub struct SiteSettings {
+    /// Any of the `(username, password)`s is accepted; the passwords are hashed, in plain text, or empty
+    pub global_auth_data: Option<Vec<(String, String)>>,
+    pub path_auth_data: BTreeMap<String, Option<Vec<(String, String)>>>,
+    /// Under each path, the permissions of each user, or `*` for everyone else
+    pub path_permissions: BTreeMap<String, BTreeMap<String, Permissions>>,
+    pub additional_headers: Vec<(String, Vec<u8>)>,
+}
+
+impl SiteSettings {
+    fn new(path_auth: &BTreeMap<String, Vec<String>>, htpasswd: &BTreeMap<String, Htpasswd>,
+           path_permissions: &BTreeMap<String, BTreeMap<String, Permissions>>, additional_headers: Vec<(String, Vec<u8>)>)
+           -> SiteSettings {
+        let mut path_auth_data = BTreeMap::new();
+        let mut global_auth_data = None;
+
+        for path in path_auth.keys().chain(htpasswd.keys()).collect::<BTreeSet<_>>() {
+            let mut creds = path_auth.get(path)
+                .filter(|creds| !creds.is_empty())
+                .map(|creds| {
+                    creds.iter()
+                        .map(|auth| {
+                            let (username, password) = auth.split_once(':').unwrap_or((auth, ""));
+                            (username.to_string(), password.to_string())
+                        })
+                        .collect::<Vec<_>>()
+                });
+            if let Some((_, accounts)) = htpasswd.get(path) {
+                creds.get_or_insert_with(Vec::new).extend(accounts.iter().cloned());
+            }
+
+            if path == "" {
+                global_auth_data = creds;
+            } else {
+                path_auth_data.insert(path.to_string(), creds);
+            }
+        }
+
+        SiteSettings {
+            global_auth_data: global_auth_data,
+            path_auth_data: path_auth_data,
+            path_permissions: path_permissions.clone(),
+            additional_headers: additional_headers,
+        }
+    }
+
+    /// The accounts to authenticate requests to the URL with, if it needs authentication
+    pub fn auth_for(&self, url: &GenericUrl) -> Option<&Vec<(String, String)>> {
+        match longest_path_match(&self.path_auth_data, url) {
+            Some(auth) => auth.as_ref(),
+            None => self.global_auth_data.as_ref(),
+        }
+    }
+}
+
+/// Settings that can be swapped out while running, by `HttpHandler::reload()`
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub struct LiveSettings {
+    /// For requests not to a virtual host
+    pub site: SiteSettings,
+    pub vhosts: BTreeMap<String, SiteSettings>,
+    pub proxies: BTreeMap<ProxySource, String>,
+    pub proxy_redirs: BTreeMap<ProxySource, String>,
+    pub mime_type_overrides: BTreeMap<OsString, Mime>,
+    /// Let GET, HEAD, OPTIONS, and PROPFIND requests through without credentials
+    pub auth_writes_only: bool,
+}
+
+impl LiveSettings {
+    pub fn new(opts: &Options) -> LiveSettings {
+        LiveSettings {
+            site: SiteSettings::new(&opts.path_auth_data, &opts.htpasswd, &opts.path_permissions, opts.additional_headers.clone()),
+            vhosts: opts.vhosts
+                .iter()
+                .map(|(host, vhost)| {
+                    // Paths the virtual host doesn't set its own credentials or permissions for keep the default ones, so it doesn't fail open
+                    let own_auth = |path: &str| vhost.path_auth_data.contains_key(path) || vhost.htpasswd.contains_key(path);
+                    (host.clone(),
+                     SiteSettings::new(&inherit_paths(&opts.path_auth_data, &vhost.path_auth_data, own_auth),
+                                       &inherit_paths(&opts.htpasswd, &vhost.htpasswd, own_auth),
+                                       &inherit_paths(&opts.path_permissions, &vhost.path_permissions, |path| vhost.path_permissions.contains_key(path)),
+                                       opts.additional_headers.iter().chain(&vhost.additional_headers).cloned().collect()))
+                })
+                .collect(),
+            proxies: opts.proxies.clone(),
+            proxy_redirs: opts.proxy_redirs.clone(),
+            mime_type_overrides: opts.mime_type_overrides.clone(),
+            auth_writes_only: opts.auth_writes_only,
+        }
+    }
+
+    /// The settings for the virtual host the URL is on, or the default ones
+    pub fn site(&self, url: &GenericUrl) -> &SiteSettings {
+        vhost_name(url).and_then(|host| self.vhosts.get(host)).unwrap_or(&self.site)
+    }
+}
+
+/// What's hosted for the default or a virtual host
+pub struct Site {
+    /// Longest prefix first, so the first one matching a request is the one it's under; the hosted directory is last
+    pub mounts: Vec<Mount>,
+    pub try_404: Option<PathBuf>,
+}
+
+/// A directory listing entry, either read from the directory or a mount point under it
+struct ListingEntry {
+    path: PathBuf,
+    name: String,
+    metadata: fs::Metadata,
+    is_file: bool,
+    is_mount: bool,
+}
+
 pub struct HttpHandler {
-    pub hosted_directory: (String, PathBuf),
+    /// For requests not to a virtual host
+    pub site: Site,
+    pub vhosts: BTreeMap<String, Site>,
     pub follow_symlinks: bool,
-    pub sandbox_symlinks: bool,
-    pub generate_listings: bool,
     pub check_indices: bool,
     pub strip_extensions: bool,
-    pub try_404: Option<PathBuf>,
+    pub precompressed: bool,
+    pub compression_levels: CompressionLevels,
     /// (at all, log_time, log_colour)
     pub log: (bool, bool, bool),
     pub webdav: WebDavLevel,
-    pub global_auth_data: Option<(String, Option<String>)>,
-    pub path_auth_data: BTreeMap<String, Option<(String, Option<String>)>>,
+    pub live: RwLock<Arc<LiveSettings>>,
     pub writes_temp_dir: Option<(String, PathBuf)>,
     pub encoded_temp_dir: Option<(String, PathBuf)>,
-    pub proxies: BTreeMap<IpCidr, String>,
-    pub proxy_redirs: BTreeMap<IpCidr, String>,
-    pub mime_type_overrides: BTreeMap<OsString, Mime>,
-    pub additional_headers: Vec<(String, Vec<u8>)>,
 
     pub cache_gen: RwLock<CacheT<Vec<u8>>>,
+    pub cache_gen_paths: RwLock<HashMap<PathBuf, HashSet<blake3::Hash>>>, // listed directory -> cache keys
     pub cache_fs_files: RwLock<HashMap<String, blake3::Hash>>, // etag -> cache key
+    pub cache_fs_paths: RwLock<HashMap<PathBuf, String>>, // path -> etag
     pub cache_fs: RwLock<CacheT<(PathBuf, bool, u64)>>,
     pub cache_gen_size: AtomicU64,
     pub cache_fs_size: AtomicU64,
+    pub cache_gen_lru: LruIndex<(blake3::Hash, EncodingType)>,
+    pub cache_fs_lru: LruIndex<(blake3::Hash, EncodingType)>,
+    pub cache_gen_stats: CacheStats,
+    pub cache_fs_stats: CacheStats,
     pub encoded_filesystem_limit: u64,
     pub encoded_generated_limit: u64,
+    pub encoded_persist: bool,
+    pub encode_streaming: u64,
+    pub encoder_threads: usize,
+    pub encode_prewarm: bool,
+    pub encoder_queue: OnceLock<SyncSender<EncodeJob>>,
+    pub encoding_in_progress: Mutex<HashSet<(String, EncodingType)>>, // (etag, encoding)
+    pub audit_log: OnceLock<AuditLog>,
+    pub auth_failures: AuthFailures,
+    /// Files being replaced or deleted, each only by one request at a time, see `replacing()`
+    pub replacing: (Mutex<HashSet<PathBuf>>, Condvar),
+    pub verified_passwords: VerifiedPasswords,
 
     pub allowed_methods: &'static [method::Method],
 }
 
-impl HttpHandler {
-    pub fn new(opts: &Options) -> HttpHandler {
-        let mut path_auth_data = BTreeMap::new();
-        let mut global_auth_data = None;
+/// A file marked as being replaced or deleted by `HttpHandler::replacing()`, until dropped
+struct Replacing<'a>(&'a HttpHandler, PathBuf);
 
-        for (path, creds) in &opts.path_auth_data {
-            let creds = creds.as_ref()
-                .map(|auth| {
-                    let mut itr = auth.split_terminator(':');
-                    (itr.next().unwrap().to_string(), itr.next().map(str::to_string))
-                });
+impl<'a> Drop for Replacing<'a> {
+    fn drop(&mut self) {
+        self.0.replacing.0.lock().unwrap_or_else(PoisonError::into_inner).remove(&self.1);
+        self.0.replacing.1.notify_all();
+    }
+}
 
-            if path == "" {
-                global_auth_data = creds;
-            } else {
-                path_auth_data.insert(path.to_string(), creds);
+impl HttpHandler {
+    pub fn new(opts: &Options) -> HttpHandler {
+        let allow_writes = opts.allow_writes || opts.mounts.iter().any(|m| m.allow_writes);
+        let root_mount = |hosted_directory: &(String, PathBuf)| {
+            Mount {
+                prefix: String::new(),
+                directory: hosted_directory.clone(),
+                allow_writes: opts.allow_writes,
+                generate_listings: opts.generate_listings,
+                sandbox_symlinks: opts.sandbox_symlinks,
             }
-        }
-
+        };
         let allowed_methods = [method::Options, method::Get, method::Head, method::Trace]
             .iter()
-            .chain(dav_level_1_methods(opts.allow_writes)
+            .chain(dav_level_1_methods(allow_writes)
                 .iter()
                 .filter(|method| {
                     opts.webdav == WebDavLevel::All || (opts.webdav == WebDavLevel::MkColMoveOnly && matches!(**method, method::DavMkcol | method::DavMove))
                 }))
-            .chain([method::Put, method::Delete].iter().filter(|_| opts.allow_writes))
+            .chain([method::Put, method::Delete].iter().filter(|_| allow_writes))
             .cloned()
             .collect::<Vec<_>>()
             .leak();
 
+        let mut mounts = opts.mounts.clone();
+        mounts.push(root_mount(&opts.hosted_directory));
+        mounts.sort_by_key(|m| cmp::Reverse(m.prefix.len()));
+
         HttpHandler {
-            hosted_directory: opts.hosted_directory.clone(),
+            site: Site {
+                mounts: mounts,
+                try_404: opts.try_404.clone(),
+            },
+            vhosts: opts.vhosts
+                .iter()
+                .map(|(host, vhost)| {
+                    (host.clone(),
+                     Site {
+                         mounts: vec![root_mount(&vhost.hosted_directory)],
+                         try_404: vhost.try_404.clone(),
+                     })
+                })
+                .collect(),
             follow_symlinks: opts.follow_symlinks,
-            sandbox_symlinks: opts.sandbox_symlinks,
-            generate_listings: opts.generate_listings,
             check_indices: opts.check_indices,
             strip_extensions: opts.strip_extensions,
-            try_404: opts.try_404.clone(),
+            precompressed: opts.precompressed,
+            compression_levels: opts.compression_levels,
             log: (opts.loglevel < LogLevel::NoServeStatus, opts.log_time, opts.log_colour),
             webdav: opts.webdav,
-            global_auth_data: global_auth_data,
-            path_auth_data: path_auth_data,
-            writes_temp_dir: HttpHandler::temp_subdir(&opts.temp_directory, opts.allow_writes, "writes"),
+            live: RwLock::new(Arc::new(LiveSettings::new(opts))),
+            writes_temp_dir: HttpHandler::temp_subdir(&opts.temp_directory, allow_writes, "writes"),
             encoded_temp_dir: HttpHandler::temp_subdir(&opts.temp_directory, opts.encode_fs, "encoded"),
             cache_gen: Default::default(),
+            cache_gen_paths: Default::default(),
             cache_fs: Default::default(),
             cache_fs_files: Default::default(),
+            cache_fs_paths: Default::default(),
             cache_gen_size: Default::default(),
             cache_fs_size: Default::default(),
+            cache_gen_lru: Default::default(),
+            cache_fs_lru: Default::default(),
+            cache_gen_stats: Default::default(),
+            cache_fs_stats: Default::default(),
             encoded_filesystem_limit: opts.encoded_filesystem_limit.unwrap_or(u64::MAX),
             encoded_generated_limit: opts.encoded_generated_limit.unwrap_or(u64::MAX),
-            proxies: opts.proxies.clone(),
-            proxy_redirs: opts.proxy_redirs.clone(),
-            mime_type_overrides: opts.mime_type_overrides.clone(),
-            additional_headers: opts.additional_headers.clone(),
+            encoded_persist: opts.encoded_persist,
+            encode_streaming: opts.encode_streaming.unwrap_or(u64::MAX),
+            encoder_threads: opts.encoder_threads,
+            encode_prewarm: opts.encode_prewarm,
+            encoder_queue: OnceLock::new(),
+            encoding_in_progress: Default::default(),
+            audit_log: OnceLock::new(),
+            auth_failures: AuthFailures::new(opts.auth_failures, opts.auth_lockout),
+            replacing: Default::default(),
+            verified_passwords: Default::default(),
             allowed_methods: allowed_methods,
         }
     }
 
-    pub fn clean_temp_dirs(&self, temp_directory: &(String, PathBuf), generate_tls: bool) {
-        mem::forget(self.cache_fs_files.write());
-        mem::forget(self.cache_fs.write());
+    pub fn clean_temp_dirs(&self, temp_directory: &(String, PathBuf), generate_tls: bool, listen: &[Listen]) {
+        let cache_fs_files = self.cache_fs_files.write().expect("Filesystem file cache write lock poisoned");
+        let cache_fs = self.cache_fs.write().expect("Filesystem cache write lock poisoned");
+
+        let mut encoded_temp_dir = self.encoded_temp_dir.as_ref();
+        if self.encoded_persist {
+            match self.save_encoded_index(&cache_fs_files, &cache_fs) {
+                Ok(()) => encoded_temp_dir = None,
+                Err(err) => {
+                    log!(self.log, "Failed to save encoded file index: {}", err);
+                }
+            }
+        }
+        mem::forget(cache_fs_files);
+        mem::forget(cache_fs);
 
         let tls = HttpHandler::temp_subdir(temp_directory, generate_tls, "tls");
-        for (temp_name, temp_dir) in [self.writes_temp_dir.as_ref(), self.encoded_temp_dir.as_ref(), tls.as_ref()].iter().flatten() {
+        for (temp_name, temp_dir) in [self.writes_temp_dir.as_ref(), encoded_temp_dir, tls.as_ref()].iter().flatten() {
             if fs::remove_dir_all(&temp_dir).is_ok() {
                 log!(self.log, "Deleted temp dir {magenta}{}{reset}", temp_name);
             }
@@ -231,6 +436,13 @@ impl HttpHandler {
         if fs::remove_dir(&temp_directory.1).is_ok() {
             log!(self.log, "Deleted temp dir {magenta}{}{reset}", temp_directory.0);
         }
+        for l in listen {
+            if let ListenAddress::Unix(ref path, _) = l.address {
+                if fs::remove_file(path).is_ok() {
+                    log!(self.log, "Deleted socket {magenta}{}{reset}", path.display());
+                }
+            }
+        }
     }
 
     fn temp_subdir(&(ref temp_name, ref temp_dir): &(String, PathBuf), flag: bool, name: &str) -> Option<(String, PathBuf)> {
@@ -252,8 +464,15 @@ impl HttpHandler {
 
 impl Handler for &'static HttpHandler {
     fn handle(&self, req: &mut Request) -> IronResult<Response> {
-        if self.global_auth_data.is_some() || !self.path_auth_data.is_empty() {
-            if let Some(resp) = self.verify_auth(req)? {
+        let live = self.live();
+        let site = live.site(req.url.as_ref());
+        if site.global_auth_data.is_some() || !site.path_auth_data.is_empty() {
+            if let Some(resp) = self.verify_auth(req, site, live.auth_writes_only)? {
+                return Ok(resp);
+            }
+        }
+        if !site.path_permissions.is_empty() {
+            if let Some(resp) = self.verify_permissions(req, site)? {
                 return Ok(resp);
             }
         }
@@ -282,37 +501,23 @@ impl Handler for &'static HttpHandler {
         if self.webdav >= WebDavLevel::All {
             resp.headers.set(Dav::LEVEL_1);
         }
-        for (h, v) in &self.additional_headers {
-            resp.headers.append_raw(&h[..], v[..].into());
+        for (h, v) in &site.additional_headers {
+            resp.headers.append_raw(h.clone(), v.clone().into());
         }
         Ok(resp)
     }
 }
 
 impl HttpHandler {
-    fn verify_auth(&self, req: &mut Request) -> IronResult<Option<Response>> {
-        let mut auth = self.global_auth_data.as_ref();
-
-        if !self.path_auth_data.is_empty() {
-            let mut path = req.url.as_ref().path();
-            if path.starts_with('/') {
-                path = &path[1..];
-            }
-            if path.ends_with('/') {
-                path = &path[..path.len() - 1];
-            }
-
-            while !path.is_empty() {
-                if let Some(pad) = self.path_auth_data.get(path) {
-                    auth = pad.as_ref();
-                    break;
-                }
-
-                path = &path[..path.rfind('/').unwrap_or(0)];
-            }
-        }
+    /// The current `LiveSettings`; hold on to them for the whole request, so it sees them consistently
+    pub fn live(&self) -> Arc<LiveSettings> {
+        self.live.read().expect("Live settings read lock poisoned").clone()
+    }
 
-        let auth = if let Some(auth) = auth {
+    /// Check the request's credentials, if its path needs any; with `writes_only`, only requests that could change files need them,
+    /// but the others' are still checked, if supplied
+    fn verify_auth(&self, req: &mut Request, site: &SiteSettings, writes_only: bool) -> IronResult<Option<Response>> {
+        let auth = if let Some(auth) = site.auth_for(req.url.as_ref()) {
             auth
         } else {
             return Ok(None);
@@ -320,52 +525,103 @@ impl HttpHandler {
 
         match req.headers.get() {
             Some(headers::Authorization(headers::Basic { username, password })) => {
-                let pwd = if password == &Some(String::new()) {
-                    &None
+                let client = self.remote_addresses(&req).lockout_key();
+                if let Some(left) = client.as_ref().and_then(|client| self.auth_failures.locked_out(client)) {
+                    log!(self.log,
+                         "{} requested to {red}{}{reset} {yellow}{}{reset} as {magenta}{}{reset} while locked out for {}s more",
+                         self.remote_addresses(&req),
+                         req.method,
+                         req.url,
+                         username,
+                         left.as_secs_f64().ceil() as u64);
+                    self.audit(req, AuditEvent::LockedOut, Some(username));
+
+                    return Ok(Some(HttpHandler::handle_locked_out(left)));
+                }
+
+                let pwd = password.as_ref().map_or("", |s| &s[..]);
+
+                // Look through every account, so the time taken doesn't depend on which one it is
+                let account = auth.iter().fold(None, |found, acc| if constant_time_eq(acc.0.as_bytes(), username.as_bytes()) && found.is_none() {
+                    Some(acc)
                 } else {
-                    password
+                    found
+                });
+                let matches = match account {
+                    Some((_, p)) => self.verified_passwords.matches(p, pwd),
+                    None => {
+                        // Still check one, so unknown usernames can't be told from known ones by how quickly they're rejected
+                        password_matches(if auth.iter().any(|(_, p)| is_password_hash(p)) { DUMMY_PASSWORD_HASH } else { "" }, pwd);
+                        false
+                    }
                 };
-
-                if &auth.0 == username && &auth.1 == pwd {
+                if matches {
                     log!(self.log,
                          "{} correctly authorised to {red}{}{reset} {yellow}{}{reset}",
                          self.remote_addresses(&req),
                          req.method,
                          req.url);
+                    self.audit(req, AuditEvent::Success, Some(username));
+                    if let Some(client) = client.as_ref() {
+                        self.auth_failures.succeeded(client);
+                    }
 
                     Ok(None)
                 } else {
+                    // Never the password, which might be nearly right
                     log!(self.log,
-                         "{} requested to {red}{}{reset} {yellow}{}{reset} with invalid credentials \"{}{}{}\"",
+                         "{} requested to {red}{}{reset} {yellow}{}{reset} with invalid credentials for {magenta}{}{reset}",
                          self.remote_addresses(&req),
                          req.method,
                          req.url,
-                         username,
-                         if password.is_some() { ":" } else { "" },
-                         password.as_ref().map_or("", |s| &s[..]));
-
+                         username);
+                    self.audit(req, AuditEvent::Invalid, Some(username));
+
+                    if let Some((failures, lockout)) = client.as_ref().and_then(|client| self.auth_failures.failed(client)) {
+                        log!(self.log,
+                             "{} locked out for {red}{}s{reset} after {} invalid credentials in a row",
+                             self.remote_addresses(&req),
+                             lockout.as_secs(),
+                             failures);
+                        return Ok(Some(HttpHandler::handle_locked_out(lockout)));
+                    }
                     Ok(Some(Response::with((status::Unauthorized, Header(WwwAuthenticate("basic".into())), "Supplied credentials invalid.\n"))))
                 }
             }
-            None => {
-                log!(self.log,
-                     "{} requested to {red}{}{reset} {yellow}{}{reset} without authorisation",
-                     self.remote_addresses(&req),
-                     req.method,
-                     req.url);
-
-                Ok(Some(Response::with((status::Unauthorized, Header(WwwAuthenticate("basic".into())), "Credentials required.\n"))))
-            }
+            None if writes_only && matches!(req.method, method::Get | method::Head | method::Options | method::DavPropfind) => Ok(None),
+            None => Ok(Some(self.handle_credentials_required(req))),
         }
     }
 
+    fn handle_credentials_required(&self, req: &Request) -> Response {
+        log!(self.log,
+             "{} requested to {red}{}{reset} {yellow}{}{reset} without authorisation",
+             self.remote_addresses(&req),
+             req.method,
+             req.url);
+        self.audit(req, AuditEvent::Missing, None);
+
+        Response::with((status::Unauthorized, Header(WwwAuthenticate("basic".into())), "Credentials required.\n"))
+    }
+
+    fn handle_locked_out(left: Duration) -> Response {
+        Response::with((status::TooManyRequests,
+                        Header(RetryAfter(left.as_secs_f64().ceil() as u64)),
+                        "Too many invalid credentials, try again later.\n"))
+    }
+
+    /// Whether the caller didn't supply credentials, but could, for the requested path, which only happens with `--auth-writes-only`
+    fn caller_can_log_in(&self, req: &Request) -> bool {
+        self.live().site(req.url.as_ref()).auth_for(req.url.as_ref()).is_some() && !req.headers.has::<headers::Authorization<headers::Basic>>()
+    }
+
     fn handle_options(&self, req: &mut Request) -> IronResult<Response> {
         log!(self.log, "{} asked for {red}OPTIONS{reset}", self.remote_addresses(&req));
-        Ok(Response::with((status::NoContent, Header(headers::Server(USER_AGENT.into())), Header(headers::Allow(self.allowed_methods.into())))))
+        Ok(Response::with((status::NoContent, Header(headers::Server(USER_AGENT.into())), Header(headers::Allow(self.caller_allowed_methods(req))))))
     }
 
     fn handle_get(&self, req: &mut Request) -> IronResult<Response> {
-        let (mut req_p, symlink, url_err) = self.parse_requested_path(req);
+        let (mut req_p, symlink, url_err, mount) = self.parse_requested_path(req);
 
         if url_err {
             return self.handle_invalid_url(req, "<p>Percent-encoding decoded to invalid UTF-8.</p>");
@@ -378,26 +634,30 @@ impl HttpHandler {
         }
 
         if !req_p.exists() || (symlink && !self.follow_symlinks) ||
-           (symlink && self.follow_symlinks && self.sandbox_symlinks && !is_descendant_of(&req_p, &self.hosted_directory.1)) {
+           (symlink && self.follow_symlinks && mount.sandbox_symlinks && !is_descendant_of(&req_p, &mount.directory.1)) {
             return self.handle_nonexistent_get(req, req_p);
         }
 
-        let is_file = is_actually_file(&req_p.metadata().expect("Failed to get file metadata").file_type(), &req_p);
-        let range = req.headers.get_mut().map(|r: &mut headers::Range| mem::replace(r, headers::Range::Bytes(vec![])));
+        let metadata = req_p.metadata().expect("Failed to get file metadata");
+        let is_file = is_actually_file(&metadata.file_type(), &req_p);
+        let range = req.headers
+            .get_mut()
+            .map(|r: &mut headers::Range| mem::replace(r, headers::Range::Bytes(vec![])))
+            .filter(|_| HttpHandler::if_range_matches(req, &file_etag(&metadata), &file_time_modified(&metadata)));
         let raw_fs = req.headers.get().map(|r: &RawFsApiHeader| r.0).unwrap_or(false);
         if is_file {
             if raw_fs {
-                self.handle_get_raw_fs_file(req, req_p)
+                self.handle_get_raw_fs_file(req, req_p, mount)
             } else if let Some(range) = range {
-                self.handle_get_file_range(req, req_p, range)
+                self.handle_get_file_range(req, req_p, range, mount)
             } else {
-                self.handle_get_file(req, &req_p, false)
+                self.handle_get_file(req, &req_p, false, Some(mount))
             }
         } else {
             if raw_fs {
-                self.handle_get_raw_fs_dir(req, req_p)
+                self.handle_get_raw_fs_dir(req, req_p, mount)
             } else {
-                self.handle_get_dir(req, req_p)
+                self.handle_get_dir(req, req_p, mount)
             }
         }
     }
@@ -423,7 +683,7 @@ impl HttpHandler {
     }
 
     fn handle_nonexistent_get(&self, req: &mut Request, req_p: PathBuf) -> IronResult<Response> {
-        self.handle_nonexistent_status_impl(req, req_p, status::NotFound, &self.try_404)
+        self.handle_nonexistent_status_impl(req, req_p, status::NotFound, &self.site(req.url.as_ref()).try_404)
     }
 
     fn handle_nonexistent_status_impl(&self, req: &mut Request, req_p: PathBuf, status: status::Status, try_404: &Option<PathBuf>) -> IronResult<Response> {
@@ -435,7 +695,7 @@ impl HttpHandler {
 
         if let Some(try_404) = try_404.as_ref() {
             if try_404.metadata().map(|m| !m.is_dir()).unwrap_or(false) {
-                return self.handle_get_file(req, try_404, true)
+                return self.handle_get_file(req, try_404, true, None)
             }
         }
 
@@ -447,14 +707,14 @@ impl HttpHandler {
                                                            ""))
     }
 
-    fn handle_get_raw_fs_file(&self, req: &mut Request, req_p: PathBuf) -> IronResult<Response> {
+    fn handle_get_raw_fs_file(&self, req: &mut Request, req_p: PathBuf, mount: &Mount) -> IronResult<Response> {
         log!(self.log,
              "{} was served metadata for file {magenta}{}{reset}",
              self.remote_addresses(&req),
              req_p.display());
         self.handle_raw_fs_api_response(status::Ok,
                                         &FilesetData {
-                                            writes_supported: self.writes_temp_dir.is_some(),
+                                            writes_supported: mount.allow_writes,
                                             is_root: false,
                                             is_file: true,
                                             files: vec![get_raw_fs_metadata(&req_p)],
@@ -465,40 +725,123 @@ impl HttpHandler {
         req_tags.iter().any(|retag| retag.tag() == etag)
     }
 
-    fn should_304_path(req: &mut Request, req_p: &Path, etag: &str) -> bool {
-        if let Some(headers::IfNoneMatch::Items(inm)) = req.headers.get::<headers::IfNoneMatch>() {
-            if HttpHandler::etag_match(inm, &etag) {
-                return true;
+    fn etag_match_strong(req_tags: &[headers::EntityTag], etag: &str) -> bool {
+        req_tags.iter().any(|retag| !retag.weak && retag.tag() == etag)
+    }
+
+    /// Evaluate the If-Match, If-Unmodified-Since, If-None-Match, and If-Modified-Since headers,
+    /// in the order specified by https://tools.ietf.org/html/rfc7232#section-6
+    ///
+    /// `etag` and `modified` describe the current representation, or are `None` if the resource doesn't exist.
+    fn evaluate_preconditions(req: &Request, etag: Option<&str>, modified: Option<&Tm>) -> Precondition {
+        let get_head = req.method == method::Get || req.method == method::Head;
+
+        match req.headers.get::<headers::IfMatch>() {
+            Some(headers::IfMatch::Any) => {
+                if etag.is_none() {
+                    return Precondition::Failed;
+                }
             }
-        } else if let Some(headers::IfModifiedSince(since)) = req.headers.get::<headers::IfModifiedSince>() {
-            // unavoidable truncation, the timestamp format is second-resolution; to_timespec() is what <Tm as Ord> does
-            if file_time_modified_p(req_p).to_timespec().sec <= since.0.to_timespec().sec {
-                return true;
+            Some(headers::IfMatch::Items(im)) => {
+                if !etag.map(|etag| HttpHandler::etag_match_strong(im, etag)).unwrap_or(false) {
+                    return Precondition::Failed;
+                }
+            }
+            None => {
+                if let (Some(headers::IfUnmodifiedSince(since)), Some(modified)) = (req.headers.get::<headers::IfUnmodifiedSince>(), modified) {
+                    // unavoidable truncation, the timestamp format is second-resolution; to_timespec() is what <Tm as Ord> does
+                    if modified.to_timespec().sec > since.0.to_timespec().sec {
+                        return Precondition::Failed;
+                    }
+                }
             }
         }
 
-        return false;
+        let matched = if get_head {
+            Precondition::NotModified
+        } else {
+            Precondition::Failed
+        };
+        match req.headers.get::<headers::IfNoneMatch>() {
+            Some(headers::IfNoneMatch::Any) => {
+                if etag.is_some() {
+                    return matched;
+                }
+            }
+            Some(headers::IfNoneMatch::Items(inm)) => {
+                if etag.map(|etag| HttpHandler::etag_match(inm, etag)).unwrap_or(false) {
+                    return matched;
+                }
+            }
+            None => {
+                if let (true, Some(headers::IfModifiedSince(since)), Some(modified)) = (get_head, req.headers.get::<headers::IfModifiedSince>(), modified) {
+                    if modified.to_timespec().sec <= since.0.to_timespec().sec {
+                        return Precondition::NotModified;
+                    }
+                }
+            }
+        }
+
+        Precondition::Passed
+    }
+
+    fn evaluate_preconditions_path(req: &Request, req_p: &Path) -> Precondition {
+        match req_p.metadata() {
+            Ok(metadata) => HttpHandler::evaluate_preconditions(req, Some(&file_etag(&metadata)), Some(&file_time_modified(&metadata))),
+            Err(_) => HttpHandler::evaluate_preconditions(req, None, None),
+        }
+    }
+
+    /// Check whether the If-Range validator, if any, still describes the file, i.e. whether the Range header is to be honoured
+    ///
+    /// Partial responses are tagged "etag+ranges", so those count as the same representation.
+    fn if_range_matches(req: &Request, etag: &str, modified: &Tm) -> bool {
+        match req.headers.get::<headers::IfRange>() {
+            None => true,
+            Some(headers::IfRange::EntityTag(tag)) => !tag.weak && tag.tag().strip_prefix(etag).map(|rest| rest.is_empty() || rest.starts_with('+')).unwrap_or(false),
+            Some(headers::IfRange::Date(date)) => modified.to_timespec().sec == date.0.to_timespec().sec,
+        }
+    }
+
+    fn handle_precondition_failed(&self, req: &mut Request) -> IronResult<Response> {
+        self.handle_generated_response_encoding(req,
+                                                status::PreconditionFailed,
+                                                error_html("412 Precondition Failed",
+                                                           format_args!("The preconditions for {} weren't met.", url_path(&req.url)),
+                                                           ""))
     }
 
-    fn handle_get_file_range(&self, req: &mut Request, req_p: PathBuf, range: headers::Range) -> IronResult<Response> {
+    fn handle_get_file_range(&self, req: &mut Request, req_p: PathBuf, range: headers::Range, mount: &Mount) -> IronResult<Response> {
         match range {
             headers::Range::Bytes(ref brs) => {
-                if brs.len() == 1 {
-                    let metadata = req_p.metadata().expect("Failed to get requested file metadata");
-                    let flen = file_length(&metadata, &req_p);
-
-                    let mut etag = file_etag(&metadata).into_bytes(); // normaletag+123-41231
-                    let _ = write!(&mut etag, "+{}", brs[0]);
-                    let etag = unsafe { String::from_utf8_unchecked(etag) };
-                    if HttpHandler::should_304_path(req, &req_p, &etag) {
+                let metadata = req_p.metadata().expect("Failed to get requested file metadata");
+                let flen = file_length(&metadata, &req_p);
+
+                // The preconditions are about the file, not the ranges of it
+                let full_etag = file_etag(&metadata);
+                match HttpHandler::evaluate_preconditions(req, Some(&full_etag), Some(&file_time_modified(&metadata))) {
+                    Precondition::Passed => {}
+                    Precondition::NotModified => {
                         log!(self.log, "{} Not Modified", self.remote_addresses(req));
                         return Ok(Response::with((status::NotModified,
                                                   (Header(headers::Server(USER_AGENT.into())),
                                                    Header(headers::LastModified(headers::HttpDate(file_time_modified_p(&req_p)))),
                                                    Header(headers::AcceptRanges(headers::RangeUnit::Bytes))),
-                                                  Header(headers::ETag(headers::EntityTag::strong(etag))))));
+                                                  Header(headers::ETag(headers::EntityTag::strong(full_etag))))));
+                    }
+                    Precondition::Failed => {
+                        log!(self.log, "{} Precondition Failed", self.remote_addresses(req));
+                        return self.handle_precondition_failed(req);
                     }
+                }
+
+                let mut etag = full_etag.into_bytes(); // normaletag+123-41231,-5
+                for (i, br) in brs.iter().enumerate() {
+                    let _ = write!(&mut etag, "{}{}", if i == 0 { '+' } else { ',' }, br);
+                }
+                let etag = unsafe { String::from_utf8_unchecked(etag) };
 
+                if brs.len() == 1 {
                     match brs[0] {
                         // Cases where from is bigger than to are filtered out by iron so can never happen
                         headers::ByteRangeSpec::FromTo(from, to) => self.handle_get_file_closed_range(req, req_p, from, to, etag),
@@ -518,7 +861,21 @@ impl HttpHandler {
                         }
                     }
                 } else {
-                    self.handle_invalid_range(req, req_p, &range, "More than one range is unsupported.")
+                    let ranges = satisfiable_byte_ranges(brs, flen);
+                    match ranges.len() {
+                        0 => self.handle_invalid_range(req, req_p, &range, "None of the ranges are satisfiable."),
+                        1 => self.handle_get_file_closed_range(req, req_p, ranges[0].0, ranges[0].1, etag),
+                        n if n > MAX_BYTE_RANGES => {
+                            // Allowed to be ignored instead (RFC 7233 section 3.1), and nothing's wrong with the ranges themselves
+                            log!(self.log,
+                                 "{} requested {} byte ranges of {magenta}{}{reset}, serving the whole file",
+                                 self.remote_addresses(req),
+                                 n,
+                                 req_p.display());
+                            self.handle_get_file(req, &req_p, false, Some(mount))
+                        }
+                        _ => self.handle_get_file_multi_range(req, req_p, ranges, flen, etag),
+                    }
                 }
             }
             headers::Range::Unregistered(..) => self.handle_invalid_range(req, req_p, &range, "Custom ranges are unsupported."),
@@ -535,21 +892,7 @@ impl HttpHandler {
              req_p.display(),
              mime_type);
 
-        let mut f = File::open(&req_p).expect("Failed to open requested file");
-        f.seek(SeekFrom::Start(from)).expect("Failed to seek requested file");
-
-        Ok(Response::with((status::PartialContent,
-                           (Header(headers::Server(USER_AGENT.into())),
-                            Header(headers::LastModified(headers::HttpDate(file_time_modified_p(&req_p)))),
-                            Header(headers::ContentRange(headers::ContentRangeSpec::Bytes {
-                                range: Some((from, to)),
-                                instance_length: Some(file_length(&f.metadata().expect("Failed to get requested file metadata"), &req_p)),
-                            })),
-                            Header(headers::ETag(headers::EntityTag::strong(etag))),
-                            Header(headers::AcceptRanges(headers::RangeUnit::Bytes))),
-                           f,
-                           mime_type,
-                           Header(headers::ContentLength(to + 1 - from)))))
+        self.handle_get_file_opened_range(req_p, |_| (from, to), mime_type, etag)
     }
 
     fn handle_get_file_right_opened_range(&self, req: &mut Request, req_p: PathBuf, from: u64, etag: String) -> IronResult<Response> {
@@ -561,7 +904,7 @@ impl HttpHandler {
              from,
              mime_type);
 
-        self.handle_get_file_opened_range(req_p, |flen| (SeekFrom::Start(from), from, flen - from), mime_type, etag)
+        self.handle_get_file_opened_range(req_p, |flen| (from, flen - 1), mime_type, etag)
     }
 
     fn handle_get_file_left_opened_range(&self, req: &mut Request, req_p: PathBuf, from: u64, etag: String) -> IronResult<Response> {
@@ -573,30 +916,64 @@ impl HttpHandler {
              req_p.display(),
              mime_type);
 
-        self.handle_get_file_opened_range(req_p, |flen| (SeekFrom::End(-(from as i64)), flen - from, from), mime_type, etag)
+        self.handle_get_file_opened_range(req_p, |flen| (flen - from, flen - 1), mime_type, etag)
     }
 
-    fn handle_get_file_opened_range<F: FnOnce(u64) -> (SeekFrom, u64, u64)>(&self, req_p: PathBuf, cb: F, mt: Mime, etag: String) -> IronResult<Response> {
-        let mut f = File::open(&req_p).expect("Failed to open requested file");
+    /// Serve the inclusive range `cb` returns for the file's length
+    fn handle_get_file_opened_range<F: FnOnce(u64) -> (u64, u64)>(&self, req_p: PathBuf, cb: F, mt: Mime, etag: String) -> IronResult<Response> {
+        let f = File::open(&req_p).expect("Failed to open requested file");
         let fmeta = f.metadata().expect("Failed to get requested file metadata");
         let flen = file_length(&fmeta, &req_p);
-        let (s, b_from, clen) = cb(flen);
-        f.seek(s).expect("Failed to seek requested file");
+        let range = cb(flen);
+        let f = seek_byte_range(f, range).expect("Failed to seek requested file");
 
         Ok(Response::with((status::PartialContent,
-                           f,
+                           BodyReader(f),
                            (Header(headers::Server(USER_AGENT.into())),
                             Header(headers::LastModified(headers::HttpDate(file_time_modified(&fmeta)))),
-                            Header(headers::ContentRange(headers::ContentRangeSpec::Bytes {
-                                range: Some((b_from, flen - 1)),
-                                instance_length: Some(flen),
-                            })),
+                            Header(headers::ContentRange(byte_range_content_range(range, flen))),
                             Header(headers::ETag(headers::EntityTag::strong(etag))),
-                            Header(headers::ContentLength(clen)),
+                            Header(headers::ContentLength(range.1 + 1 - range.0)),
                             Header(headers::AcceptRanges(headers::RangeUnit::Bytes))),
                            mt)))
     }
 
+    fn handle_get_file_multi_range(&self, req: &mut Request, req_p: PathBuf, ranges: Vec<(u64, u64)>, flen: u64, etag: String) -> IronResult<Response> {
+        let mime_type = self.guess_mime_type(&req_p);
+        log!(self.log,
+             "{} was served byte ranges {} of file {magenta}{}{reset} as {blue}{}{reset}",
+             self.remote_addresses(&req),
+             CommaList(ranges.iter().map(|&(from, to)| DisplayThree(from, "-", to))),
+             req_p.display(),
+             mime_type);
+
+        let f = match File::open(&req_p) {
+            Ok(f) => f,
+            Err(err) => return self.handle_requested_entity_unopenable(req, err, "file"),
+        };
+        let boundary = multipart_boundary();
+        let body = MultipartByteRanges {
+            file: f,
+            ranges: ranges,
+            boundary: boundary.clone(),
+            mime_type: mime_type,
+            file_length: flen,
+        };
+        let clen = body.content_length();
+
+        Ok(Response::with((status::PartialContent,
+                           (Header(headers::Server(USER_AGENT.into())),
+                            Header(headers::LastModified(headers::HttpDate(file_time_modified_p(&req_p)))),
+                            Header(headers::ETag(headers::EntityTag::strong(etag))),
+                            Header(headers::AcceptRanges(headers::RangeUnit::Bytes))),
+                           // multipart/byteranges; boundary=...
+                           Mime(MimeTopLevel::Multipart,
+                                MimeSubLevel::Ext("byteranges".to_string()),
+                                vec![(MimeAttr::Boundary, MimeAttrValue::Ext(boundary))]),
+                           Box::new(body) as Box<dyn WriteBody>,
+                           Header(headers::ContentLength(clen)))))
+    }
+
     fn handle_invalid_range(&self, req: &mut Request, req_p: PathBuf, range: &headers::Range, reason: &str) -> IronResult<Response> {
         self.handle_generated_response_encoding(req,
                                                 status::RangeNotSatisfiable,
@@ -627,7 +1004,7 @@ impl HttpHandler {
                            mime_type)))
     }
 
-    fn handle_get_file(&self, req: &mut Request, req_p: &PathBuf, is_404: bool) -> IronResult<Response> {
+    fn handle_get_file(&self, req: &mut Request, req_p: &PathBuf, is_404: bool, mount: Option<&Mount>) -> IronResult<Response> {
         let mime_type = self.guess_mime_type(&req_p);
         log!(self.log,
              "{} was served file {magenta}{}{reset} as {blue}{}{reset}",
@@ -640,136 +1017,167 @@ impl HttpHandler {
         let headers = (Header(headers::Server(USER_AGENT.into())),
                        Header(headers::LastModified(headers::HttpDate(file_time_modified(&metadata)))),
                        Header(headers::AcceptRanges(headers::RangeUnit::Bytes)));
-        if HttpHandler::should_304_path(req, &req_p, &etag) {
-            log!(self.log, "{} Not Modified", self.remote_addresses(req).as_spaces());
-            return Ok(Response::with((status::NotModified, headers, Header(headers::ETag(headers::EntityTag::strong(etag))))));
+        match HttpHandler::evaluate_preconditions(req, Some(&etag), Some(&file_time_modified(&metadata))) {
+            Precondition::Passed => {}
+            Precondition::NotModified => {
+                log!(self.log, "{} Not Modified", self.remote_addresses(req).as_spaces());
+                return Ok(Response::with((status::NotModified, headers, Header(headers::ETag(headers::EntityTag::strong(etag))))));
+            }
+            Precondition::Failed => {
+                log!(self.log, "{} Precondition Failed", self.remote_addresses(req).as_spaces());
+                return self.handle_precondition_failed(req);
+            }
+        }
+
+        if self.precompressed {
+            if let Some((file, encoding, len)) = self.precompressed_sibling(req, &req_p, &metadata, mount) {
+                log!(self.log, "{} precompressed as {}", self.remote_addresses(req).as_spaces(), encoding);
+                return Ok(Response::with((if is_404 { status::NotFound } else { status::Ok },
+                                          headers,
+                                          (Header(headers::ETag(headers::EntityTag::strong(etag))),
+                                           Header(headers::ContentEncoding([encoding].into())),
+                                           Header(headers::Vary::Items(vec!["Accept-Encoding".parse().unwrap()]))),
+                                          file,
+                                          mime_type,
+                                          Header(headers::ContentLength(len)))));
+            }
         }
 
         let flen = file_length(&metadata, &req_p);
-        if self.encoded_temp_dir.is_some() && flen > MIN_ENCODING_SIZE && flen < MAX_ENCODING_SIZE &&
-           req_p.extension().map(|s| !extension_is_blacklisted(s)).unwrap_or(true) {
-            self.handle_get_file_encoded(req, &req_p, mime_type, headers, etag)
-        } else {
-            let file = match File::open(&req_p) {
-                Ok(file) => file,
-                Err(err) => return self.handle_requested_entity_unopenable(req, err, "file"),
-            };
-            Ok(Response::with((if is_404 { status::NotFound } else { status::Ok },
-                               headers,
-                               Header(headers::ETag(headers::EntityTag::strong(etag))),
-                               file,
-                               mime_type,
-                               Header(headers::ContentLength(file_length(&metadata, &req_p))))))
+        if self.encoded_temp_dir.is_some() && flen > MIN_ENCODING_SIZE && req_p.extension().map(|s| !extension_is_blacklisted(s)).unwrap_or(true) {
+            // Too big to encode ahead of time, but still streamable, if streaming's enabled at all
+            if self.encode_streaming != u64::MAX && (flen > self.encode_streaming || flen >= MAX_ENCODING_SIZE) {
+                // Chunked transfer encoding's HTTP/1.1-only
+                if req.version != HttpVersion::Http10 {
+                    if let Some(encoding) = req.headers.get_mut::<headers::AcceptEncoding>().and_then(|es| response_encoding(&mut **es)) {
+                        return self.handle_get_file_streamed(req, &req_p, is_404, mime_type, headers, etag, encoding);
+                    }
+                }
+            } else if flen < MAX_ENCODING_SIZE {
+                return self.handle_get_file_encoded(req, &req_p, mime_type, headers, etag);
+            }
         }
+
+        let file = match File::open(&req_p) {
+            Ok(file) => file,
+            Err(err) => return self.handle_requested_entity_unopenable(req, err, "file"),
+        };
+        Ok(Response::with((if is_404 { status::NotFound } else { status::Ok },
+                           headers,
+                           Header(headers::ETag(headers::EntityTag::strong(etag))),
+                           file,
+                           mime_type,
+                           Header(headers::ContentLength(file_length(&metadata, &req_p))))))
+    }
+
+    fn handle_get_file_streamed(&self, req: &mut Request, req_p: &PathBuf, is_404: bool, mt: Mime,
+                                headers: (Header<headers::Server>, Header<headers::LastModified>, Header<headers::AcceptRanges>), etag: String,
+                                encoding: Encoding)
+                                -> IronResult<Response> {
+        let file = match File::open(&req_p) {
+            Ok(file) => file,
+            Err(err) => return self.handle_requested_entity_unopenable(req, err, "file"),
+        };
+        log!(self.log, "{} encoded as {} (streamed)", self.remote_addresses(req).as_spaces(), encoding);
+
+        Ok(Response::with((if is_404 { status::NotFound } else { status::Ok },
+                           headers,
+                           Header(headers::ETag(headers::EntityTag::strong(etag))),
+                           (Header(headers::ContentEncoding([encoding.clone()].into())),
+                            Header(headers::Vary::Items(vec!["Accept-Encoding".parse().unwrap()]))),
+                           Box::new(EncodedFileStream {
+                               file: file,
+                               encoding: encoding,
+                               levels: self.compression_levels,
+                           }) as Box<dyn WriteBody>,
+                           mt)))
+    }
+
+    /// Find the most preferred accepted encoding with a `FILE.ext` sibling at least as new as `FILE`,
+    /// if it's not a symlink that `FILE` itself would be refused as under `mount`
+    fn precompressed_sibling(&self, req: &Request, req_p: &Path, metadata: &fs::Metadata, mount: Option<&Mount>) -> Option<(File, Encoding, u64)> {
+        let mut encodings = req.headers.get::<headers::AcceptEncoding>()?.0.clone();
+        encodings.sort_by(|lhs, rhs| rhs.quality.cmp(&lhs.quality));
+
+        let modified = file_time_modified(metadata).to_timespec();
+        encodings.into_iter().filter(|e| e.quality.0 != 0).find_map(|e| {
+            let mut sibling_p = req_p.as_os_str().to_os_string();
+            sibling_p.push(".");
+            sibling_p.push(precompressed_extension(&e.item)?);
+            let sibling_p = PathBuf::from(sibling_p);
+            if fs::symlink_metadata(&sibling_p).ok()?.file_type().is_symlink() &&
+               (!self.follow_symlinks || mount.is_some_and(|mount| mount.sandbox_symlinks && !is_descendant_of(&sibling_p, &mount.directory.1))) {
+                return None;
+            }
+            let sibling = File::open(&sibling_p).ok()?;
+            let sibling_meta = sibling.metadata().ok()?;
+            if !sibling_meta.is_file() || file_time_modified(&sibling_meta).to_timespec() < modified {
+                return None;
+            }
+            Some((sibling, e.item, sibling_meta.len()))
+        })
     }
 
     fn handle_get_file_encoded(&self, req: &mut Request, req_p: &PathBuf, mt: Mime,
                                headers: (Header<headers::Server>, Header<headers::LastModified>, Header<headers::AcceptRanges>), etag: String)
                                -> IronResult<Response> {
         if let Some(encoding) = req.headers.get_mut::<headers::AcceptEncoding>().and_then(|es| response_encoding(&mut **es)) {
-            self.create_temp_dir(&self.encoded_temp_dir);
-
             let hash = self.cache_fs_files.read().expect("Filesystem file cache read lock poisoned").get(&etag).cloned();
-            let hash = match hash {
-                Some(hash) => hash,
-                None => {
-                    match file_hash(&req_p) {
-                        Ok(h) => {
-                            self.cache_fs_files.write().expect("Filesystem file cache write lock poisoned").insert(etag.clone(), h);
-                            h
-                        }
-                        Err(err) => return self.handle_requested_entity_unopenable(req, err, "file"),
-                    }
-                }
-            };
-            let cache_key = (hash, encoding.0);
-
-            let forgor = {
-                match self.cache_fs.read().expect("Filesystem cache read lock poisoned").get(&cache_key) {
-                    Some(&((ref resp_p, true, _), ref atime)) => {
-                        match File::open(resp_p) {
-                            Ok(resp) => {
-                                atime.store(precise_time_ns(), AtomicOrdering::Relaxed);
-                                log!(self.log,
-                                     "{} encoded as {} for {:.1}% ratio (cached)",
-                                     self.remote_addresses(req).as_spaces(),
-                                     encoding,
-                                     ((file_length(&req_p.metadata().expect("Failed to get requested file metadata"), &req_p) as f64) /
-                                      (file_length(&resp.metadata().expect("Failed to get encoded file metadata"), &resp_p) as f64)) *
-                                     100f64);
-
-                                return Ok(Response::with((status::Ok,
-                                                          headers,
-                                                          Header(headers::ETag(headers::EntityTag::strong(etag))),
-                                                          Header(headers::ContentEncoding([encoding].into())),
-                                                          resp,
-                                                          mt)));
-                            }
-                            Err(err) if err.kind() == IoErrorKind::NotFound => true,
-                            e @ Err(_) => {
-                                e.expect("Failed to get encoded file metadata");
-                                unsafe { std::hint::unreachable_unchecked() }
+            if let Some(hash) = hash {
+                let cache_key = (hash, encoding.0);
+
+                let forgor = {
+                    match self.cache_fs.read().expect("Filesystem cache read lock poisoned").get(&cache_key) {
+                        Some(&((ref resp_p, true, _), ref atime)) => {
+                            match File::open(resp_p) {
+                                Ok(resp) => {
+                                    atime.store(precise_time_ns(), AtomicOrdering::Relaxed);
+                                    self.cache_fs_stats.hit();
+                                    log!(self.log,
+                                         "{} encoded as {} for {:.1}% ratio (cached)",
+                                         self.remote_addresses(req).as_spaces(),
+                                         encoding,
+                                         ((file_length(&req_p.metadata().expect("Failed to get requested file metadata"), &req_p) as f64) /
+                                          (file_length(&resp.metadata().expect("Failed to get encoded file metadata"), &resp_p) as f64)) *
+                                         100f64);
+
+                                    return Ok(Response::with((status::Ok,
+                                                              headers,
+                                                              Header(headers::ETag(headers::EntityTag::strong(etag))),
+                                                              Header(headers::ContentEncoding([encoding].into())),
+                                                              resp,
+                                                              mt)));
+                                }
+                                Err(err) if err.kind() == IoErrorKind::NotFound => true,
+                                e @ Err(_) => {
+                                    e.expect("Failed to get encoded file metadata");
+                                    unsafe { std::hint::unreachable_unchecked() }
+                                }
                             }
                         }
+                        Some(&((_, false, _), _)) => {
+                            self.cache_fs_stats.hit();
+                            let file = match File::open(&req_p) {
+                                Ok(file) => file,
+                                Err(err) => return self.handle_requested_entity_unopenable(req, err, "file"),
+                            };
+                            return Ok(Response::with((status::Ok, headers, Header(headers::ETag(headers::EntityTag::strong(etag))), file, mt)));
+                        }
+                        None => false,
                     }
-                    Some(&((_, false, _), _)) => {
-                        let file = match File::open(&req_p) {
-                            Ok(file) => file,
-                            Err(err) => return self.handle_requested_entity_unopenable(req, err, "file"),
-                        };
-                        return Ok(Response::with((status::Ok, headers, Header(headers::ETag(headers::EntityTag::strong(etag))), file, mt)));
+                };
+                if forgor {
+                    self.cache_fs_files.write().expect("Filesystem file cache write lock poisoned").retain(|_, v| *v == hash);
+                    if let Some(((_, _, size), _)) = self.cache_fs.write().expect("Filesystem cache write lock poisoned").remove(&cache_key) {
+                        self.cache_fs_size.fetch_sub(size, AtomicOrdering::Relaxed);
                     }
-                    None => false,
+                    return self.handle_get_file_encoded(req, req_p, mt, headers, etag);
                 }
-            };
-            if forgor {
-                self.cache_fs_files.write().expect("Filesystem file cache write lock poisoned").retain(|_, v| *v == hash);
-                self.cache_fs.write().expect("Filesystem cache write lock poisoned").remove(&cache_key);
-                return self.handle_get_file_encoded(req, req_p, mt, headers, etag);
             }
 
-            let mut resp_p = self.encoded_temp_dir.as_ref().unwrap().1.join(cache_key.0.to_hex().as_str());
-            match (req_p.extension(), encoding_extension(&encoding)) {
-                (Some(ext), Some(enc)) => {
-                    let mut new_ext = ext.as_encoded_bytes().to_vec();
-                    new_ext.push(b'.');
-                    new_ext.extend_from_slice(enc.as_bytes());
-                    resp_p.set_extension(unsafe { OsStr::from_encoded_bytes_unchecked(&new_ext) })
-                }
-                (None, Some(enc)) => resp_p.set_extension(enc),
-                (_, None) => unsafe { std::hint::unreachable_unchecked() },
-            };
-
-            if encode_file(&req_p, &resp_p, &encoding) {
-                let resp_p_len = file_length(&resp_p.metadata().expect("Failed to get encoded file metadata"), &resp_p);
-                let gain = (file_length(&req_p.metadata().expect("Failed to get requested file metadata"), &req_p) as f64) / (resp_p_len as f64);
-                if gain < MIN_ENCODING_GAIN || resp_p_len > self.encoded_filesystem_limit {
-                    let mut cache = self.cache_fs.write().expect("Filesystem cache write lock poisoned");
-                    cache.insert(cache_key, ((PathBuf::new(), false, 0), AtomicU64::new(u64::MAX)));
-                    fs::remove_file(resp_p).expect("Failed to remove too big encoded file");
-                } else {
-                    log!(self.log,
-                         "{} encoded as {} for {:.1}% ratio",
-                         self.remote_addresses(req).as_spaces(),
-                         encoding,
-                         gain * 100f64);
-
-                    let mut cache = self.cache_fs.write().expect("Filesystem cache write lock poisoned");
-                    self.cache_fs_size.fetch_add(resp_p_len, AtomicOrdering::Relaxed);
-                    cache.insert(cache_key, ((resp_p.clone(), true, resp_p_len), AtomicU64::new(precise_time_ns())));
-
-                    return Ok(Response::with((status::Ok,
-                                              headers,
-                                              Header(headers::ETag(headers::EntityTag::strong(etag))),
-                                              Header(headers::ContentEncoding([encoding].into())),
-                                              resp_p.as_path(),
-                                              mt)));
-                }
-            } else {
-                log!(self.log,
-                     "{} failed to encode as {}, sending identity",
-                     self.remote_addresses(req).as_spaces(),
-                     encoding);
+            self.cache_fs_stats.miss();
+            if self.queue_encode(&req_p, &etag, &encoding) {
+                log!(self.log, "{} queued for encoding as {}", self.remote_addresses(req).as_spaces(), encoding);
             }
         }
 
@@ -785,59 +1193,46 @@ impl HttpHandler {
                            mt)))
     }
 
-    fn handle_get_raw_fs_dir(&self, req: &mut Request, req_p: PathBuf) -> IronResult<Response> {
+    fn handle_get_raw_fs_dir(&self, req: &mut Request, req_p: PathBuf, mount: &Mount) -> IronResult<Response> {
         log!(self.log,
              "{} was served metadata for directory {magenta}{}{reset}",
              self.remote_addresses(&req),
              req_p.display());
         self.handle_raw_fs_api_response(status::Ok,
                                         &FilesetData {
-                                            writes_supported: self.writes_temp_dir.is_some(),
+                                            writes_supported: mount.allow_writes,
                                             is_root: req.url.as_ref().path_segments().unwrap().count() + !req.url.as_ref().as_str().ends_with('/') as usize ==
                                                      1,
                                             is_file: false,
-                                            files: req_p.read_dir()
-                                                .expect("Failed to read requested directory")
-                                                .map(|p| p.expect("Failed to iterate over requested directory"))
-                                                .filter(|f| {
-                    let fp = f.path();
-                    let mut symlink = false;
-                    !((!self.follow_symlinks &&
-                       {
-                        symlink = is_symlink(&fp);
-                        symlink
-                    }) || (self.follow_symlinks && self.sandbox_symlinks && symlink && !is_descendant_of(fp, &self.hosted_directory.1)))
-                })
-                                                .map(|f| {
-                    let is_file = is_actually_file(&f.file_type().expect("Failed to get file type"), &f.path());
-                    if is_file {
-                        get_raw_fs_metadata(f.path())
-                    } else {
-                        RawFileData {
-                            mime_type: Mime(MimeTopLevel::Text, MimeSubLevel::Ext("directory".to_string()), Default::default()), // text/directory
-                            name: f.file_name().into_string().expect("Failed to get file name"),
-                            last_modified: file_time_modified_p(&f.path()),
-                            size: 0,
-                            is_file: false,
-                        }
-                    }
-                })
+                                            files: self.listing_entries(req, req_p.read_dir().expect("Failed to read requested directory"), mount)
+                                                .into_iter()
+                                                .map(|f| if f.is_file {
+                                                    get_raw_fs_metadata(f.path)
+                                                } else {
+                                                    RawFileData {
+                                                        mime_type: Mime(MimeTopLevel::Text, MimeSubLevel::Ext("directory".to_string()), Default::default()), // text/directory
+                                                        name: f.name,
+                                                        last_modified: file_time_modified_p(&f.path),
+                                                        size: 0,
+                                                        is_file: false,
+                                                    }
+                                                })
                                                 .collect(),
                                         })
     }
 
-    fn handle_get_dir(&self, req: &mut Request, req_p: PathBuf) -> IronResult<Response> {
+    fn handle_get_dir(&self, req: &mut Request, req_p: PathBuf, mount: &Mount) -> IronResult<Response> {
         if self.check_indices {
             let mut idx = req_p.join("index");
             if let Some(e) = INDEX_EXTENSIONS.iter()
                 .find(|e| {
                     idx.set_extension(e);
                     idx.exists() &&
-                    ((!self.follow_symlinks || !self.sandbox_symlinks) ||
-                     (self.follow_symlinks && self.sandbox_symlinks && is_descendant_of(&req_p, &self.hosted_directory.1)))
+                    ((!self.follow_symlinks || !mount.sandbox_symlinks) ||
+                     (self.follow_symlinks && mount.sandbox_symlinks && is_descendant_of(&req_p, &mount.directory.1)))
                 }) {
                 if req.url.as_ref().path_segments().unwrap().next_back() == Some("") {
-                    let r = self.handle_get_file(req, &idx, false);
+                    let r = self.handle_get_file(req, &idx, false, Some(mount));
                     log!(self.log,
                          "{} found index file for directory {magenta}{}{reset}",
                          self.remote_addresses(req).as_spaces(),
@@ -849,14 +1244,14 @@ impl HttpHandler {
             }
         }
 
-        if !self.generate_listings {
+        if !mount.generate_listings {
             return self.handle_nonexistent_get(req, req_p);
         }
 
         if client_mobile(&req.headers) {
-            self.handle_get_mobile_dir_listing(req, req_p)
+            self.handle_get_mobile_dir_listing(req, req_p, mount)
         } else {
-            self.handle_get_dir_listing(req, req_p)
+            self.handle_get_dir_listing(req, req_p, mount)
         }
     }
 
@@ -868,8 +1263,8 @@ impl HttpHandler {
 
     /// Try to resolve any X-Original-URL headers for a redirect, else raw `/loca/tion` from request
     fn user_facing_request_url(&self, req: &Request) -> String {
-        for (network, header) in &self.proxy_redirs {
-            if network.contains(&req.remote_addr.ip()) {
+        for (source, header) in &self.live().proxy_redirs {
+            if source.contains(&req.remote_addr) {
                 if let Some(saddrs) = req.headers.get_raw(header) {
                     if saddrs.len() > 0 {
                         if let Ok(s) = str::from_utf8(&saddrs[0]) {
@@ -900,14 +1295,20 @@ impl HttpHandler {
         Ok(Response::with((status::SeeOther, Header(headers::Server(USER_AGENT.into())), Header(headers::Location(new_url)))))
     }
 
-    fn handle_get_mobile_dir_listing(&self, req: &mut Request, req_p: PathBuf) -> IronResult<Response> {
+    fn handle_get_mobile_dir_listing(&self, req: &mut Request, req_p: PathBuf, mount: &Mount) -> IronResult<Response> {
         let relpath = url_path(&req.url);
         let is_root = relpath == "/";
         let mut relpath_escaped = escape_specials(&relpath);
         if relpath_escaped.as_bytes().last() != Some(&b'/') {
             relpath_escaped.to_mut().push('/');
         }
-        let show_file_management_controls = self.writes_temp_dir.is_some();
+        // Offer everything to those who can log in, and prompt them to when they try to manage files
+        let perms = if self.caller_can_log_in(req) {
+            Permissions::ALL
+        } else {
+            self.caller_permissions(req)
+        };
+        let show_file_management_controls = mount.allow_writes && perms.write;
         log!(self.log,
              "{} was served mobile directory listing for {magenta}{}{reset}",
              self.remote_addresses(&req),
@@ -930,43 +1331,22 @@ impl HttpHandler {
                        modified.strftime("%F %T").unwrap(),
                        up_path = unsafe { str::from_utf8_unchecked(parentpath) });
         };
+        let list = self.listing_entries(req, req_p.read_dir().expect("Failed to read requested directory"), mount);
         let list_f = |out: &mut Vec<u8>| {
-            let mut list = req_p.read_dir()
-                .expect("Failed to read requested directory")
-                .map(|p| p.expect("Failed to iterate over requested directory"))
-                .filter(|f| {
-                    let fp = f.path();
-                    let mut symlink = false;
-                    !((!self.follow_symlinks &&
-                       {
-                        symlink = is_symlink(&fp);
-                        symlink
-                    }) || (self.follow_symlinks && self.sandbox_symlinks && symlink && !is_descendant_of(fp, &self.hosted_directory.1)))
-                })
-                .collect::<Vec<_>>();
-            list.sort_by(|lhs, rhs| {
-                (is_actually_file(&lhs.file_type().expect("Failed to get file type"), &lhs.path()),
-                 lhs.file_name().to_str().expect("Failed to get file name").to_lowercase())
-                    .cmp(&(is_actually_file(&rhs.file_type().expect("Failed to get file type"), &rhs.path()),
-                           rhs.file_name().to_str().expect("Failed to get file name").to_lowercase()))
-            });
-            for f in list {
-                let is_file = is_actually_file(&f.file_type().expect("Failed to get file type"), &f.path());
-                let fmeta = f.metadata().expect("Failed to get requested file metadata");
-                let fname = f.file_name().into_string().expect("Failed to get file name");
-                let path = f.path();
-                let modified = file_time_modified(&fmeta);
+            for f in &list {
+                let (is_file, fmeta, fname, path) = (f.is_file, &f.metadata, &f.name, &f.path);
+                let modified = file_time_modified(fmeta);
                 let modified_ts = modified.to_timespec();
 
                 let _ = writeln!(out,
                                  concat!(r#"<a href="{path}{fname}" id="{}"><div><span class="{}{}_icon">{}{}</span>{}</div>"#,
                                          r#"<div><time ms={}{:03}>{} UTC</time>{}</div></a>"#),
-                                 NoDoubleQuotes(&fname),
+                                 NoDoubleQuotes(fname),
                                  if is_file { "file" } else { "dir" },
-                                 file_icon_suffix(&path, is_file),
-                                 NoHtmlLiteral(&fname),
+                                 file_icon_suffix(path, is_file),
+                                 NoHtmlLiteral(fname),
                                  if is_file { "" } else { "/" },
-                                 if show_file_management_controls {
+                                 if show_file_management_controls && perms.delete && !f.is_mount {
                                      DisplayThree(r#"<span class="manage"><span class="delete_file_icon" onclick="delete_onclick(arguments[0])">Delete</span>"#,
                                                   if self.webdav >= WebDavLevel::MkColMoveOnly {
                                                       r#" <span class="rename_icon" onclick="rename_onclick(arguments[0])">Rename</span>"#
@@ -981,57 +1361,63 @@ impl HttpHandler {
                                  modified_ts.nsec / 1000_000,
                                  modified.strftime("%F %T").unwrap(),
                                  if is_file {
-                                     DisplayThree("<span class=\"size\">", Maybe(Some(HumanReadableSize(file_length(&fmeta, &path)))), "</span>")
+                                     DisplayThree("<span class=\"size\">", Maybe(Some(HumanReadableSize(file_length(fmeta, path)))), "</span>")
                                  } else {
                                      DisplayThree("", Maybe(None), "")
                                  },
                                  path = relpath_escaped,
-                                 fname = encode_tail_if_trimmed(escape_specials(&fname)));
+                                 fname = encode_tail_if_trimmed(escape_specials(fname)));
             }
         };
 
-        self.handle_generated_response_encoding(req,
-                                                status::Ok,
-                                                directory_listing_mobile_html(&relpath_escaped[!is_root as usize..],
-                                                                              if show_file_management_controls {
-                                                                                  concat!(r#"<style>"#, include_str!(concat!(env!("OUT_DIR"), "/assets/upload.css")), r#"</style>"#,
-                                                                                          r#"<script>"#, include_str!(concat!(env!("OUT_DIR"), "/assets/upload.js")))
-                                                                              } else {
-                                                                                  ""
-                                                                              },
-                                                                              if show_file_management_controls {
-                                                                                  include_str!(concat!(env!("OUT_DIR"), "/assets/manage_mobile.js"))
-                                                                              } else {
-                                                                                  ""
-                                                                              },
-                                                                              if show_file_management_controls {
-                                                                                  concat!(include_str!(concat!(env!("OUT_DIR"), "/assets/manage.js")), r#"</script>"#)
-                                                                              } else {
-                                                                                  ""
-                                                                              },
-                                                                              parent_f,
-                                                                              list_f,
-                                                                              if show_file_management_controls {
-                                                                                  concat!(r#"<span class="heading">Upload files: "#,
-                                                                                          r#"<input type="file" multiple /></span>"#)
-                                                                              } else {
-                                                                                  ""
-                                                                              },
-                                                                              if show_file_management_controls && self.webdav >= WebDavLevel::MkColMoveOnly {
-                                                                                  r#"<a id='new"directory' href><span class="new_dir_icon">Create directory</span></a>"#
-                                                                              } else {
-                                                                                  ""
-                                                                              }))
-    }
-
-    fn handle_get_dir_listing(&self, req: &mut Request, req_p: PathBuf) -> IronResult<Response> {
+        self.handle_listing_response_encoding(req,
+                                              &req_p,
+                                              directory_listing_mobile_html(&relpath_escaped[!is_root as usize..],
+                                                                            if show_file_management_controls {
+                                                                                concat!(r#"<style>"#, include_str!(concat!(env!("OUT_DIR"), "/assets/upload.css")), r#"</style>"#,
+                                                                                        r#"<script>"#, include_str!(concat!(env!("OUT_DIR"), "/assets/upload.js")))
+                                                                            } else {
+                                                                                ""
+                                                                            },
+                                                                            if show_file_management_controls {
+                                                                                include_str!(concat!(env!("OUT_DIR"), "/assets/manage_mobile.js"))
+                                                                            } else {
+                                                                                ""
+                                                                            },
+                                                                            if show_file_management_controls {
+                                                                                concat!(include_str!(concat!(env!("OUT_DIR"), "/assets/manage.js")), r#"</script>"#)
+                                                                            } else {
+                                                                                ""
+                                                                            },
+                                                                            parent_f,
+                                                                            list_f,
+                                                                            if show_file_management_controls {
+                                                                                concat!(r#"<span class="heading">Upload files: "#,
+                                                                                        r#"<input type="file" multiple /></span>"#)
+                                                                            } else {
+                                                                                ""
+                                                                            },
+                                                                            if show_file_management_controls && self.webdav >= WebDavLevel::MkColMoveOnly {
+                                                                                r#"<a id='new"directory' href><span class="new_dir_icon">Create directory</span></a>"#
+                                                                            } else {
+                                                                                ""
+                                                                            }))
+    }
+
+    fn handle_get_dir_listing(&self, req: &mut Request, req_p: PathBuf, mount: &Mount) -> IronResult<Response> {
         let relpath = url_path(&req.url);
         let is_root = relpath == "/";
         let mut relpath_escaped = escape_specials(&relpath);
         if relpath_escaped.as_bytes().last() != Some(&b'/') {
             relpath_escaped.to_mut().push('/');
         }
-        let show_file_management_controls = self.writes_temp_dir.is_some();
+        // Offer everything to those who can log in, and prompt them to when they try to manage files
+        let perms = if self.caller_can_log_in(req) {
+            Permissions::ALL
+        } else {
+            self.caller_permissions(req)
+        };
+        let show_file_management_controls = mount.allow_writes && perms.write;
         log!(self.log,
              "{} was served directory listing for {magenta}{}{reset}",
              self.remote_addresses(&req),
@@ -1061,31 +1447,12 @@ impl HttpHandler {
             Ok(rd) => rd,
             Err(err) => return self.handle_requested_entity_unopenable(req, err, "directory"),
         };
+        let list = self.listing_entries(req, rd, mount);
         let list_f = |out: &mut Vec<u8>| {
-            let mut list = rd.map(|p| p.expect("Failed to iterate over requested directory"))
-                .filter(|f| {
-                    let fp = f.path();
-                    let mut symlink = false;
-                    !((!self.follow_symlinks &&
-                       {
-                        symlink = is_symlink(&fp);
-                        symlink
-                    }) || (self.follow_symlinks && self.sandbox_symlinks && symlink && !is_descendant_of(fp, &self.hosted_directory.1)))
-                })
-                .collect::<Vec<_>>();
-            list.sort_by(|lhs, rhs| {
-                (is_actually_file(&lhs.file_type().expect("Failed to get file type"), &lhs.path()),
-                 lhs.file_name().to_str().expect("Failed to get file name").to_lowercase())
-                    .cmp(&(is_actually_file(&rhs.file_type().expect("Failed to get file type"), &rhs.path()),
-                           rhs.file_name().to_str().expect("Failed to get file name").to_lowercase()))
-            });
-            for f in list {
-                let path = f.path();
-                let is_file = is_actually_file(&f.file_type().expect("Failed to get file type"), &path);
-                let fmeta = f.metadata().expect("Failed to get requested file metadata");
-                let fname = f.file_name().into_string().expect("Failed to get file name");
-                let len = file_length(&fmeta, &path);
-                let modified = file_time_modified(&fmeta);
+            for f in &list {
+                let (is_file, fmeta, fname, path) = (f.is_file, &f.metadata, &f.name, &f.path);
+                let len = file_length(fmeta, path);
+                let modified = file_time_modified(fmeta);
                 let modified_ts = modified.to_timespec();
                 struct FileSizeDisplay(bool, u64);
                 impl fmt::Display for FileSizeDisplay {
@@ -1102,10 +1469,10 @@ impl HttpHandler {
                                "<tr id=\"{}\"><td><a href=\"{path}{fname}\" tabindex=\"-1\" class=\"{}{}_icon\"></a></td> <td><a \
                                 href=\"{path}{fname}\">{}{}</a></td> <td><a href=\"{path}{fname}\" tabindex=\"-1\"><time ms={}{:03}>{}</time></a></td> \
                                 <td><a href=\"{path}{fname}\" tabindex=\"-1\">{}{}{}</a></td> {}</tr>\n",
-                               NoDoubleQuotes(&fname),
+                               NoDoubleQuotes(fname),
                                if is_file { "file" } else { "dir" },
-                               file_icon_suffix(&path, is_file),
-                               NoHtmlLiteral(&fname),
+                               file_icon_suffix(path, is_file),
+                               NoHtmlLiteral(fname),
                                if is_file { "" } else { "/" },
                                modified_ts.sec,
                                modified_ts.nsec / 1000_000,
@@ -1117,7 +1484,9 @@ impl HttpHandler {
                                    Maybe(None)
                                },
                                if is_file { "</abbr>" } else { "" },
-                               if show_file_management_controls {
+                               if show_file_management_controls && (f.is_mount || !perms.delete) {
+                                   DisplayThree("<td>&nbsp;", "", "</td>")
+                               } else if show_file_management_controls {
                                    DisplayThree("<td><a href class=\"delete_file_icon\" onclick=\"delete_onclick(arguments[0])\">Delete</a>",
                                                 if self.webdav >= WebDavLevel::MkColMoveOnly {
                                                     " <a href class=\"rename_icon\" onclick=\"rename_onclick(arguments[0])\">Rename</a>"
@@ -1129,60 +1498,60 @@ impl HttpHandler {
                                    DisplayThree("", "", "")
                                },
                                path = relpath_escaped,
-                               fname = encode_tail_if_trimmed(escape_specials(&fname)));
+                               fname = encode_tail_if_trimmed(escape_specials(fname)));
             }
         };
 
-        self.handle_generated_response_encoding(req,
-                                                status::Ok,
-                                                directory_listing_html(&relpath_escaped[!is_root as usize..],
-                                                                       if show_file_management_controls {
-                                                                           concat!(r#"<style>"#,
-                                                                                   include_str!(concat!(env!("OUT_DIR"), "/assets/upload.css")),
-                                                                                   r#"</style>"#,
-                                                                                   r#"<script>"#,
-                                                                                   include_str!(concat!(env!("OUT_DIR"), "/assets/upload.js")))
-                                                                       } else {
-                                                                           ""
-                                                                       },
-                                                                       if show_file_management_controls {
-                                                                           include_str!(concat!(env!("OUT_DIR"), "/assets/manage_desktop.js"))
-                                                                       } else {
-                                                                           ""
-                                                                       },
-                                                                       if show_file_management_controls {
-                                                                           concat!(include_str!(concat!(env!("OUT_DIR"), "/assets/manage.js")), r#"</script>"#)
-                                                                       } else {
-                                                                           ""
-                                                                       },
-                                                                       parent_f,
-                                                                       list_f,
-                                                                       if show_file_management_controls {
-                                                                           "<hr />\
-                                                                            <p>Drag&amp;Drop to upload or <input type=\"file\" multiple />.</p>"
-                                                                       } else {
-                                                                           ""
-                                                                       },
-                                                                       if show_file_management_controls {
-                                                                           "<th>Manage</th>"
-                                                                       } else {
-                                                                           ""
-                                                                       },
-                                                                       if show_file_management_controls && self.webdav >= WebDavLevel::MkColMoveOnly {
-                                                                           "<tr id=\'new\"directory\'><td><a tabindex=\"-1\" href \
-                                                                            class=\"new_dir_icon\"></a></td><td colspan=3><a href>Create \
-                                                                            directory</a></td><td><a tabindex=\"-1\" href>&nbsp;</a></td></tr>"
-                                                                       } else {
-                                                                           ""
-                                                                       }))
+        self.handle_listing_response_encoding(req,
+                                              &req_p,
+                                              directory_listing_html(&relpath_escaped[!is_root as usize..],
+                                                                     if show_file_management_controls {
+                                                                         concat!(r#"<style>"#,
+                                                                                 include_str!(concat!(env!("OUT_DIR"), "/assets/upload.css")),
+                                                                                 r#"</style>"#,
+                                                                                 r#"<script>"#,
+                                                                                 include_str!(concat!(env!("OUT_DIR"), "/assets/upload.js")))
+                                                                     } else {
+                                                                         ""
+                                                                     },
+                                                                     if show_file_management_controls {
+                                                                         include_str!(concat!(env!("OUT_DIR"), "/assets/manage_desktop.js"))
+                                                                     } else {
+                                                                         ""
+                                                                     },
+                                                                     if show_file_management_controls {
+                                                                         concat!(include_str!(concat!(env!("OUT_DIR"), "/assets/manage.js")), r#"</script>"#)
+                                                                     } else {
+                                                                         ""
+                                                                     },
+                                                                     parent_f,
+                                                                     list_f,
+                                                                     if show_file_management_controls {
+                                                                         "<hr />\
+                                                                          <p>Drag&amp;Drop to upload or <input type=\"file\" multiple />.</p>"
+                                                                     } else {
+                                                                         ""
+                                                                     },
+                                                                     if show_file_management_controls {
+                                                                         "<th>Manage</th>"
+                                                                     } else {
+                                                                         ""
+                                                                     },
+                                                                     if show_file_management_controls && self.webdav >= WebDavLevel::MkColMoveOnly {
+                                                                         "<tr id=\'new\"directory\'><td><a tabindex=\"-1\" href \
+                                                                          class=\"new_dir_icon\"></a></td><td colspan=3><a href>Create \
+                                                                          directory</a></td><td><a tabindex=\"-1\" href>&nbsp;</a></td></tr>"
+                                                                     } else {
+                                                                         ""
+                                                                     }))
     }
 
     fn handle_put(&self, req: &mut Request) -> IronResult<Response> {
-        if self.writes_temp_dir.is_none() {
-            return self.handle_forbidden_method(req, "-w", "write requests");
-        }
+        let (req_p, symlink, url_err, mount) = self.parse_requested_path(req);
 
-        let (req_p, symlink, url_err) = self.parse_requested_path(req);
+        if !mount.allow_writes {
+            return self.handle_forbidden_method(req, write_switch(mount), "write requests");
+        }
 
         if url_err {
             self.handle_invalid_url(req, "<p>Percent-encoding decoded to invalid UTF-8.</p>")
@@ -1194,29 +1563,39 @@ impl HttpHandler {
             self.handle_put_partial_content(req)
         } else {
             let illegal = (symlink && !self.follow_symlinks) ||
-                          (symlink && self.follow_symlinks && self.sandbox_symlinks && !is_nonexistent_descendant_of(&req_p, &self.hosted_directory.1));
+                          (symlink && self.follow_symlinks && mount.sandbox_symlinks && !is_nonexistent_descendant_of(&req_p, &mount.directory.1));
             if illegal {
                 return self.handle_nonexistent(req, req_p);
             }
-            self.handle_put_file(req, req_p)
+            if HttpHandler::evaluate_preconditions_path(req, &req_p) != Precondition::Passed {
+                log!(self.log,
+                     "{} tried to {red}PUT{reset} {magenta}{}{reset} but its preconditions failed",
+                     self.remote_addresses(&req),
+                     req_p.display());
+                let _ = io::copy(&mut req.body, &mut io::sink());
+                return self.handle_precondition_failed(req);
+            }
+            let create_only = req.headers.get::<headers::IfNoneMatch>() == Some(&headers::IfNoneMatch::Any);
+            self.handle_put_file(req, req_p, create_only)
         }
     }
 
     fn handle_disallowed_method(&self, req: &mut Request, tpe: &str) -> IronResult<Response> {
+        let allowed_methods = self.caller_allowed_methods(req);
         log!(self.log,
              "{} tried to {red}{}{reset} on {magenta}{}{reset} ({blue}{}{reset}) but only {red}{}{reset} are allowed",
              self.remote_addresses(&req),
              req.method,
              url_path(&req.url),
              tpe,
-             CommaList(self.allowed_methods.iter()));
+             CommaList(allowed_methods.iter()));
 
         let resp_text = error_html("405 Method Not Allowed",
                                    format_args!("Can't {} on a {}.", req.method, tpe),
-                                   format_args!("<p>Allowed methods: {}</p>", CommaList(self.allowed_methods.iter())));
+                                   format_args!("<p>Allowed methods: {}</p>", CommaList(allowed_methods.iter())));
         self.handle_generated_response_encoding(req, status::MethodNotAllowed, resp_text)
             .map(|mut r| {
-                r.headers.set(headers::Allow(self.allowed_methods.into()));
+                r.headers.set(headers::Allow(allowed_methods));
                 r
             })
     }
@@ -1235,37 +1614,56 @@ impl HttpHandler {
                                                            ""))
     }
 
-    fn handle_put_file(&self, req: &mut Request, req_p: PathBuf) -> IronResult<Response> {
+    fn handle_put_file(&self, req: &mut Request, req_p: PathBuf, create_only: bool) -> IronResult<Response> {
         let _ = fs::create_dir_all(req_p.parent().expect("Failed to get requested file's parent directory"));
         let direct_output = File::create_new(&req_p);
 
+        // If-None-Match: * passed, but someone else created the file in the meantime
+        if create_only && direct_output.is_err() && req_p.exists() {
+            log!(self.log,
+                 "{} tried to {red}PUT{reset} {magenta}{}{reset} but it was created concurrently",
+                 self.remote_addresses(&req),
+                 req_p.display());
+            let _ = io::copy(&mut req.body, &mut io::sink());
+            return self.handle_precondition_failed(req);
+        }
+
         let existent = direct_output.is_err();
         let mtime = req.headers.get::<XLastModified>().map(|xlm| xlm.0).or_else(|| req.headers.get::<XOcMTime>().map(|xocmt| xocmt.0 * 1000));
-        log!(self.log,
-             "{} {} {magenta}{}{reset}, size: {}B{}{}",
-             self.remote_addresses(&req),
-             if existent { "replaced" } else { "created" },
-             req_p.display(),
-             *req.headers.get::<headers::ContentLength>().expect("No Content-Length header"),
-             mtime.map_or("", |_| ". modified: "),
-             Maybe(mtime.map(MsAsS)));
 
-        let mut ibuf = BufReader::with_capacity(1024 * 1024, &mut req.body);
+        // Content-Length isn't there for Transfer-Encoding: chunked, so count what we actually got
+        struct CountingReader<R: Read>(R, u64);
+        impl<R: Read> Read for CountingReader<R> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let rd = self.0.read(buf)?;
+                self.1 += rd as u64;
+                Ok(rd)
+            }
+        }
+
+        let mut ibuf = BufReader::with_capacity(1024 * 1024, CountingReader(&mut req.body, 0));
         let file = match direct_output {
             Ok(mut file) => {
                 if let Err(err) = io::copy(&mut ibuf, &mut file) {
                     drop(file);
                     fs::remove_file(&req_p).expect("Failed to remove requested file after failure");
                     let _ = io::copy(&mut ibuf, &mut io::sink());
+                    let received = ibuf.into_inner().1;
+                    self.log_put_failed(req, &req_p, received);
                     return self.handle_put_error(req, "File not created.", err);
                 }
+                let received = ibuf.into_inner().1;
+                self.log_put_file(req, &req_p, existent, received, mtime);
 
                 file
             }
             Err(_) => {
                 self.create_temp_dir(&self.writes_temp_dir);
                 let &(_, ref temp_dir) = self.writes_temp_dir.as_ref().unwrap();
-                let temp_file_p = temp_dir.join(req_p.file_name().expect("Failed to get requested file's filename"));
+                // Unique, so concurrent uploads to the same file don't write over each other's
+                let mut temp_file_name = OsString::from(format!("{:016x}-", RandomState::new().hash_one(&req_p)));
+                temp_file_name.push(req_p.file_name().expect("Failed to get requested file's filename"));
+                let temp_file_p = temp_dir.join(temp_file_name);
                 struct DropDelete<'a>(&'a Path);
                 impl<'a> Drop for DropDelete<'a> {
                     fn drop(&mut self) {
@@ -1277,21 +1675,43 @@ impl HttpHandler {
                 let _temp_file_p_destroyer = DropDelete(&temp_file_p);
                 if let Err(err) = io::copy(&mut ibuf, &mut temp_file) {
                     let _ = io::copy(&mut ibuf, &mut io::sink());
+                    let received = ibuf.into_inner().1;
+                    self.log_put_failed(req, &req_p, received);
                     return self.handle_put_error(req, "File not created.", err);
                 }
+                let received = ibuf.into_inner().1;
 
-                let _temp_file_p_destroyer = DropDelete(&temp_file_p);
-                temp_file.rewind().expect("Failed to rewind temp file");
-                let mut file = File::create(&req_p).expect("Failed to open requested file");
-                // matches std::io::copy() #[cfg]
-                #[cfg(any(target_os = "linux", target_os = "android"))]
-                let err = io::copy(&mut temp_file, &mut file);
-                #[cfg(not(any(target_os = "linux", target_os = "android")))]
-                let err = io::copy(&mut BufReader::with_capacity(1024 * 1024, &mut temp_file), &mut file);
-                if let Err(err) = err {
-                    return self.handle_put_error(req, "File truncated.", err);
+                // Checked again now that the whole body's here, and held until it's replaced,
+                // so only one of several writes conditional on the same version gets to replace it
+                let _replacing = self.replacing(&req_p);
+                if HttpHandler::evaluate_preconditions_path(req, &req_p) != Precondition::Passed {
+                    log!(self.log,
+                         "{} tried to {red}PUT{reset} {magenta}{}{reset} but it was changed concurrently",
+                         self.remote_addresses(req),
+                         req_p.display());
+                    return self.handle_precondition_failed(req);
                 }
 
+                let file = if fs::rename(&temp_file_p, &req_p).is_ok() {
+                    temp_file
+                } else {
+                    // Temp directory on another filesystem
+                    temp_file.rewind().expect("Failed to rewind temp file");
+                    let mut file = File::create(&req_p).expect("Failed to open requested file");
+                    // matches std::io::copy() #[cfg]
+                    #[cfg(any(target_os = "linux", target_os = "android"))]
+                    let err = io::copy(&mut temp_file, &mut file);
+                    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+                    let err = io::copy(&mut BufReader::with_capacity(1024 * 1024, &mut temp_file), &mut file);
+                    if let Err(err) = err {
+                        self.log_put_failed(req, &req_p, received);
+                        return self.handle_put_error(req, "File truncated.", err);
+                    }
+
+                    file
+                };
+                self.log_put_file(req, &req_p, existent, received, mtime);
+
                 file
             }
         };
@@ -1308,6 +1728,35 @@ impl HttpHandler {
                            Header(headers::Server(USER_AGENT.into())))))
     }
 
+    fn log_put_file(&self, req: &Request, req_p: &Path, existent: bool, size: u64, mtime: Option<u64>) {
+        log!(self.log,
+             "{} {} {magenta}{}{reset}, size: {}B{}{}",
+             self.remote_addresses(&req),
+             if existent { "replaced" } else { "created" },
+             req_p.display(),
+             size,
+             mtime.map_or("", |_| ". modified: "),
+             Maybe(mtime.map(MsAsS)));
+    }
+
+    /// Wait for other requests replacing or deleting `req_p` to finish, then mark it as being replaced until the result is dropped
+    fn replacing(&self, req_p: &Path) -> Replacing<'_> {
+        let mut replacing = self.replacing.0.lock().expect("Replacing files lock poisoned");
+        while replacing.contains(req_p) {
+            replacing = self.replacing.1.wait(replacing).expect("Replacing files lock poisoned");
+        }
+        replacing.insert(req_p.to_path_buf());
+        Replacing(self, req_p.to_path_buf())
+    }
+
+    fn log_put_failed(&self, req: &Request, req_p: &Path, received: u64) {
+        log!(self.log,
+             "{} failed to {red}PUT{reset} {magenta}{}{reset}, received: {}B",
+             self.remote_addresses(req),
+             req_p.display(),
+             received);
+    }
+
     fn handle_put_error(&self, req: &mut Request, res: &str, err: IoError) -> IronResult<Response> {
         log!(self.log, "{} {} {}", self.remote_addresses(req).as_spaces(), res, err);
         return self.handle_generated_response_encoding(req,
@@ -1316,19 +1765,33 @@ impl HttpHandler {
     }
 
     fn handle_delete(&self, req: &mut Request) -> IronResult<Response> {
-        if self.writes_temp_dir.is_none() {
-            return self.handle_forbidden_method(req, "-w", "write requests");
-        }
+        let (req_p, symlink, url_err, mount) = self.parse_requested_path_custom_symlink(req.url.as_ref(), false);
 
-        let (req_p, symlink, url_err) = self.parse_requested_path_custom_symlink(req.url.as_ref(), false);
+        if !mount.allow_writes {
+            return self.handle_forbidden_method(req, write_switch(mount), "write requests");
+        }
 
         if url_err {
             self.handle_invalid_url(req, "<p>Percent-encoding decoded to invalid UTF-8.</p>")
         } else if !req_p.exists() || (symlink && !self.follow_symlinks) ||
-                  (symlink && self.follow_symlinks && self.sandbox_symlinks && !is_descendant_of(&req_p, &self.hosted_directory.1)) {
+                  (symlink && self.follow_symlinks && mount.sandbox_symlinks && !is_descendant_of(&req_p, &mount.directory.1)) {
             self.handle_nonexistent(req, req_p)
+        } else if req_p == mount.directory.1 {
+            log!(self.log,
+                 "{} tried to {red}DELETE{reset} mount point {magenta}{}{reset}",
+                 self.remote_addresses(&req),
+                 req_p.display());
+            Ok(Response::with(status::Forbidden))
         } else {
-            self.handle_delete_path(req, req_p, symlink)
+            let _replacing = self.replacing(&req_p);
+            if HttpHandler::evaluate_preconditions_path(req, &req_p) != Precondition::Passed {
+                log!(self.log,
+                     "{} tried to {red}DELETE{reset} {magenta}{}{reset} but its preconditions failed",
+                     self.remote_addresses(&req),
+                     req_p.display());
+                return self.handle_precondition_failed(req);
+            }
+            self.handle_delete_path(req, req_p.clone(), symlink)
         }
     }
 
@@ -1405,7 +1868,21 @@ impl HttpHandler {
                                                            "This operation was not implemented.",
                                                            format_args!("<p>Unsupported request method: {}.<br />\nSupported methods: {}.</p>",
                                                                         req.method,
-                                                                        CommaList(self.allowed_methods.iter()))))
+                                                                        CommaList(self.caller_allowed_methods(req).iter()))))
+    }
+
+    /// Like `handle_generated_response_encoding()`, but remembering it's the listing of `dir`,
+    /// so the watcher can drop its encoded versions once the directory changes
+    fn handle_listing_response_encoding(&self, req: &mut Request, dir: &Path, resp: String) -> IronResult<Response> {
+        if self.encoded_temp_dir.is_some() {
+            self.cache_gen_paths
+                .write()
+                .expect("Generated path cache write lock poisoned")
+                .entry(dir.to_path_buf())
+                .or_default()
+                .insert(blake3::hash(resp.as_bytes()));
+        }
+        self.handle_generated_response_encoding(req, status::Ok, resp)
     }
 
     fn handle_generated_response_encoding(&self, req: &mut Request, st: status::Status, resp: String) -> IronResult<Response> {
@@ -1430,6 +1907,7 @@ impl HttpHandler {
             {
                 if let Some(enc_resp) = self.cache_gen.read().expect("Generated file cache read lock poisoned").get(&cache_key) {
                     enc_resp.1.store(precise_time_ns(), AtomicOrdering::Relaxed);
+                    self.cache_gen_stats.hit();
                     log!(self.log,
                          "{} encoded as {} for {:.1}% ratio (cached)",
                          self.remote_addresses(req).as_spaces(),
@@ -1445,7 +1923,8 @@ impl HttpHandler {
                 }
             }
 
-            if let Some(enc_resp) = encode_str(&resp, &encoding) {
+            self.cache_gen_stats.miss();
+            if let Some(enc_resp) = encode_str(&resp, &encoding, &self.compression_levels) {
                 log!(self.log,
                      "{} encoded as {} for {:.1}% ratio",
                      self.remote_addresses(req).as_spaces(),
@@ -1453,9 +1932,13 @@ impl HttpHandler {
                      ((resp.len() as f64) / (enc_resp.len() as f64)) * 100f64);
 
                 if enc_resp.len() as u64 <= self.encoded_generated_limit {
+                    let atime = precise_time_ns();
                     let mut cache = self.cache_gen.write().expect("Generated file cache write lock poisoned");
                     self.cache_gen_size.fetch_add(enc_resp.len() as u64, AtomicOrdering::Relaxed);
-                    cache.insert(cache_key.clone(), (enc_resp, AtomicU64::new(precise_time_ns())));
+                    if let Some((old_resp, _)) = cache.insert(cache_key, (enc_resp, AtomicU64::new(atime))) {
+                        self.cache_gen_size.fetch_sub(old_resp.len() as u64, AtomicOrdering::Relaxed);
+                    }
+                    self.cache_gen_lru.insert(atime, cache_key);
 
                     return Ok(Response::with((st,
                                               Header(headers::Server(USER_AGENT.into())),
@@ -1507,16 +1990,30 @@ impl HttpHandler {
                            serde_json::to_string(&resp).unwrap())))
     }
 
-    fn parse_requested_path(&self, req: &Request) -> (PathBuf, bool, bool) {
+    fn parse_requested_path(&self, req: &Request) -> (PathBuf, bool, bool, &Mount) {
         self.parse_requested_path_custom_symlink(req.url.as_ref(), true)
     }
 
-    fn parse_requested_path_custom_symlink(&self, req_url: &GenericUrl, follow_symlinks: bool) -> (PathBuf, bool, bool) {
+    fn parse_requested_path_custom_symlink(&self, req_url: &GenericUrl, follow_symlinks: bool) -> (PathBuf, bool, bool, &Mount) {
+        let segments = req_url.path_segments().unwrap().filter(|p| !p.is_empty()).collect::<Vec<_>>();
+        let (mount, prefix_len) = self.site(req_url)
+            .mounts
+            .iter()
+            .find_map(|m| {
+                let prefix = m.prefix.split('/').filter(|p| !p.is_empty());
+                let prefix_len = prefix.clone().count();
+                if prefix_len <= segments.len() && prefix.zip(&segments).all(|(p, s)| percent_decode(s).as_deref() == Some(p)) {
+                    Some((m, prefix_len))
+                } else {
+                    None
+                }
+            })
+            .expect("Hosted directory mount missing");
+
         let mut depth_left = MAX_SYMLINKS;
-        let (mut cur, sk, err, abs) = req_url.path_segments()
-            .unwrap()
-            .filter(|p| !p.is_empty())
-            .fold((self.hosted_directory.1.clone(), false, false, true),
+        let (mut cur, sk, err, abs) = segments[prefix_len..]
+            .iter()
+            .fold((mount.directory.1.clone(), false, false, true),
                   |(mut cur, mut sk, mut err, mut abs), pp| {
                 if let Some(pp) = percent_decode(pp) {
                     cur.push(&*pp);
@@ -1547,7 +2044,76 @@ impl HttpHandler {
             }
         }
 
-        (cur, sk, err)
+        (cur, sk, err, mount)
+    }
+
+    /// What's hosted for the virtual host the URL is on, or the default
+    fn site(&self, url: &GenericUrl) -> &Site {
+        vhost_name(url).and_then(|host| self.vhosts.get(host)).unwrap_or(&self.site)
+    }
+
+    /// All sites: the default, then virtual hosts
+    pub fn sites(&self) -> impl Iterator<Item = &Site> {
+        iter::once(&self.site).chain(self.vhosts.values())
+    }
+
+    /// The mounts directly under the requested path, by their names in it
+    fn child_mounts(&self, req: &Request) -> Vec<(&str, &Mount)> {
+        let url_path = url_path(&req.url);
+        self.site(req.url.as_ref())
+            .mounts
+            .iter()
+            .filter(|m| !m.prefix.is_empty())
+            .filter_map(|m| {
+                let (parent, name) = m.prefix.rsplit_once('/').unwrap_or(("", &m.prefix));
+                if parent.split('/').filter(|p| !p.is_empty()).eq(url_path.split('/').filter(|p| !p.is_empty())) {
+                    Some((name, m))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Entries of the requested directory to list, sorted directories-first, with mount points in place of whatever they hide
+    fn listing_entries(&self, req: &Request, rd: fs::ReadDir, mount: &Mount) -> Vec<ListingEntry> {
+        let mount_points = self.child_mounts(req);
+        let mut list = rd.map(|p| p.expect("Failed to iterate over requested directory"))
+            .filter(|f| {
+                let fp = f.path();
+                let mut symlink = false;
+                !((!self.follow_symlinks &&
+                   {
+                    symlink = is_symlink(&fp);
+                    symlink
+                }) || (self.follow_symlinks && mount.sandbox_symlinks && symlink && !is_descendant_of(fp, &mount.directory.1)))
+            })
+            .filter(|f| !self.precompressed || !is_precompressed_sibling(&f.path()))
+            .map(|f| {
+                let path = f.path();
+                ListingEntry {
+                    is_file: is_actually_file(&f.file_type().expect("Failed to get file type"), &path),
+                    metadata: f.metadata().expect("Failed to get requested file metadata"),
+                    name: f.file_name().into_string().expect("Failed to get file name"),
+                    path: path,
+                    is_mount: false,
+                }
+            })
+            .filter(|f| mount_points.iter().all(|&(name, _)| name != f.name))
+            .chain(mount_points.iter().flat_map(|&(name, m)| {
+                m.directory.1.metadata().ok().map(|metadata| {
+                    ListingEntry {
+                        path: m.directory.1.clone(),
+                        name: name.to_string(),
+                        metadata: metadata,
+                        is_file: false,
+                        is_mount: true,
+                    }
+                })
+            }))
+            .collect::<Vec<_>>();
+        list.sort_by_key(|f| (f.is_file, f.name.to_lowercase()));
+        list
     }
 
     fn create_temp_dir(&self, td: &Option<(String, PathBuf)>) {
@@ -1558,10 +2124,10 @@ impl HttpHandler {
     }
 
     #[inline(always)]
-    fn remote_addresses<'s, 'r, 'ra, 'rb: 'ra>(&'s self, req: &'r Request<'ra, 'rb>) -> AddressWriter<'r, 's, 'ra, 'rb> {
+    fn remote_addresses<'r, 'ra, 'rb: 'ra>(&self, req: &'r Request<'ra, 'rb>) -> AddressWriter<'r, 'ra, 'rb> {
         AddressWriter {
             request: req,
-            proxies: &self.proxies,
+            live: self.live(),
             log: self.log,
             just_spaces: false,
         }
@@ -1571,7 +2137,7 @@ impl HttpHandler {
         // Based on mime_guess::guess_mime_type_opt(); that one does to_str() instead of to_string_lossy()
         let ext = req_p.extension().unwrap_or(OsStr::new(""));
 
-        (self.mime_type_overrides.get(&*ext).cloned())
+        (self.live().mime_type_overrides.get(&*ext).cloned())
             .or_else(|| ext.to_str().and_then(get_mime_type_opt))
             .unwrap_or_else(|| if file_binary(req_p) {
                 Mime(MimeTopLevel::Application, MimeSubLevel::OctetStream, Default::default()) // "application/octet-stream"
@@ -1581,21 +2147,62 @@ impl HttpHandler {
     }
 }
 
+/// The virtual host name the URL is for, without the trailing dot, if any
+fn vhost_name(url: &GenericUrl) -> Option<&str> {
+    url.host_str().map(|host| host.strip_suffix('.').unwrap_or(host))
+}
+
+/// The `default` paths not `overridden`, and the `own` ones
+fn inherit_paths<V: Clone, F: Fn(&str) -> bool>(default: &BTreeMap<String, V>, own: &BTreeMap<String, V>, overridden: F) -> BTreeMap<String, V> {
+    default.iter().filter(|(path, _)| !overridden(path)).chain(own).map(|(path, val)| (path.clone(), val.clone())).collect()
+}
+
+/// The value for the longest of the normalised `paths` the URL's path is under, if any
+fn longest_path_match<'v, V>(paths: &'v BTreeMap<String, V>, url: &GenericUrl) -> Option<&'v V> {
+    if paths.is_empty() {
+        return None;
+    }
+
+    let path = percent_decode(url.path()).unwrap_or(Cow::Borrowed(url.path()));
+    let mut path = path.trim_matches('/');
+    loop {
+        if let Some(val) = paths.get(path) {
+            return Some(val);
+        }
+        if path.is_empty() {
+            return None;
+        }
+        path = &path[..path.rfind('/').unwrap_or(0)];
+    }
+}
+
+/// The switch enabling writes to the mount, for 403s
+fn write_switch(mount: &Mount) -> &'static str {
+    if mount.prefix.is_empty() {
+        "-w"
+    } else {
+        "--mount PREFIX=DIR,rw"
+    }
+}
+
 /// text/html; charset=utf-8
 fn text_html_charset_utf8() -> Mime {
     Mime(MimeTopLevel::Text, MimeSubLevel::Html, vec![(MimeAttr::Charset, MimeAttrValue::Utf8)])
 }
 
 
-pub struct AddressWriter<'r, 'p, 'ra, 'rb: 'ra> {
+/// What peers on Unix domain sockets are logged as, if they weren't forwarded for anyone
+const UNIX_SOCKET_NAME: &str = "unix socket";
+
+pub struct AddressWriter<'r, 'ra, 'rb: 'ra> {
     pub request: &'r Request<'ra, 'rb>,
-    pub proxies: &'p BTreeMap<IpCidr, String>,
+    pub live: Arc<LiveSettings>,
     /// (at all, log_time, log_colour)
     pub log: (bool, bool, bool),
     pub just_spaces: bool,
 }
 
-impl<'r, 'p, 'ra, 'rb: 'ra> fmt::Display for AddressWriter<'r, 'p, 'ra, 'rb> {
+impl<'r, 'ra, 'rb: 'ra> fmt::Display for AddressWriter<'r, 'ra, 'rb> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         use trivial_colours::{Reset as CReset, Colour as C};
 
@@ -1603,23 +2210,24 @@ impl<'r, 'p, 'ra, 'rb: 'ra> fmt::Display for AddressWriter<'r, 'p, 'ra, 'rb> {
             return write!(f, "{:w$}", "", w = self.width());
         }
 
+        let mut forwarded = self.forwarded().into_iter();
+        let remote_addr = if self.request.remote_addr != UNIX_SOCKET_PEER {
+            self.request.remote_addr.to_string()
+        } else {
+            // Everything on a Unix domain socket comes from the same place, so who it's forwarded for is more useful
+            forwarded.next().map(|saddr| String::from_utf8_lossy(saddr).into_owned()).unwrap_or_else(|| UNIX_SOCKET_NAME.to_string())
+        };
         if self.log.2 {
-            write!(f, "{green}{}{reset}", self.request.remote_addr, green = C::Green, reset = CReset)?;
+            write!(f, "{green}{}{reset}", remote_addr, green = C::Green, reset = CReset)?;
         } else {
-            write!(f, "{}", self.request.remote_addr)?;
+            write!(f, "{}", remote_addr)?;
         }
 
-        for (network, header) in self.proxies {
-            if network.contains(&self.request.remote_addr.ip()) {
-                if let Some(saddrs) = self.request.headers.get_raw(header) {
-                    for saddr in saddrs {
-                        if self.log.1 {
-                            write!(f, " for {green}{}{reset}", String::from_utf8_lossy(saddr), green = C::Green, reset = CReset)?;
-                        } else {
-                            write!(f, " for {}", String::from_utf8_lossy(saddr))?;
-                        }
-                    }
-                }
+        for saddr in forwarded {
+            if self.log.1 {
+                write!(f, " for {green}{}{reset}", String::from_utf8_lossy(saddr), green = C::Green, reset = CReset)?;
+            } else {
+                write!(f, " for {}", String::from_utf8_lossy(saddr))?;
             }
         }
 
@@ -1627,7 +2235,7 @@ impl<'r, 'p, 'ra, 'rb: 'ra> fmt::Display for AddressWriter<'r, 'p, 'ra, 'rb> {
     }
 }
 
-impl<'r, 'p, 'ra, 'rb: 'ra> AddressWriter<'r, 'p, 'ra, 'rb> {
+impl<'r, 'ra, 'rb: 'ra> AddressWriter<'r, 'ra, 'rb> {
     fn maybe_spaces(mut self, ms: bool) -> Self {
         self.just_spaces = ms;
         self
@@ -1638,27 +2246,82 @@ impl<'r, 'p, 'ra, 'rb: 'ra> AddressWriter<'r, 'p, 'ra, 'rb> {
     }
 
     fn width(&self) -> usize {
-        // per http://192.168.1.109:8000/target/doc/rust/src/core/net/socket_addr.rs.html#571
-        const LONGEST_IPV6_SOCKET_ADDR: &str = "[ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff%4294967296]:65536";
-        let mut widthbuf = ArrayString::<{ LONGEST_IPV6_SOCKET_ADDR.len() }>::new();
-        write!(&mut widthbuf, "{}", self.request.remote_addr).unwrap();
-        let mut len = widthbuf.len();
-        for (network, header) in self.proxies {
-            if network.contains(&self.request.remote_addr.ip()) {
-                if let Some(saddrs) = self.request.headers.get_raw(header) {
-                    for saddr in saddrs {
-                        len += " for ".len();
-                        len += saddr.len();
-                    }
-                }
-            }
+        let mut forwarded = self.forwarded().into_iter();
+        let mut len = if self.request.remote_addr != UNIX_SOCKET_PEER {
+            // per http://192.168.1.109:8000/target/doc/rust/src/core/net/socket_addr.rs.html#571
+            const LONGEST_IPV6_SOCKET_ADDR: &str = "[ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff%4294967296]:65536";
+            let mut widthbuf = ArrayString::<{ LONGEST_IPV6_SOCKET_ADDR.len() }>::new();
+            write!(&mut widthbuf, "{}", self.request.remote_addr).unwrap();
+            widthbuf.len()
+        } else {
+            forwarded.next().map(|saddr| String::from_utf8_lossy(saddr).len()).unwrap_or(UNIX_SOCKET_NAME.len())
+        };
+        for saddr in forwarded {
+            len += " for ".len();
+            len += String::from_utf8_lossy(saddr).len();
         }
         return len;
     }
+
+    /// The client's own address, without the port: the last one in the last trusted proxy header, which the proxy itself added,
+    /// otherwise the peer's
+    pub fn client(&self) -> String {
+        match self.forwarded().last() {
+            Some(saddr) => String::from_utf8_lossy(saddr).rsplit(',').next().unwrap_or_default().trim().to_string(),
+            None if self.request.remote_addr == UNIX_SOCKET_PEER => UNIX_SOCKET_NAME.to_string(),
+            None => self.request.remote_addr.ip().to_string(),
+        }
+    }
+
+    /// What to count the client's invalid credentials against: its address, or the /64 network for IPv6, since that's usually one host's;
+    /// none for requests over Unix domain sockets not forwarded by a trusted proxy, since those clients all share the one peer
+    pub fn lockout_key(&self) -> Option<String> {
+        if self.request.remote_addr == UNIX_SOCKET_PEER && self.forwarded().is_empty() {
+            return None;
+        }
+
+        let client = self.client();
+        Some(match client.parse::<IpAddr>().map(|ip| ip.to_canonical()) {
+            Ok(IpAddr::V6(ip)) => format!("{}/64", Ipv6Addr::from(u128::from(ip) & (!0 << 64))),
+            _ => client,
+        })
+    }
+
+    /// The addresses in trusted proxies' headers
+    fn forwarded(&self) -> Vec<&[u8]> {
+        self.live
+            .proxies
+            .iter()
+            .filter(|(source, _)| source.contains(&self.request.remote_addr))
+            .flat_map(|(_, header)| self.request.headers.get_raw(header).into_iter().flatten())
+            .map(|saddr| &saddr[..])
+            .collect()
+    }
 }
 
 
-/// Attempt to start a server on ports from `from` to `up_to`, inclusive, with the specified handler.
+/// A socket bound to, but not served yet, so privileges can be dropped in between
+pub enum BoundSocket {
+    Http(HttpListener),
+    Https(HttpsListener<TlsServer>),
+    #[cfg(not(target_os = "windows"))]
+    Unix(UnixSocketListener),
+}
+
+impl BoundSocket {
+    /// Start handling requests on the socket with the specified handler
+    pub fn serve<H: Handler>(self, hndlr: H) -> iron::error::HttpResult<Listening> {
+        let ir = Iron::new(hndlr);
+        match self {
+            BoundSocket::Http(listener) => ir.listen(ShutdownListener(listener), Protocol::http()),
+            BoundSocket::Https(listener) => ir.listen(ShutdownListener(listener), Protocol::http()),
+            #[cfg(not(target_os = "windows"))]
+            BoundSocket::Unix(listener) => ir.listen(ShutdownListener(listener), Protocol::http()),
+        }
+    }
+}
+
+/// Attempt to bind to ports from `from` to `up_to`, inclusive, returning the first free one and its description.
 ///
 /// If an error other than the port being full is encountered it is returned.
 ///
@@ -1670,19 +2333,14 @@ impl<'r, 'p, 'ra, 'rb: 'ra> AddressWriter<'r, 'p, 'ra, 'rb> {
 /// # extern crate https;
 /// # extern crate iron;
 /// # use https::ops::try_ports;
-/// # use iron::{status, Response};
-/// let server = try_ports(|req| Ok(Response::with((status::Ok, "Abolish the burgeoisie!"))), 8000, 8100, None).unwrap();
+/// # use iron::{status, Request, Response};
+/// let (socket, _) = try_ports("127.0.0.1".parse().unwrap(), 8000, 8100, &None).unwrap();
+/// let server = socket.serve(|_: &mut Request| Ok(Response::with((status::Ok, "Abolish the burgeoisie!")))).unwrap();
 /// ```
-pub fn try_ports<H: Handler + Copy>(hndlr: H, addr: IpAddr, from: u16, up_to: u16, tls_data: &Option<((String, PathBuf), String)>) -> Result<Listening, Error> {
+pub fn try_ports(addr: IpAddr, from: u16, up_to: u16, tls: &Option<TlsServer>) -> Result<(BoundSocket, String), Error> {
     for port in from..=up_to {
-        let ir = Iron::new(hndlr);
-        match if let Some(&((_, ref id), ref pw)) = tls_data.as_ref() {
-            ir.https((addr, port),
-                     NativeTlsServer::new(id, pw).map_err(|err| Error(format!("Opening TLS certificate: {}", err)))?)
-        } else {
-            ir.http((addr, port))
-        } {
-            Ok(server) => return Ok(server),
+        match bind_port(SocketAddr::new(addr, port), tls) {
+            Ok(socket) => return Ok(socket),
             Err(iron::error::HttpError::Io(ioe)) if ioe.kind() == IoErrorKind::AddrInUse => { /* next */ }
             Err(error) => return Err(Error(format!("Starting server: {}", error))),
         }
@@ -1691,6 +2349,69 @@ pub fn try_ports<H: Handler + Copy>(hndlr: H, addr: IpAddr, from: u16, up_to: u1
     Err(Error(format!("Starting server: no free ports")))
 }
 
+/// Bind to the specified port, with TLS if it's specified, returning the socket and its description.
+pub fn bind_port(addr: SocketAddr, tls: &Option<TlsServer>) -> iron::error::HttpResult<(BoundSocket, String)> {
+    let mut listener = HttpListener::new(addr)?;
+    let addr = listener.local_addr()?;
+    Ok(match tls.as_ref() {
+        Some(tls) => (BoundSocket::Https(HttpsListener::with_listener(listener, tls.clone())), format!("https://{}", addr)),
+        None => (BoundSocket::Http(listener), format!("http://{}", addr)),
+    })
+}
+
+/// Bind to the specified socket, with TLS if it's specified for it, returning it and its description.
+pub fn bind(listen: &Listen, tls: &Option<TlsServer>) -> Result<(BoundSocket, String), Error> {
+    let err = |err: &dyn fmt::Display| Error(format!("Starting server on {}: {}", listen.address, err));
+    match listen.address {
+        ListenAddress::Tcp(addr) => {
+            let listener = tcp_listener(addr).map_err(|e| err(&e))?;
+            let addr = listener.local_addr().map_err(|e| err(&e))?;
+            let listener = HttpListener::from(listener);
+            Ok(match tls.as_ref().filter(|_| listen.tls) {
+                Some(tls) => (BoundSocket::Https(HttpsListener::with_listener(listener, tls.clone())), format!("https://{}", addr)),
+                None => (BoundSocket::Http(listener), format!("http://{}", addr)),
+            })
+        }
+        #[cfg(not(target_os = "windows"))]
+        ListenAddress::Unix(ref path, mode) => {
+            Ok((BoundSocket::Unix(UnixSocketListener::bind(path, mode).map_err(|e| err(&e))?), listen.address.to_string()))
+        }
+        #[cfg(target_os = "windows")]
+        ListenAddress::Unix(..) => Err(err(&"Unix domain sockets not supported on this platform")),
+    }
+}
+
+/// Take a socket from `activated_sockets()`,
+/// with TLS if it's named "tls" or "https", or it's a TCP socket not named "plain" or "http" and `tls` is specified,
+/// returning it and its description.
+pub fn bind_activated(socket: ActivatedSocket, name: Option<&str>, tls: &Option<TlsServer>) -> Result<(BoundSocket, String), Error> {
+    let err = |err: &dyn fmt::Display| Error(format!("Starting server on activated socket{}: {}", Maybe(name.map(|n| format!(" {}", n))), err));
+    match socket {
+        ActivatedSocket::Tcp(listener) => {
+            let addr = listener.local_addr().map_err(|e| err(&e))?;
+            let listener = HttpListener::from(listener);
+            let use_tls = match name {
+                Some("tls" | "https") => true,
+                Some("plain" | "http") => false,
+                _ => tls.is_some(),
+            };
+            match tls.as_ref().filter(|_| use_tls) {
+                Some(tls) => Ok((BoundSocket::Https(HttpsListener::with_listener(listener, tls.clone())), format!("https://{}", addr))),
+                None if use_tls => Err(err(&"TLS needs a certificate from --ssl or --gen-ssl")),
+                None => Ok((BoundSocket::Http(listener), format!("http://{}", addr))),
+            }
+        }
+        #[cfg(not(target_os = "windows"))]
+        ActivatedSocket::Unix(listener) => {
+            if let Some("tls" | "https") = name {
+                return Err(err(&"TLS not supported on Unix domain sockets"));
+            }
+            let path = listener.local_addr().ok().and_then(|addr| addr.as_pathname().map(|p| format!("unix:{}", p.display())));
+            Ok((BoundSocket::Unix(UnixSocketListener::from(listener)), path.unwrap_or_else(|| "unix socket".to_string())))
+        }
+    }
+}
+
 /// Generate a passwordless self-signed certificate in the `"tls"` subdirectory of the specified directory
 /// with the filenames `"tls.*"`.
 ///
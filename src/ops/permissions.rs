@@ -0,0 +1,193 @@
+//! Per-path permissions of users, checked before requests are dispatched.
+//!
+//! Paths without any permissions set, nor under one that has them, let everyone do everything, same as before permissions.
+
+
+use self::super::super::util::{Destination, percent_decode, error_html, url_path};
+use self::super::{HttpHandler, SiteSettings, AuditEvent, longest_path_match};
+use iron::{headers, status, method, IronResult, Response, Request};
+use iron::url::Url as GenericUrl;
+use std::borrow::Cow;
+use std::str::FromStr;
+use std::fmt;
+
+
+/// What a user can do under a path
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Permissions {
+    /// GET, HEAD, and PROPFIND files, and COPY them
+    pub read: bool,
+    /// PUT, MKCOL, PROPPATCH, and COPY and MOVE to
+    pub write: bool,
+    /// DELETE, and MOVE from
+    pub delete: bool,
+    /// GET, HEAD, and PROPFIND directories
+    pub list: bool,
+}
+
+impl Permissions {
+    pub const ALL: Permissions = Permissions {
+        read: true,
+        write: true,
+        delete: true,
+        list: true,
+    };
+    pub const NONE: Permissions = Permissions {
+        read: false,
+        write: false,
+        delete: false,
+        list: false,
+    };
+
+    /// Whether these include all of `needed`
+    pub fn allow(&self, needed: Permissions) -> bool {
+        (self.read || !needed.read) && (self.write || !needed.write) && (self.delete || !needed.delete) && (self.list || !needed.list)
+    }
+}
+
+impl FromStr for Permissions {
+    type Err = String;
+
+    /// Parse a comma-separated list of `read`, `write`, `delete`, and `list`, `all`, or `none`
+    fn from_str(s: &str) -> Result<Permissions, String> {
+        let mut perms = Permissions::NONE;
+        for perm in s.split(',') {
+            match perm {
+                "read" => perms.read = true,
+                "write" => perms.write = true,
+                "delete" => perms.delete = true,
+                "list" => perms.list = true,
+                "all" => perms = Permissions::ALL,
+                "none" => {}
+                _ => return Err(format!("{} not one of read, write, delete, list, all, or none", perm)),
+            }
+        }
+        Ok(perms)
+    }
+}
+
+impl fmt::Display for Permissions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let perms = [("read", self.read), ("write", self.write), ("delete", self.delete), ("list", self.list)];
+        if !perms.iter().any(|&(_, set)| set) {
+            return f.write_str("none");
+        }
+        for (i, (perm, _)) in perms.iter().filter(|&&(_, set)| set).enumerate() {
+            if i != 0 {
+                f.write_str(",")?;
+            }
+            f.write_str(perm)?;
+        }
+        Ok(())
+    }
+}
+
+
+impl SiteSettings {
+    /// What `user` (`None` if unauthenticated) can do at the URL: what's set for them under the longest path that has any permissions,
+    /// otherwise what's set for `*` there, otherwise nothing; everything if no path it's under has any
+    pub fn permissions_for(&self, url: &GenericUrl, user: Option<&str>) -> Permissions {
+        match longest_path_match(&self.path_permissions, url) {
+            Some(users) => user.and_then(|user| users.get(user)).or_else(|| users.get("*")).copied().unwrap_or(Permissions::NONE),
+            None => Permissions::ALL,
+        }
+    }
+}
+
+impl HttpHandler {
+    /// The user the request authenticated as, if its path needs authentication at all.
+    ///
+    /// Only meaningful after `verify_auth()` accepted the request.
+    fn authenticated_user<'r>(req: &'r Request, site: &SiteSettings) -> Option<&'r str> {
+        site.auth_for(req.url.as_ref())?;
+        match req.headers.get() {
+            Some(headers::Authorization(headers::Basic { username, .. })) => Some(username),
+            None => None,
+        }
+    }
+
+    /// What the caller can do at the requested path
+    pub(super) fn caller_permissions(&self, req: &Request) -> Permissions {
+        let live = self.live();
+        let site = live.site(req.url.as_ref());
+        site.permissions_for(req.url.as_ref(), HttpHandler::authenticated_user(req, site))
+    }
+
+    /// The allowed methods the caller has the permissions for at the requested path
+    pub(super) fn caller_allowed_methods(&self, req: &Request) -> Cow<'static, [method::Method]> {
+        if self.live().site(req.url.as_ref()).path_permissions.is_empty() {
+            return Cow::Borrowed(self.allowed_methods);
+        }
+
+        let perms = self.caller_permissions(req);
+        Cow::Owned(self.allowed_methods
+            .iter()
+            .filter(|m| match **m {
+                method::Get | method::Head | method::DavPropfind => perms.read || perms.list,
+                method::Put | method::DavMkcol | method::DavProppatch => perms.write,
+                method::Delete | method::DavMove => perms.delete,
+                method::DavCopy => perms.read,
+                _ => true,
+            })
+            .cloned()
+            .collect())
+    }
+
+    /// Check the caller has the permissions the request needs, for its path and its Destination, if any
+    pub(super) fn verify_permissions(&self, req: &mut Request, site: &SiteSettings) -> IronResult<Option<Response>> {
+        let needed = match req.method {
+            method::Get | method::Head | method::DavPropfind => {
+                if self.parse_requested_path(req).0.is_dir() {
+                    Permissions { list: true, ..Permissions::NONE }
+                } else {
+                    Permissions { read: true, ..Permissions::NONE }
+                }
+            }
+            method::Put | method::DavMkcol | method::DavProppatch => Permissions { write: true, ..Permissions::NONE },
+            method::Delete | method::DavMove => Permissions { delete: true, ..Permissions::NONE },
+            method::DavCopy => Permissions { read: true, ..Permissions::NONE },
+            _ => Permissions::NONE,
+        };
+        let destination = match req.method {
+            method::DavCopy | method::DavMove => {
+                req.headers.get::<Destination>().map(|dest| {
+                    // Same as what COPY and MOVE do
+                    let mut dest_url = dest.0.clone();
+                    let _ = dest_url.set_host(req.url.as_ref().host_str());
+                    dest_url
+                })
+            }
+            _ => None,
+        };
+
+        let user = HttpHandler::authenticated_user(req, site);
+        let denied = if !site.permissions_for(req.url.as_ref(), user).allow(needed) {
+            Some((url_path(&req.url).into_owned(), needed))
+        } else {
+            let needed = Permissions { write: true, ..Permissions::NONE };
+            destination.filter(|dest| !site.permissions_for(dest, user).allow(needed))
+                .map(|dest| (percent_decode(dest.path()).unwrap_or_default().into_owned(), needed))
+        };
+        let (path, needed) = match denied {
+            Some(denied) => denied,
+            None => return Ok(None),
+        };
+        if user.is_none() && site.auth_for(req.url.as_ref()).is_some() {
+            // Only with --auth-writes-only: prompt for credentials, whose user might have the permissions
+            return Ok(Some(self.handle_credentials_required(req)));
+        }
+
+        log!(self.log,
+             "{} tried to {red}{}{reset} {yellow}{}{reset} without {blue}{}{reset} permission for {magenta}{}{reset}",
+             self.remote_addresses(&req),
+             req.method,
+             req.url,
+             needed,
+             path);
+        self.audit(req, AuditEvent::Forbidden, user);
+        self.handle_generated_response_encoding(req,
+                                                status::Forbidden,
+                                                error_html("403 Forbidden", format_args!("You can't {} {}.", req.method, path), ""))
+            .map(Some)
+    }
+}
@@ -1,11 +1,99 @@
 use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
 use iron::{IronResult, Response, Handler, Request};
 use self::super::super::util::HumanReadableSize;
+use std::collections::{BTreeMap, HashSet};
 use self::super::super::Options;
-use std::collections::HashSet;
 use self::super::HttpHandler;
 use time::precise_time_ns;
-use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::{fmt, fs};
+
+
+/// Time-ordered index of cache entries, to find the least recently used ones in O(log n).
+///
+/// Cache hits only bump the entry's atime; the index catches up lazily when popping,
+/// by re-queueing entries whose atime moved since they were indexed.
+#[derive(Debug)]
+pub struct LruIndex<K>(Mutex<LruEntries<K>>);
+
+#[derive(Debug)]
+struct LruEntries<K> {
+    entries: BTreeMap<(u64, u64), K>, // (atime, sequence) -> key
+    next_seq: u64,
+}
+
+impl<K> LruIndex<K> {
+    /// Index `key`, last accessed at `atime`.
+    pub fn insert(&self, atime: u64, key: K) {
+        self.0.lock().expect("LRU index lock poisoned").insert(atime, key);
+    }
+
+    /// Remove and return the least recently used key, if it was last accessed before `before`.
+    ///
+    /// `atime_of` returns the current atime of a key, or `None` if it's no longer cached.
+    pub fn pop_lru_before<F: FnMut(&K) -> Option<u64>>(&self, before: u64, mut atime_of: F) -> Option<K> {
+        let mut index = self.0.lock().expect("LRU index lock poisoned");
+        loop {
+            let ((indexed_atime, _), key) = index.entries.pop_first()?;
+            match atime_of(&key) {
+                None => {}
+                Some(atime) if atime == indexed_atime && atime < before => return Some(key),
+                Some(atime) => {
+                    index.insert(atime, key);
+                    if atime == indexed_atime {
+                        return None;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<K> LruEntries<K> {
+    fn insert(&mut self, atime: u64, key: K) {
+        self.entries.insert((atime, self.next_seq), key);
+        self.next_seq += 1;
+    }
+}
+
+impl<K> Default for LruIndex<K> {
+    fn default() -> LruIndex<K> {
+        LruIndex(Mutex::new(LruEntries {
+            entries: BTreeMap::new(),
+            next_seq: 0,
+        }))
+    }
+}
+
+
+/// Hit, miss, and eviction counters for an encoded cache
+#[derive(Debug, Default)]
+pub struct CacheStats {
+    pub hits: AtomicU64,
+    pub misses: AtomicU64,
+    pub evictions: AtomicU64,
+}
+
+impl CacheStats {
+    pub fn hit(&self) {
+        self.hits.fetch_add(1, AtomicOrdering::Relaxed);
+    }
+
+    pub fn miss(&self) {
+        self.misses.fetch_add(1, AtomicOrdering::Relaxed);
+    }
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "{}/{}/{}",
+               self.hits.load(AtomicOrdering::Relaxed),
+               self.misses.load(AtomicOrdering::Relaxed),
+               self.evictions.load(AtomicOrdering::Relaxed))
+    }
+}
 
 
 pub struct PruneChain {
@@ -35,32 +123,13 @@ impl PruneChain {
         let mut start = 0u64;
         let mut freed_fs = 0u64;
         let mut freed_gen = 0u64;
+        let mut removed_fs = vec![];
 
 
         if let Some(limit) = self.encoded_filesystem_limit {
             if self.handler.cache_fs_size.load(AtomicOrdering::Relaxed) > limit {
                 start = precise_time_ns();
-
-                let mut cache_files = self.handler.cache_fs_files.write().expect("Filesystem files cache write lock poisoned");
-                let mut removed_file_hashes = HashSet::new();
-                let mut cache = self.handler.cache_fs.write().expect("Filesystem cache write lock poisoned");
-                let size = self.handler.cache_fs_size.load(AtomicOrdering::Relaxed);
-                while size - freed_fs > limit {
-                    let key = match cache.iter().min_by_key(|i| (i.1).1.load(AtomicOrdering::Relaxed)) {
-                        Some((key, ((path, _, _), _))) => {
-                            match fs::remove_file(path) {
-                                Ok(()) => *key,
-                                Err(_) => break,
-                            }
-                        }
-                        None => break,
-                    };
-                    let ((_, _, sz), _) = cache.remove(&key).unwrap();
-                    freed_fs += sz;
-                    removed_file_hashes.insert(key.0);
-                }
-                self.handler.cache_fs_size.fetch_sub(freed_fs, AtomicOrdering::Relaxed);
-                cache_files.retain(|_, v| !removed_file_hashes.contains(v));
+                self.prune_fs(u64::MAX, Some(limit), &mut freed_fs, &mut removed_fs);
             }
         }
 
@@ -69,18 +138,7 @@ impl PruneChain {
                 if start == 0 {
                     start = precise_time_ns();
                 }
-
-                let mut cache = self.handler.cache_gen.write().expect("Generated file cache write lock poisoned");
-                let size = self.handler.cache_gen_size.load(AtomicOrdering::Relaxed);
-                while size - freed_gen > limit {
-                    let key = match cache.iter().min_by_key(|i| (i.1).1.load(AtomicOrdering::Relaxed)) {
-                        Some((key, _)) => key.clone(),
-                        None => break,
-                    };
-                    let (data, _) = cache.remove(&key).unwrap();
-                    freed_gen += data.len() as u64;
-                }
-                self.handler.cache_gen_size.fetch_sub(freed_gen, AtomicOrdering::Relaxed);
+                self.prune_gen(u64::MAX, Some(limit), &mut freed_gen);
             }
         }
 
@@ -91,53 +149,90 @@ impl PruneChain {
 
             let last = self.last_prune.swap(start, AtomicOrdering::Relaxed);
             if last < start && (start - last) / 1000 / 1000 / 1000 >= self.prune_interval {
-                {
-                    let mut cache_files = self.handler.cache_fs_files.write().expect("Filesystem files cache write lock poisoned");
-                    let mut removed_file_hashes = HashSet::new();
-                    let mut cache = self.handler.cache_fs.write().expect("Filesystem cache write lock poisoned");
-                    cache.retain(|(hash, _), ((path, _, sz), atime)| {
-                        let atime = atime.load(AtomicOrdering::Relaxed);
-                        if atime > start || (start - atime) / 1000 / 1000 / 1000 <= limit {
-                            return true;
-                        }
-
-                        if fs::remove_file(path).is_err() {
-                            return true;
-                        }
-                        freed_fs += *sz;
-                        self.handler.cache_fs_size.fetch_sub(*sz, AtomicOrdering::Relaxed);
-                        removed_file_hashes.insert(*hash);
-                        false
-                    });
-                    cache_files.retain(|_, v| !removed_file_hashes.contains(v));
-                }
-                {
-                    let mut cache = self.handler.cache_gen.write().expect("Generated file cache write lock poisoned");
-                    cache.retain(|_, (data, atime)| {
-                        let atime = atime.load(AtomicOrdering::Relaxed);
-                        if atime > start || (start - atime) / 1000 / 1000 / 1000 <= limit {
-                            return true;
-                        }
-
-                        freed_gen += data.len() as u64;
-                        self.handler.cache_gen_size.fetch_sub(data.len() as u64, AtomicOrdering::Relaxed);
-                        false
-                    });
-                }
+                let before = start.saturating_sub(limit.saturating_mul(1000 * 1000 * 1000));
+                self.prune_fs(before, None, &mut freed_fs, &mut removed_fs);
+                self.prune_gen(before, None, &mut freed_gen);
             }
         }
 
+        // Outside the cache lock, so requests aren't stalled on the filesystem
+        for path in removed_fs {
+            let _ = fs::remove_file(path);
+        }
+
         if freed_fs != 0 || freed_gen != 0 {
             let end = precise_time_ns();
             log!(self.handler.log,
-                 "Pruned {} + {} in {}ns; used: {} + {}",
+                 "Pruned {} + {} in {}ns; used: {} + {}; hits/misses/evictions: {} + {}",
                  HumanReadableSize(freed_fs),
                  HumanReadableSize(freed_gen),
                  end - start,
                  HumanReadableSize(self.handler.cache_fs_size.load(AtomicOrdering::Relaxed)),
-                 HumanReadableSize(self.handler.cache_gen_size.load(AtomicOrdering::Relaxed)));
+                 HumanReadableSize(self.handler.cache_gen_size.load(AtomicOrdering::Relaxed)),
+                 self.handler.cache_fs_stats,
+                 self.handler.cache_gen_stats);
+        }
+    }
+
+    /// Evict encoded filesystem files last accessed before `before`, or, if `limit` is specified, until they fit in it
+    fn prune_fs(&self, before: u64, limit: Option<u64>, freed: &mut u64, removed: &mut Vec<PathBuf>) {
+        let mut cache_files = self.handler.cache_fs_files.write().expect("Filesystem files cache write lock poisoned");
+        let mut cache = self.handler.cache_fs.write().expect("Filesystem cache write lock poisoned");
+        let mut removed_file_hashes = HashSet::new();
+        while limit.map(|limit| self.handler.cache_fs_size.load(AtomicOrdering::Relaxed) > limit).unwrap_or(true) {
+            let key = match self.handler.cache_fs_lru.pop_lru_before(before, |k| cache.get(k).map(|e| e.1.load(AtomicOrdering::Relaxed))) {
+                Some(key) => key,
+                None => break,
+            };
+            let ((path, _, sz), _) = cache.remove(&key).unwrap();
+            self.handler.cache_fs_size.fetch_sub(sz, AtomicOrdering::Relaxed);
+            self.handler.cache_fs_stats.evictions.fetch_add(1, AtomicOrdering::Relaxed);
+            *freed += sz;
+            removed_file_hashes.insert(key.0);
+            removed.push(path);
+        }
+        if !removed_file_hashes.is_empty() {
+            let mut removed_etags = HashSet::new();
+            cache_files.retain(|etag, v| if removed_file_hashes.contains(v) {
+                removed_etags.insert(etag.clone());
+                false
+            } else {
+                true
+            });
+            self.handler.cache_fs_paths.write().expect("Filesystem path cache write lock poisoned").retain(|_, etag| !removed_etags.contains(etag));
         }
     }
+
+    /// Evict encoded generated responses last accessed before `before`, or, if `limit` is specified, until they fit in it
+    fn prune_gen(&self, before: u64, limit: Option<u64>, freed: &mut u64) {
+        let mut cache = self.handler.cache_gen.write().expect("Generated file cache write lock poisoned");
+        let mut removed_hashes = HashSet::new();
+        while limit.map(|limit| self.handler.cache_gen_size.load(AtomicOrdering::Relaxed) > limit).unwrap_or(true) {
+            let key = match self.handler.cache_gen_lru.pop_lru_before(before, |k| cache.get(k).map(|e| e.1.load(AtomicOrdering::Relaxed))) {
+                Some(key) => key,
+                None => break,
+            };
+            let (data, _) = cache.remove(&key).unwrap();
+            self.handler.cache_gen_size.fetch_sub(data.len() as u64, AtomicOrdering::Relaxed);
+            self.handler.cache_gen_stats.evictions.fetch_add(1, AtomicOrdering::Relaxed);
+            *freed += data.len() as u64;
+            removed_hashes.insert(key.0);
+        }
+        if !removed_hashes.is_empty() {
+            let mut cache_gen_paths = self.handler.cache_gen_paths.write().expect("Generated path cache write lock poisoned");
+            cache_gen_paths.retain(|_, hashes| {
+                hashes.retain(|hash| !removed_hashes.contains(hash));
+                !hashes.is_empty()
+            });
+        }
+    }
+
+    pub fn log_stats(&self) {
+        log!(self.handler.log,
+             "Encoded cache hits/misses/evictions: filesystem {}, generated {}",
+             self.handler.cache_fs_stats,
+             self.handler.cache_gen_stats);
+    }
 }
 
 impl Handler for &'static PruneChain {
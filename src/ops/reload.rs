@@ -0,0 +1,76 @@
+use self::super::{HttpHandler, LiveSettings, LimitBandwidthMiddleware, generate_auth_data};
+use self::super::super::options::{LogLevel, Options};
+use self::super::super::util::CommaList;
+use std::sync::Arc;
+use std::env;
+
+
+impl HttpHandler {
+    /// Re-read the command line and `--config` file, and swap in the new `LiveSettings` and bandwidth limit.
+    ///
+    /// `opts` are the options currently in effect, and get updated to match;
+    /// changes to options that can't be applied while running are logged and otherwise ignored.
+    ///
+    /// Refused under `--chroot`, since the command line's paths wouldn't resolve to the same places inside it.
+    pub fn reload(&self, opts: &mut Options, bandwidth: &LimitBandwidthMiddleware) {
+        if opts.chroot.is_some() {
+            log!(self.log, "{red}Not reloading configuration{reset}: unsupported with --chroot, restart to apply changes");
+            return;
+        }
+
+        let mut new = match Options::parse_from(env::args_os().collect()) {
+            Ok(new) => new,
+            Err(err) => {
+                log!(self.log,
+                     "Failed to reload configuration: {red}{}{reset}",
+                     err.message.lines().next().unwrap_or_default().trim_start_matches("error: "));
+                return;
+            }
+        };
+
+        for path in &new.generate_path_auth {
+            let creds = match opts.path_auth_data.get(path) {
+                Some(creds) if opts.generate_path_auth.contains(path) => creds.clone(),
+                _ => {
+                    let creds = generate_auth_data();
+                    if opts.loglevel < LogLevel::NoAuth {
+                        log!(self.log, "Generated credentials for {magenta}/{}{reset}: {}", path, creds);
+                    }
+                    vec![creds]
+                }
+            };
+            new.path_auth_data.insert(path.clone(), creds);
+        }
+
+        let restart_needed = opts.restart_needed(&new);
+        opts.path_auth_data = new.path_auth_data;
+        opts.generate_path_auth = new.generate_path_auth;
+        opts.htpasswd = new.htpasswd;
+        opts.path_permissions = new.path_permissions;
+        opts.auth_writes_only = new.auth_writes_only;
+        opts.proxies = new.proxies;
+        opts.proxy_redirs = new.proxy_redirs;
+        opts.mime_type_overrides = new.mime_type_overrides;
+        opts.additional_headers = new.additional_headers;
+        opts.request_bandwidth = new.request_bandwidth;
+        for (host, vhost) in &mut opts.vhosts {
+            if let Some(new_vhost) = new.vhosts.remove(host) {
+                vhost.path_auth_data = new_vhost.path_auth_data;
+                vhost.htpasswd = new_vhost.htpasswd;
+                vhost.path_permissions = new_vhost.path_permissions;
+                vhost.additional_headers = new_vhost.additional_headers;
+            }
+        }
+
+        *self.live.write().expect("Live settings write lock poisoned") = Arc::new(LiveSettings::new(opts));
+        self.verified_passwords.clear();
+        bandwidth.set(opts.request_bandwidth);
+
+        log!(self.log, "Reloaded configuration");
+        if !restart_needed.is_empty() {
+            log!(self.log,
+                 "{yellow}Restart needed{reset} to apply changes to {}",
+                 CommaList(restart_needed.iter()));
+        }
+    }
+}
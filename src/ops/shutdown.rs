@@ -0,0 +1,136 @@
+//! Graceful shutdown: once it begins, new connections are closed as soon as they're accepted,
+//! and requests in progress – including writing out their responses – get a deadline to finish.
+
+
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::sync::{Condvar, Mutex};
+use hyper::net::NetworkListener;
+use iron::response::WriteBody;
+use self::super::HttpHandler;
+use std::time::Duration;
+use std::net::SocketAddr;
+use std::io::{self, Write};
+use iron::{headers, Response};
+use hyper;
+
+
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+static ABANDONED: AtomicBool = AtomicBool::new(false);
+static ACTIVE_REQUESTS: Mutex<usize> = Mutex::new(0);
+static REQUEST_DONE: Condvar = Condvar::new();
+
+
+/// Whether shutdown has begun
+pub fn shutting_down() -> bool {
+    SHUTTING_DOWN.load(AtomicOrdering::Relaxed)
+}
+
+/// Stop waiting for requests in progress, e.g. on a second Ctrl-C
+pub fn abandon_shutdown() {
+    let _active = ACTIVE_REQUESTS.lock().expect("Active request count lock poisoned");
+    ABANDONED.store(true, AtomicOrdering::Relaxed);
+    REQUEST_DONE.notify_all();
+}
+
+
+/// A request in progress until dropped
+pub struct ActiveRequest(());
+
+impl ActiveRequest {
+    pub fn start() -> ActiveRequest {
+        *ACTIVE_REQUESTS.lock().expect("Active request count lock poisoned") += 1;
+        ActiveRequest(())
+    }
+
+    /// Keep the request in progress until `resp` is written out
+    pub fn until_written(self, mut resp: Response) -> Response {
+        // Same as what Iron does for body-less responses, which would otherwise be written after this is dropped
+        if resp.body.is_none() && !resp.headers.has::<headers::ContentLength>() {
+            resp.headers.set(headers::ContentLength(0));
+        }
+        resp.body = Some(Box::new(ActiveRequestWriteBody {
+            underlying: resp.body.take(),
+            _active: self,
+        }));
+        resp
+    }
+}
+
+impl Drop for ActiveRequest {
+    fn drop(&mut self) {
+        *ACTIVE_REQUESTS.lock().expect("Active request count lock poisoned") -= 1;
+        REQUEST_DONE.notify_all();
+    }
+}
+
+struct ActiveRequestWriteBody {
+    underlying: Option<Box<dyn WriteBody>>,
+    _active: ActiveRequest,
+}
+
+impl WriteBody for ActiveRequestWriteBody {
+    fn write_body(&mut self, res: &mut dyn Write) -> io::Result<()> {
+        match self.underlying.as_mut() {
+            Some(body) => body.write_body(res),
+            None => Ok(()),
+        }
+    }
+}
+
+
+/// A Hyper listener that closes connections as soon as they're accepted once shutdown has begun
+#[derive(Clone)]
+pub struct ShutdownListener<L>(pub L);
+
+impl<L: NetworkListener> NetworkListener for ShutdownListener<L> {
+    type Stream = L::Stream;
+
+    fn accept(&mut self) -> hyper::Result<L::Stream> {
+        loop {
+            let stream = self.0.accept()?;
+            if !shutting_down() {
+                return Ok(stream);
+            }
+        }
+    }
+
+    fn local_addr(&mut self) -> io::Result<SocketAddr> {
+        self.0.local_addr()
+    }
+
+    fn set_read_timeout(&mut self, dur: Option<Duration>) {
+        self.0.set_read_timeout(dur)
+    }
+
+    fn set_write_timeout(&mut self, dur: Option<Duration>) {
+        self.0.set_write_timeout(dur)
+    }
+}
+
+
+impl HttpHandler {
+    /// Stop taking new connections, and wait up to `timeout` (or until `abandon_shutdown()`) for the requests in progress to finish,
+    /// logging how many didn't
+    pub fn shut_down(&self, timeout: Duration) {
+        SHUTTING_DOWN.store(true, AtomicOrdering::Relaxed);
+
+        let active = ACTIVE_REQUESTS.lock().expect("Active request count lock poisoned");
+        if *active == 0 {
+            return;
+        }
+        log!(self.log,
+             "Shutting down, waiting up to {}s for {} request{} to finish",
+             timeout.as_secs(),
+             *active,
+             if *active == 1 { "" } else { "s" });
+
+        let active = *REQUEST_DONE.wait_timeout_while(active, timeout, |active| *active != 0 && !ABANDONED.load(AtomicOrdering::Relaxed))
+            .expect("Active request count lock poisoned")
+            .0;
+        if active == 0 {
+            log!(self.log, "All requests finished");
+        } else {
+            log!(self.log, "{red}Aborted {} request{}{reset}", active, if active == 1 { "" } else { "s" });
+        }
+    }
+}
@@ -0,0 +1,180 @@
+//! TLS with the identity picked by the host the client asks for with SNI.
+//!
+//! native-tls can't do this, so where it'd use OpenSSL anyway and there are per-host identities,
+//! this uses OpenSSL directly, set up the same way; elsewhere, per-host identities aren't supported.
+
+
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+use openssl::ssl::{SslAcceptorBuilder, SslAcceptor, SslVersion, SslMethod, SslStream, NameType, SniError};
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+use hyper::net::{SslServer, NetworkStream};
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+use hyper_native_tls::TlsStream as NativeTlsStream;
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+use std::collections::HashMap;
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+use std::sync::{Arc, Mutex};
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+use openssl::pkcs12::Pkcs12;
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+use std::net::SocketAddr;
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+use std::time::Duration;
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+use std::{fmt, fs, io};
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+use hyper;
+use hyper_native_tls::NativeTlsServer;
+use std::path::Path;
+
+
+/// An `SslServer` presenting the default identity, or, if there are per-host ones,
+/// the identity for the host the client asks for with SNI, if it has one
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+#[derive(Clone)]
+pub enum TlsServer {
+    Native(NativeTlsServer),
+    Sni(Arc<SslAcceptor>),
+}
+
+/// An `SslServer` presenting the default identity
+#[cfg(any(target_os = "windows", target_os = "macos"))]
+pub type TlsServer = NativeTlsServer;
+
+/// A Hyper stream for `TlsServer`
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+#[derive(Debug, Clone)]
+pub enum TlsStream<S> {
+    Native(NativeTlsStream<S>),
+    Sni(Arc<Mutex<SslStream<S>>>),
+}
+
+
+/// Load the default PKCS#12 identity and the per-host ones, as `(host, identity, password)`.
+///
+/// The errors are suitable for "Opening TLS certificate: {}".
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+pub fn tls_server<'h, I: IntoIterator<Item = (&'h str, &'h Path, &'h str)>>(identity: &Path, password: &str, hosts: I) -> Result<TlsServer, String> {
+    let mut contexts = HashMap::new();
+    for (host, identity, password) in hosts {
+        contexts.insert(host.to_string(), acceptor(identity, password)?.build().into_context());
+    }
+    if contexts.is_empty() {
+        return NativeTlsServer::new(identity, password).map(TlsServer::Native).map_err(|err| err.to_string());
+    }
+
+    let mut acceptor = acceptor(identity, password)?;
+    acceptor.set_servername_callback(move |ssl, _| {
+        if let Some(context) = ssl.servername(NameType::HOST_NAME).and_then(|host| contexts.get(&host.to_lowercase()[..]).cloned()) {
+            ssl.set_ssl_context(&context).map_err(|_| SniError::ALERT_FATAL)?;
+        }
+        Ok(())
+    });
+    Ok(TlsServer::Sni(Arc::new(acceptor.build())))
+}
+
+/// Load the default PKCS#12 identity; per-host ones aren't supported here.
+///
+/// The errors are suitable for "Opening TLS certificate: {}".
+#[cfg(any(target_os = "windows", target_os = "macos"))]
+pub fn tls_server<'h, I: IntoIterator<Item = (&'h str, &'h Path, &'h str)>>(identity: &Path, password: &str, hosts: I) -> Result<TlsServer, String> {
+    if let Some((host, _, _)) = hosts.into_iter().next() {
+        return Err(format!("per-host certificates (for {}) not supported on this platform", host));
+    }
+    NativeTlsServer::new(identity, password).map_err(|err| err.to_string())
+}
+
+/// Same as native-tls' `TlsAcceptor::new()` with the default `TlsAcceptorBuilder` (as of native-tls 0.2.18)
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+fn acceptor(identity: &Path, password: &str) -> Result<SslAcceptorBuilder, String> {
+    let err = |err: &dyn fmt::Display| format!("{}: {}", identity.display(), err);
+
+    let der = fs::read(identity).map_err(|e| err(&e))?;
+    let identity_data = Pkcs12::from_der(&der).and_then(|p| p.parse2(password)).map_err(|e| err(&e))?;
+    let (pkey, cert) = match (identity_data.pkey, identity_data.cert) {
+        (Some(pkey), Some(cert)) => (pkey, cert),
+        _ => return Err(err(&"no private key or certificate")),
+    };
+
+    let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).map_err(|e| err(&e))?;
+    acceptor.set_private_key(&pkey).map_err(|e| err(&e))?;
+    acceptor.set_certificate(&cert).map_err(|e| err(&e))?;
+    // PKCS12_parse() returns the chain backwards
+    for cert in identity_data.ca.into_iter().flatten().rev() {
+        acceptor.add_extra_chain_cert(cert).map_err(|e| err(&e))?;
+    }
+    acceptor.set_min_proto_version(Some(SslVersion::TLS1_2)).map_err(|e| err(&e))?;
+    acceptor.set_max_proto_version(None).map_err(|e| err(&e))?;
+    Ok(acceptor)
+}
+
+
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+impl<T> SslServer<T> for TlsServer
+    where T: NetworkStream + Send + Clone + fmt::Debug + Sync
+{
+    type Stream = TlsStream<T>;
+
+    fn wrap_server(&self, stream: T) -> hyper::Result<TlsStream<T>> {
+        match *self {
+            TlsServer::Native(ref server) => server.wrap_server(stream).map(TlsStream::Native),
+            TlsServer::Sni(ref acceptor) => {
+                match acceptor.accept(stream) {
+                    Ok(s) => Ok(TlsStream::Sni(Arc::new(Mutex::new(s)))),
+                    Err(e) => Err(hyper::Error::Ssl(Box::new(e))),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+impl<S: io::Read + io::Write> io::Read for TlsStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            TlsStream::Native(ref mut s) => s.read(buf),
+            TlsStream::Sni(ref s) => s.lock().expect("TLS stream lock poisoned").read(buf),
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+impl<S: io::Read + io::Write> io::Write for TlsStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            TlsStream::Native(ref mut s) => s.write(buf),
+            TlsStream::Sni(ref s) => s.lock().expect("TLS stream lock poisoned").write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            TlsStream::Native(ref mut s) => s.flush(),
+            TlsStream::Sni(ref s) => s.lock().expect("TLS stream lock poisoned").flush(),
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+impl<S: NetworkStream> NetworkStream for TlsStream<S> {
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        match *self {
+            TlsStream::Native(ref mut s) => s.peer_addr(),
+            TlsStream::Sni(ref s) => s.lock().expect("TLS stream lock poisoned").get_mut().peer_addr(),
+        }
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        match *self {
+            TlsStream::Native(ref s) => s.set_read_timeout(dur),
+            TlsStream::Sni(ref s) => s.lock().expect("TLS stream lock poisoned").get_ref().set_read_timeout(dur),
+        }
+    }
+
+    fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        match *self {
+            TlsStream::Native(ref s) => s.set_write_timeout(dur),
+            TlsStream::Sni(ref s) => s.lock().expect("TLS stream lock poisoned").get_ref().set_write_timeout(dur),
+        }
+    }
+}
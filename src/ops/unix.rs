@@ -0,0 +1,140 @@
+//! Serving over Unix domain sockets, whose connections appear to come from `UNIX_SOCKET_PEER`.
+
+
+use std::os::unix::fs::{PermissionsExt, FileTypeExt};
+use std::io::{self, ErrorKind as IoErrorKind, Read, Write};
+use self::super::super::util::UNIX_SOCKET_PEER;
+use hyper::net::{NetworkListener, NetworkStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::net::{SocketAddr, Shutdown};
+use std::time::Duration;
+use std::path::Path;
+use std::sync::Arc;
+use std::{fmt, fs};
+use hyper;
+
+
+/// A Hyper listener for a Unix domain socket
+#[derive(Clone)]
+pub struct UnixSocketListener {
+    listener: Arc<UnixListener>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+}
+
+/// A Hyper stream for `UnixSocketListener`
+pub struct UnixSocketStream(UnixStream);
+
+
+impl UnixSocketListener {
+    /// Listen on the socket at `path`, replacing it if it's stale, and set its permissions to `mode`, if any
+    pub fn bind(path: &Path, mode: Option<u32>) -> io::Result<UnixSocketListener> {
+        remove_stale_socket(path)?;
+        let listener = UnixListener::bind(path)?;
+        if let Some(mode) = mode {
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        }
+        Ok(UnixSocketListener::from(listener))
+    }
+}
+
+impl From<UnixListener> for UnixSocketListener {
+    fn from(listener: UnixListener) -> UnixSocketListener {
+        UnixSocketListener {
+            listener: Arc::new(listener),
+            read_timeout: None,
+            write_timeout: None,
+        }
+    }
+}
+
+/// Remove the socket at `path` if it's left over from a previous run, i.e. nothing's listening on it.
+///
+/// A live socket and anything that isn't a socket are left alone, and are an error.
+pub fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => {
+            match UnixStream::connect(path) {
+                Ok(_) => Err(io::Error::new(IoErrorKind::AddrInUse, "socket in use")),
+                Err(err) if err.kind() == IoErrorKind::ConnectionRefused => fs::remove_file(path),
+                Err(err) => Err(err),
+            }
+        }
+        Ok(_) => Err(io::Error::new(IoErrorKind::AlreadyExists, "not a socket")),
+        Err(err) if err.kind() == IoErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+
+impl NetworkListener for UnixSocketListener {
+    type Stream = UnixSocketStream;
+
+    fn accept(&mut self) -> hyper::Result<UnixSocketStream> {
+        let stream = UnixSocketStream(self.listener.accept()?.0);
+        stream.set_read_timeout(self.read_timeout)?;
+        stream.set_write_timeout(self.write_timeout)?;
+        Ok(stream)
+    }
+
+    fn local_addr(&mut self) -> io::Result<SocketAddr> {
+        Ok(UNIX_SOCKET_PEER)
+    }
+
+    fn set_read_timeout(&mut self, dur: Option<Duration>) {
+        self.read_timeout = dur;
+    }
+
+    fn set_write_timeout(&mut self, dur: Option<Duration>) {
+        self.write_timeout = dur;
+    }
+}
+
+impl Clone for UnixSocketStream {
+    fn clone(&self) -> UnixSocketStream {
+        UnixSocketStream(self.0.try_clone().expect("Failed to clone Unix domain socket"))
+    }
+}
+
+impl fmt::Debug for UnixSocketStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("UnixSocketStream(_)")
+    }
+}
+
+impl Read for UnixSocketStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for UnixSocketStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl NetworkStream for UnixSocketStream {
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        Ok(UNIX_SOCKET_PEER)
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.0.set_read_timeout(dur)
+    }
+
+    fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.0.set_write_timeout(dur)
+    }
+
+    fn close(&mut self, how: Shutdown) -> io::Result<()> {
+        match self.0.shutdown(how) {
+            Err(err) if err.kind() == IoErrorKind::NotConnected => Ok(()),
+            res => res,
+        }
+    }
+}
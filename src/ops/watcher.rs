@@ -0,0 +1,133 @@
+use self::super::super::util::{watch_tree, SUPPORTED_ENCODINGS};
+use std::sync::atomic::Ordering as AtomicOrdering;
+use iron::headers::EncodingType;
+use std::collections::HashSet;
+use self::super::HttpHandler;
+use std::path::Path;
+use std::{fs, io, mem, thread};
+use blake3;
+
+
+impl HttpHandler {
+    /// Watch the hosted directory and mounts for changes in background threads, to drop encoded filesystem files and hashes as soon as they go stale.
+    ///
+    /// Where this isn't supported, changes are only picked up once they change the etag.
+    pub fn start_watcher(&'static self) {
+        if self.encoded_temp_dir.is_none() {
+            return;
+        }
+
+        for mount in self.sites().flat_map(|site| &site.mounts) {
+            thread::Builder::new()
+                .name("fs-watcher".to_string())
+                .spawn(move || {
+                    let unwatched = |dir: &Path, err| {
+                        log!(self.log,
+                             "Not watching {magenta}{}{reset} for changes: {} (further directories that can't be watched aren't logged)",
+                             dir.display(),
+                             err);
+                    };
+                    if let Err(err) = watch_tree(&mount.directory.1, self.follow_symlinks, |path, is_dir| self.invalidate_path(path, is_dir), unwatched) {
+                        if err.kind() != io::ErrorKind::Unsupported {
+                            log!(self.log, "Not watching {magenta}{}{reset} for changes: {}", mount.directory.0, err);
+                        }
+                    }
+                })
+                .expect("Failed to spawn filesystem watcher thread");
+        }
+    }
+
+    /// Forget the hashes of the file at `path` (or of all files under it, if it's a directory) and drop their encoded versions,
+    /// and those of the listings of its directory (and of it and all directories under it, if it's one)
+    pub fn invalidate_path(&self, path: &Path, is_dir: bool) {
+        let listings = {
+            let mut cache_gen_paths = self.cache_gen_paths.write().expect("Generated path cache write lock poisoned");
+            let mut listings = path.parent().and_then(|parent| cache_gen_paths.remove(parent)).unwrap_or_default();
+            if is_dir {
+                cache_gen_paths.retain(|p, hashes| if p.starts_with(path) {
+                    listings.extend(hashes.drain());
+                    false
+                } else {
+                    true
+                });
+            }
+            listings
+        };
+        if !listings.is_empty() {
+            self.drop_generated_hashes(listings);
+        }
+
+        let etags = {
+            let mut cache_paths = self.cache_fs_paths.write().expect("Filesystem path cache write lock poisoned");
+            if is_dir {
+                let mut etags = vec![];
+                cache_paths.retain(|p, etag| if p.starts_with(path) {
+                    etags.push(mem::take(etag));
+                    false
+                } else {
+                    true
+                });
+                etags
+            } else {
+                cache_paths.remove(path).into_iter().collect()
+            }
+        };
+        if etags.is_empty() {
+            return;
+        }
+
+        let hashes = {
+            let mut cache_files = self.cache_fs_files.write().expect("Filesystem file cache write lock poisoned");
+            let mut hashes = etags.iter().flat_map(|etag| cache_files.remove(etag)).collect::<HashSet<_>>();
+            if !hashes.is_empty() {
+                // Identical content elsewhere is still good
+                for hash in cache_files.values() {
+                    hashes.remove(hash);
+                }
+            }
+            hashes
+        };
+        let dropped = self.drop_encoded(hashes.into_iter().flat_map(|hash| SUPPORTED_ENCODINGS.iter().map(move |&enc| (hash, enc))));
+
+        log!(self.log,
+             "{magenta}{}{reset} changed, dropped {} hash{} and {} encoded file{}",
+             path.display(),
+             etags.len(),
+             if etags.len() == 1 { "" } else { "es" },
+             dropped,
+             if dropped == 1 { "" } else { "s" });
+    }
+
+    /// Remove the encoded filesystem files with the specified content hashes and encodings, returning how many there were
+    pub fn drop_encoded<I: IntoIterator<Item = (blake3::Hash, EncodingType)>>(&self, keys: I) -> usize {
+        let mut removed = vec![];
+        {
+            let mut cache = self.cache_fs.write().expect("Filesystem cache write lock poisoned");
+            for key in keys {
+                if let Some(((path, worth_it, size), _)) = cache.remove(&key) {
+                    self.cache_fs_size.fetch_sub(size, AtomicOrdering::Relaxed);
+                    if worth_it {
+                        removed.push(path);
+                    }
+                }
+            }
+        }
+
+        for path in &removed {
+            let _ = fs::remove_file(path);
+        }
+        removed.len()
+    }
+
+    /// Remove all encoded generated responses with the specified content hashes
+    fn drop_generated_hashes<I: IntoIterator<Item = blake3::Hash>>(&self, hashes: I) {
+        let mut cache = self.cache_gen.write().expect("Generated file cache write lock poisoned");
+        for hash in hashes {
+            for &enc in SUPPORTED_ENCODINGS {
+                if let Some((data, _)) = cache.remove(&(hash, enc)) {
+                    self.cache_gen_size.fetch_sub(data.len() as u64, AtomicOrdering::Relaxed);
+                }
+            }
+        }
+    }
+}
@@ -24,7 +24,8 @@ use iron::url::Url as GenericUrl;
 use std::collections::BTreeSet;
 use std::path::{PathBuf, Path};
 use std::fs::{self, Metadata};
-use self::super::HttpHandler;
+use self::super::{HttpHandler, Precondition, write_switch};
+use self::super::super::options::Mount;
 use std::{fmt, mem};
 use time::strptime;
 
@@ -45,14 +46,14 @@ fn default_xml_emitter_config() -> XmlEmitterConfig {
 
 impl HttpHandler {
     pub(super) fn handle_webdav_propfind(&self, req: &mut Request) -> IronResult<Response> {
-        let (req_p, symlink, url_err) = self.parse_requested_path(req);
+        let (req_p, symlink, url_err, mount) = self.parse_requested_path(req);
 
         if url_err {
             return self.handle_invalid_url(req, "<p>Percent-encoding decoded to invalid UTF-8.</p>");
         }
 
         if !req_p.exists() || (symlink && !self.follow_symlinks) ||
-           (symlink && self.follow_symlinks && self.sandbox_symlinks && !is_descendant_of(&req_p, &self.hosted_directory.1)) {
+           (symlink && self.follow_symlinks && mount.sandbox_symlinks && !is_descendant_of(&req_p, &mount.directory.1)) {
             return self.handle_nonexistent(req, req_p);
         }
 
@@ -99,6 +100,7 @@ impl HttpHandler {
                 self.handle_webdav_propfind_write_output(req,
                                                          url,
                                                          &req_p,
+                                                         mount,
                                                          if client_microsoft(&req.headers) {
                                                              WEBDAV_ALLPROP_PROPERTIES_WINDOWS
                                                          } else {
@@ -107,8 +109,8 @@ impl HttpHandler {
                                                          false,
                                                          depth)
             }
-            PropfindVariant::PropName => self.handle_webdav_propfind_write_output(req, url, &req_p, WEBDAV_PROPNAME_PROPERTIES, true, depth),
-            PropfindVariant::Props(props) => self.handle_webdav_propfind_write_output(req, url, &req_p, &[&props[..]], false, depth),
+            PropfindVariant::PropName => self.handle_webdav_propfind_write_output(req, url, &req_p, mount, WEBDAV_PROPNAME_PROPERTIES, true, depth),
+            PropfindVariant::Props(props) => self.handle_webdav_propfind_write_output(req, url, &req_p, mount, &[&props[..]], false, depth),
         };
 
         match resp.expect("Couldn't write PROPFIND XML") {
@@ -119,8 +121,8 @@ impl HttpHandler {
 
     /// Adapted from
     /// https://github.com/tylerwhall/hyperdav-server/blob/415f512ac030478593ad389a3267aeed7441d826/src/lib.rs#L459
-    fn handle_webdav_propfind_write_output<'n, N: BorrowXmlName<'n>>(&self, req: &mut Request, mut url: String, path: &Path, props: &[&'n [N]],
-                                                                     just_names: bool, depth: Depth)
+    fn handle_webdav_propfind_write_output<'n, N: BorrowXmlName<'n>>(&self, req: &mut Request, mut url: String, path: &Path, mount: &Mount,
+                                                                     props: &[&'n [N]], just_names: bool, depth: Depth)
                                                                      -> Result<Result<Vec<u8>, IronResult<Response>>, XmlWError> {
         let mut out = intialise_xml_output()?;
         out.write(namespaces_for_props("D:multistatus", props.iter().flat_map(|pp| pp.iter())))?;
@@ -129,7 +131,8 @@ impl HttpHandler {
         self.handle_propfind_path(&mut out, &url, &path, &meta, props, just_names)?;
 
         if meta.is_dir() {
-            if let Some(ir) = self.handle_webdav_propfind_path_recursive(req, &mut out, &mut url, &path, props, just_names, depth)? {
+            let mount_points = self.child_mounts(req);
+            if let Some(ir) = self.handle_webdav_propfind_path_recursive(req, &mut out, &mut url, &path, mount, &mount_points, props, just_names, depth)? {
                 return Ok(Err(ir));
             }
         }
@@ -139,8 +142,10 @@ impl HttpHandler {
         Ok(Ok(out.into_inner()))
     }
 
+    /// `mount_points` are listed in place of whatever they hide in `root_path`
     fn handle_webdav_propfind_path_recursive<'n, W: Write, N: BorrowXmlName<'n>>(&self, req: &mut Request, out: &mut XmlWriter<W>, root_url: &mut String,
-                                                                                 root_path: &Path, props: &[&'n [N]], just_names: bool, depth: Depth)
+                                                                                 root_path: &Path, mount: &Mount, mount_points: &[(&str, &Mount)],
+                                                                                 props: &[&'n [N]], just_names: bool, depth: Depth)
                                                                                  -> Result<Option<IronResult<Response>>, XmlWError> {
         if !root_url.ends_with('/') {
             root_url.push('/');
@@ -150,6 +155,9 @@ impl HttpHandler {
         let mut links_left = MAX_SYMLINKS;
         if let Some(next_depth) = depth.lower() {
             for f in root_path.read_dir().expect("Failed to read requested directory").map(|p| p.expect("Failed to iterate over requested directory")) {
+                if mount_points.iter().any(|&(name, _)| f.file_name() == name) {
+                    continue;
+                }
                 root_url.truncate(root_url_orig_len);
                 root_url.push_str(&f.file_name().to_string_lossy()[..]);
 
@@ -171,14 +179,24 @@ impl HttpHandler {
                 }
 
                 if !(!path.exists() || (symlink && !self.follow_symlinks) ||
-                     (symlink && self.follow_symlinks && self.sandbox_symlinks && !is_descendant_of(&path, &self.hosted_directory.1))) {
+                     (symlink && self.follow_symlinks && mount.sandbox_symlinks && !is_descendant_of(&path, &mount.directory.1))) {
                     let metadata = path.metadata().expect("Failed to get requested file metadata");
                     self.handle_propfind_path(out, &root_url, &path, &metadata, props, just_names)?;
                     if metadata.is_dir() {
-                        self.handle_webdav_propfind_path_recursive(req, out, root_url, &path, props, just_names, next_depth)?;
+                        self.handle_webdav_propfind_path_recursive(req, out, root_url, &path, mount, &[], props, just_names, next_depth)?;
                     }
                 }
             }
+
+            for &(name, mount_point) in mount_points {
+                root_url.truncate(root_url_orig_len);
+                root_url.push_str(name);
+
+                if let Ok(metadata) = mount_point.directory.1.metadata() {
+                    self.handle_propfind_path(out, &root_url, &mount_point.directory.1, &metadata, props, just_names)?;
+                    self.handle_webdav_propfind_path_recursive(req, out, root_url, &mount_point.directory.1, mount_point, &[], props, just_names, next_depth)?;
+                }
+            }
         }
 
         Ok(None)
@@ -186,18 +204,18 @@ impl HttpHandler {
 
     /// NB: we don't allow modifying any properties, so we 409 Conflict all of them
     pub(super) fn handle_webdav_proppatch(&self, req: &mut Request) -> IronResult<Response> {
-        let (req_p, symlink, url_err) = self.parse_requested_path(req);
+        let (req_p, symlink, url_err, mount) = self.parse_requested_path(req);
 
         if url_err {
             return self.handle_invalid_url(req, "<p>Percent-encoding decoded to invalid UTF-8.</p>");
         }
 
-        if self.writes_temp_dir.is_none() {
-            return self.handle_forbidden_method(req, "-w", "write requests");
+        if !mount.allow_writes {
+            return self.handle_forbidden_method(req, write_switch(mount), "write requests");
         }
 
         if !req_p.exists() || (symlink && !self.follow_symlinks) ||
-           (symlink && self.follow_symlinks && self.sandbox_symlinks && !is_descendant_of(&req_p, &self.hosted_directory.1)) {
+           (symlink && self.follow_symlinks && mount.sandbox_symlinks && !is_descendant_of(&req_p, &mount.directory.1)) {
             return self.handle_nonexistent(req, req_p);
         }
 
@@ -237,7 +255,7 @@ impl HttpHandler {
     }
 
     pub(super) fn handle_webdav_mkcol(&self, req: &mut Request) -> IronResult<Response> {
-        let (req_p, symlink, url_err) = self.parse_requested_path(req);
+        let (req_p, symlink, url_err, mount) = self.parse_requested_path(req);
 
         log!(self.log,
              "{} requested to {red}MKCOL{reset} at {yellow}{}{reset}",
@@ -248,12 +266,12 @@ impl HttpHandler {
             return self.handle_invalid_url(req, "<p>Percent-encoding decoded to invalid UTF-8.</p>");
         }
 
-        if self.writes_temp_dir.is_none() {
-            return self.handle_forbidden_method(req, "-w", "write requests");
+        if !mount.allow_writes {
+            return self.handle_forbidden_method(req, write_switch(mount), "write requests");
         }
 
         if !req_p.parent().map(|pp| pp.exists()).unwrap_or(true) || (symlink && !self.follow_symlinks) ||
-           (symlink && self.follow_symlinks && self.sandbox_symlinks && !is_descendant_of(&req_p, &self.hosted_directory.1)) {
+           (symlink && self.follow_symlinks && mount.sandbox_symlinks && !is_descendant_of(&req_p, &mount.directory.1)) {
             return self.handle_nonexistent_status(req, req_p, status::Conflict);
         }
 
@@ -300,20 +318,23 @@ impl HttpHandler {
     }
 
     fn handle_webdav_copy_move(&self, req: &mut Request, is_move: bool, source_path: Option<&mut (PathBuf, bool)>) -> IronResult<Response> {
-        let (req_p, symlink, url_err) = self.parse_requested_path(req);
+        let (req_p, symlink, url_err, mount) = self.parse_requested_path(req);
 
         if url_err {
             return self.handle_invalid_url(req, "<p>Percent-encoding decoded to invalid UTF-8.</p>");
         }
-        let (dest_p, dest_symlink) = match req.headers.get::<Destination>() {
+        let (dest_p, dest_symlink, dest_mount) = match req.headers.get::<Destination>() {
             Some(dest) => {
-                let (dest_p, dest_symlink, dest_url_err) = self.parse_requested_path_custom_symlink(&dest.0, true);
+                // Stay on the requested virtual host, whose credentials were checked
+                let mut dest_url = dest.0.clone();
+                let _ = dest_url.set_host(req.url.as_ref().host_str());
+                let (dest_p, dest_symlink, dest_url_err, dest_mount) = self.parse_requested_path_custom_symlink(&dest_url, true);
 
                 if dest_url_err {
                     return self.handle_invalid_url(req, "<p>Percent-encoding decoded destination to invalid UTF-8.</p>");
                 }
 
-                (dest_p, dest_symlink)
+                (dest_p, dest_symlink, dest_mount)
             }
             None => return self.handle_invalid_url(req, "<p>Destination URL invalid or nonexistent.</p>"),
         };
@@ -330,24 +351,34 @@ impl HttpHandler {
              dest_p.display(),
              depth);
 
-        if self.writes_temp_dir.is_none() {
-            return self.handle_forbidden_method(req, "-w", "write requests");
+        // COPY only reads the source, so it can come from a read-only mount
+        if !dest_mount.allow_writes {
+            return self.handle_forbidden_method(req, write_switch(dest_mount), "write requests");
+        }
+        if is_move && !mount.allow_writes {
+            return self.handle_forbidden_method(req, write_switch(mount), "write requests");
         }
 
-        if req_p == dest_p {
+        if req_p == dest_p || dest_p == dest_mount.directory.1 || (is_move && req_p == mount.directory.1) {
             return Ok(Response::with(status::Forbidden));
         }
 
         if !req_p.exists() || (symlink && !self.follow_symlinks) ||
-           (symlink && self.follow_symlinks && self.sandbox_symlinks && !is_descendant_of(&req_p, &self.hosted_directory.1)) {
+           (symlink && self.follow_symlinks && mount.sandbox_symlinks && !is_descendant_of(&req_p, &mount.directory.1)) {
             return self.handle_nonexistent(req, req_p);
         }
 
         if !dest_p.parent().map(|pp| pp.exists()).unwrap_or(true) || (dest_symlink && !self.follow_symlinks) ||
-           (dest_symlink && self.follow_symlinks && self.sandbox_symlinks && !is_descendant_of(&dest_p, &self.hosted_directory.1)) {
+           (dest_symlink && self.follow_symlinks && dest_mount.sandbox_symlinks && !is_descendant_of(&dest_p, &dest_mount.directory.1)) {
             return Ok(Response::with(status::Conflict));
         }
 
+        let _replacing = self.replacing(&dest_p);
+        if HttpHandler::evaluate_preconditions_path(req, &dest_p) != Precondition::Passed {
+            log!(self.log, "{} Destination preconditions failed", self.remote_addresses(&req).as_spaces());
+            return self.handle_precondition_failed(req);
+        }
+
         let mut overwritten = false;
         if dest_p.exists() {
             if !overwrite {
@@ -11,23 +11,43 @@
 //! ```
 
 
-use clap::{AppSettings, ErrorKind as ClapErrorKind, Error as ClapError, Arg, App};
+use clap::{AppSettings, ErrorKind as ClapErrorKind, Error as ClapError, ArgMatches, Arg, App};
+use toml::value::{Table as TomlTable, Value as TomlValue};
 use std::collections::btree_map::{BTreeMap, Entry as BTreeMapEntry};
-use self::super::ops::WebDavLevel;
+use self::super::util::{CompressionLevels, PORT_SCAN_LOWEST, PORT_SCAN_HIGHEST, UNIX_SOCKET_PEER, parse_htpasswd};
+use self::super::ops::{WebDavLevel, Permissions};
 use std::ffi::{OsString, OsStr};
 use std::collections::BTreeSet;
 use std::env::{self, temp_dir};
-use std::num::NonZeroU64;
-use std::{cmp, str, fs};
-use std::path::PathBuf;
+use std::num::{NonZeroUsize, NonZeroU64};
+use std::{cmp, fmt, str, fs, iter, thread};
+use std::path::{PathBuf, Path};
 use std::str::FromStr;
 use std::borrow::Cow;
+use toml::Spanned;
 use iron::mime::Mime;
-use std::net::IpAddr;
-use cidr::IpCidr;
+use std::net::{SocketAddr, IpAddr};
+use cidr::{Cidr, IpCidr};
 use blake3;
 
 
+/// Replaces passwords in `--print-config` output
+const REDACTED: &str = "REDACTED";
+
+/// Options setting the same thing or conflicting, so setting one on the command line overrides the others in the config file
+const CONFIG_ALTERNATIVES: &[&[&str]] = &[&["ssl", "gen-ssl"],
+                                          &["auth", "gen-auth"],
+                                          &["webdav", "convenient-webdav"],
+                                          &["listen", "port"],
+                                          &["listen", "address"],
+                                          &["listen", "port-range"],
+                                          &["port", "port-range"]];
+
+/// An htpasswd file, as `(specified, canonicalised)`, and the `(username, password)`s read from it;
+/// the passwords are hashed or in plain text
+pub type Htpasswd = ((String, PathBuf), Vec<(String, String)>);
+
+
 #[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub enum LogLevel {
     /// Write everything
@@ -52,15 +72,124 @@ impl From<u64> for LogLevel {
 }
 
 
+/// A directory hosted under a URL prefix, with its own access flags.
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub struct Mount {
+    /// The URL prefix, without leading or trailing slashes; empty for the hosted directory
+    pub prefix: String,
+    /// The directory to host under `prefix`.
+    pub directory: (String, PathBuf),
+    /// Whether to allow write operations. Default: `--allow-write`
+    pub allow_writes: bool,
+    /// Whether to generate directory listings. Default: not `--no-listings`
+    pub generate_listings: bool,
+    /// Whether to disallow going out of the descendants of `directory` (via symlinks). Default: `--sandbox-symlinks`
+    pub sandbox_symlinks: bool,
+}
+
+
+/// A socket to listen on, from `--listen`.
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub struct Listen {
+    pub address: ListenAddress,
+    /// Whether to serve TLS. Default: whether `--ssl` or `--gen-ssl` are specified, for TCP sockets
+    pub tls: bool,
+}
+
+/// Where to listen.
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub enum ListenAddress {
+    Tcp(SocketAddr),
+    /// A Unix domain socket at the path, and the permissions to give it, if not the umask default
+    Unix(PathBuf, Option<u32>),
+}
+
+impl fmt::Display for ListenAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddress::Tcp(addr) => addr.fmt(f),
+            ListenAddress::Unix(path, _) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Who to trust a `--proxy` or `--proxy-redir` header from.
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ProxySource {
+    /// TCP peers in the network
+    Network(IpCidr),
+    /// `unix`: Unix domain socket peers
+    Unix,
+}
+
+impl ProxySource {
+    /// Whether a request from the peer is from here
+    pub fn contains(&self, peer: &SocketAddr) -> bool {
+        match self {
+            ProxySource::Network(network) => *peer != UNIX_SOCKET_PEER && network.contains(&peer.ip()),
+            ProxySource::Unix => *peer == UNIX_SOCKET_PEER,
+        }
+    }
+}
+
+impl fmt::Display for ProxySource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProxySource::Network(network) => network.fmt(f),
+            ProxySource::Unix => f.write_str("unix"),
+        }
+    }
+}
+
+
+/// A name-based virtual host, served instead of the hosted directory to requests with its name in the `Host` header.
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub struct VHost {
+    /// The directory to host.
+    pub hosted_directory: (String, PathBuf),
+    /// Instead of returning 404, try this file first. Default: `None`
+    pub try_404: Option<PathBuf>,
+    /// Data for per-path authentication, like `Options::path_auth_data`;
+    /// paths without any here or in `htpasswd` use the `Options` ones. Default: none
+    pub path_auth_data: BTreeMap<String, Vec<String>>,
+    /// htpasswd files for per-path authentication, like `Options::htpasswd`. Default: none
+    pub htpasswd: BTreeMap<String, Htpasswd>,
+    /// Per-path permissions, like `Options::path_permissions`; paths without any here use the `Options` ones. Default: none
+    pub path_permissions: BTreeMap<String, BTreeMap<String, Permissions>>,
+    /// Headers to add to every response, after `Options::additional_headers`. Default: none
+    pub additional_headers: Vec<(String, Vec<u8>)>,
+    /// Identity file to present to clients asking for this host with TLS SNI, and its password. Default: `None`
+    pub tls_data: Option<((String, PathBuf), String)>,
+}
+
+
 /// Representation of the application's all configurable values.
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
 pub struct Options {
     /// The directory to host.
     pub hosted_directory: (String, PathBuf),
+    /// Additional directories to host under URL prefixes. Default: none
+    pub mounts: Vec<Mount>,
+    /// Virtual hosts by (lower-case) name; requests for other hosts get the hosted directory. Default: none
+    pub vhosts: BTreeMap<String, VHost>,
     /// The port to host on. Default: first free port from 8000 up
     pub port: Option<u16>,
     /// The address to bind to. Default: 0.0.0.0
     pub bind_address: IpAddr,
+    /// Sockets to listen on instead of `port` on `bind_address`. Default: none
+    pub listen: Vec<Listen>,
+    /// The ports to try, inclusive, if neither `port` nor `listen` are specified. Default: 8000-9999
+    pub port_range: (u16, u16),
+    /// How many seconds to wait on shutdown for requests in progress to finish. Default: 10
+    pub shutdown_timeout: u64,
+    /// User (name or ID) to switch to once listening. Default: `None`
+    pub user: Option<String>,
+    /// Group (name or ID) to switch to once listening. Default: `user`'s
+    pub group: Option<String>,
+    /// Directory to `chroot()` into once listening; the hosted directories and temp directory have to be under it. Default: `None`
+    pub chroot: Option<(String, PathBuf)>,
+    /// Whether to serve as root. Default: false
+    pub allow_root: bool,
     /// Whether to allow symlinks to be requested. Default: true
     pub follow_symlinks: bool,
     /// Whether to disallow going out of the descendants of the hosted directory (via symlinks)
@@ -84,6 +213,18 @@ pub struct Options {
     pub allow_writes: bool,
     /// Whether to encode filesystem files. Default: true
     pub encode_fs: bool,
+    /// Whether to serve `FILE.{br,gz,zst}` in place of `FILE` to clients accepting the encoding. Default: false
+    pub precompressed: bool,
+    /// Compression level for each encoding. Default: gzip:6, deflate:6, br:9, zstd:3
+    pub compression_levels: CompressionLevels,
+    /// Whether to keep encoded filesystem files and their index across runs, instead of deleting them on exit. Default: true
+    pub encoded_persist: bool,
+    /// Encode filesystem files larger than this on the fly instead of ahead of time. Default: `None`
+    pub encode_streaming: Option<u64>,
+    /// Encode filesystem files on this many background threads. Default: available parallelism
+    pub encoder_threads: usize,
+    /// Whether to encode all files in the hosted directory at startup. Default: false
+    pub encode_prewarm: bool,
     /// Consume at most this much space for encoded filesystem files.
     pub encoded_filesystem_limit: Option<u64>,
     /// Consume at most this much memory for encoded generated responses.
@@ -106,34 +247,116 @@ pub struct Options {
     pub tls_data: Option<((String, PathBuf), String)>,
     /// Whether to generate a one-off certificate. Default: false
     pub generate_tls: bool,
-    /// Data for per-path authentication, in the form `username[:password]`, or `None` to explicitly disable
-    pub path_auth_data: BTreeMap<String, Option<String>>,
+    /// Data for per-path authentication, as any amount of accounts in the form `username[:password]`, or none to explicitly disable
+    pub path_auth_data: BTreeMap<String, Vec<String>>,
     /// Paths for which to generate auth data
     pub generate_path_auth: BTreeSet<String>,
-    /// Header names and who we trust them from in `HEADER-NAME:CIDR` format
-    pub proxies: BTreeMap<IpCidr, String>,
-    /// Header names and who we trust them from in `HEADER-NAME:CIDR` format
-    pub proxy_redirs: BTreeMap<IpCidr, String>,
+    /// htpasswd files for per-path authentication in addition to `path_auth_data`. Default: none
+    pub htpasswd: BTreeMap<String, Htpasswd>,
+    /// What each user, or `*` for everyone else, can do under each path;
+    /// paths not under any can be used fully by whoever's authenticated for them. Default: none
+    pub path_permissions: BTreeMap<String, BTreeMap<String, Permissions>>,
+    /// Whether to let GET, HEAD, OPTIONS, and PROPFIND requests through without credentials where they'd be needed. Default: false
+    pub auth_writes_only: bool,
+    /// File to append a JSON line to for every authentication success and failure. Default: `None`
+    pub audit_log: Option<PathBuf>,
+    /// How many invalid credentials in a row to allow from a client before locking it out, or 0 to never. Default: 5
+    pub auth_failures: u32,
+    /// How many seconds to lock clients out for at first, doubling with each further invalid credential, and at most. Default: 1-900
+    pub auth_lockout: (u64, u64),
+    /// Header names and who we trust them from in `HEADER-NAME:CIDR` or `HEADER-NAME:unix` format
+    pub proxies: BTreeMap<ProxySource, String>,
+    /// Header names and who we trust them from in `HEADER-NAME:CIDR` or `HEADER-NAME:unix` format
+    pub proxy_redirs: BTreeMap<ProxySource, String>,
     /// Extension -> MIME type mapping overrides; empty string for no extension
     pub mime_type_overrides: BTreeMap<OsString, Mime>,
     /// Max amount of data per second each request is allowed to return. Default: `None`
     pub request_bandwidth: Option<NonZeroU64>,
     /// Additional headers to add to every response
     pub additional_headers: Vec<(String, Vec<u8>)>,
+    /// Whether to print the effective configuration and exit. Default: false
+    pub print_config: bool,
 }
 
 impl Options {
-    /// Parse `env`-wide command-line arguments into an `Options` instance
+    /// Parse `env`-wide command-line arguments, preceded by the `--config` file, if any, into an `Options` instance
     pub fn parse() -> Options {
-        let matches = App::new("http")
+        Options::parse_from(env::args_os().collect()).unwrap_or_else(|e| e.exit())
+    }
+
+    /// Parse the specified command-line arguments, preceded by the `--config` file, if any, into an `Options` instance,
+    /// returning the error instead of exiting
+    pub fn parse_from(args: Vec<OsString>) -> Result<Options, ClapError> {
+        let cli_matches = Options::app().get_matches_from_safe(&args)?;
+        let matches = match cli_matches.value_of_os("config") {
+            Some(config) => {
+                let config_args = Options::config_args(config, &cli_matches)?;
+                Options::app().get_matches_from_safe(args[..1].iter().cloned().chain(config_args).chain(args[1..].iter().cloned()))?
+            }
+            None => cli_matches,
+        };
+        Options::parse_matches(matches)
+    }
+
+    fn app() -> App<'static, 'static> {
+        App::new("http")
             .version(crate_version!())
             .author(crate_authors!("\n"))
             .about(crate_description!())
             .setting(AppSettings::ColoredHelp)
             .arg(Arg::from_usage("[DIR] 'Directory to host. Default: current working directory'")
                 .validator(|s| Options::filesystem_dir_validator(s, "Directory to host")))
+            .arg(Arg::from_usage("--mount [PREFIX=DIR[,FLAG]...]... 'Also host DIR under PREFIX. FLAGs (rw, ro, listings, no-listings, sandbox, \
+                                  no-sandbox) override -w, -l, and -r for it'")
+                .number_of_values(1)
+                .use_delimiter(false)
+                .validator(|s| Options::mount_parse(&s, false, false, false).map(|_| ())))
+            .arg(Arg::from_usage("--vhost [HOST=DIR]... 'Host DIR instead for requests to HOST'")
+                .number_of_values(1)
+                .use_delimiter(false)
+                .validator(|s| Options::vhost_parse(&s).map(|_| ())))
+            .arg(Arg::from_usage("--vhost-404 [HOST=FALLBACK-FILE]... 'Like --404, for requests to HOST'")
+                .number_of_values(1)
+                .use_delimiter(false)
+                .validator(|s| Options::vhost_split(&s).map(|_| ())))
+            .arg(Arg::from_usage("--vhost-path-auth [HOST/PATH=[USERNAME[:PASSWORD]]]... 'Like --path-auth, for requests to HOST'")
+                .number_of_values(1)
+                .use_delimiter(false)
+                .validator(Options::vhost_path_credentials_validator))
+            .arg(Arg::from_usage("--vhost-htpasswd [HOST/PATH=FILE]... 'Like --htpasswd, for requests to HOST'")
+                .number_of_values(1)
+                .use_delimiter(false)
+                .validator(|s| Options::vhost_htpasswd_parse(&s).map(|_| ())))
+            .arg(Arg::from_usage("--vhost-path-perms [HOST/PATH=USERNAME:PERMS]... 'Like --path-perms, for requests to HOST'")
+                .number_of_values(1)
+                .use_delimiter(false)
+                .validator(|s| Options::parse_vhost_path_permissions(&s).map(|_| ())))
+            .arg(Arg::from_usage("--vhost-header [HOST=NAME: VALUE]... 'Like --header, for requests to HOST'")
+                .number_of_values(1)
+                .use_delimiter(false)
+                .validator(|s| Options::vhost_split(&s).and_then(|(_, h)| Options::header_parse(h)).map(|_| ())))
+            .arg(Arg::from_usage("--vhost-ssl [HOST=TLS_IDENTITY]... 'Present TLS_IDENTITY to clients asking for HOST with SNI. Password in HTTP_SSL_PASS'")
+                .number_of_values(1)
+                .use_delimiter(false)
+                .validator(|s| Options::vhost_split(&s).and_then(|(_, id)| Options::identity_validator(id.to_string()))))
             .arg(Arg::from_usage("-p --port [port] 'Port to use. Default: first free port from 8000 up'").validator(Options::u16_validator))
             .arg(Arg::from_usage("-a --address [address] 'Address to bind to. Default: 0.0.0.0'").validator(Options::ipaddr_validator))
+            .arg(Arg::from_usage("--listen [ADDR:PORT[,FLAG]...]... 'Listen on ADDR:PORT, or the Unix domain socket unix:PATH, instead of -a and -p. \
+                                  FLAGs (tls, plain, mode=OCTAL) override whether to use TLS and set the socket's permissions'")
+                .number_of_values(1)
+                .use_delimiter(false)
+                .conflicts_with_all(&["port", "address", "port-range"])
+                .validator(|s| Options::listen_parse(&s, false).map(|_| ())))
+            .arg(Arg::from_usage("--port-range [FROM-TO] 'Ports to try if -p isn't specified. Default: 8000-9999'")
+                .conflicts_with("port")
+                .validator(|s| Options::port_range_parse(&s).map(|_| ())))
+            .arg(Arg::from_usage("--shutdown-timeout [TIMEOUT] 'On shutdown, wait up to TIMEOUT for requests in progress to finish. Default: 10s'")
+                .validator(|s| Options::age_parse(s.into()).map(|_| ())))
+            .arg(Arg::from_usage("--user [USER] 'Switch to USER (name or ID) and its groups once listening'"))
+            .arg(Arg::from_usage("--group [GROUP] 'Switch to GROUP (name or ID) once listening. Default: USER's'"))
+            .arg(Arg::from_usage("--chroot [ROOT] 'chroot() into ROOT once listening. DIR, --mount and --vhost directories, and -t must be under it'")
+                .validator(|s| Options::filesystem_dir_validator(s, "chroot() directory")))
+            .arg(Arg::from_usage("--allow-root 'Keep serving while running as root, instead of refusing to. Default: false'"))
             .arg(Arg::from_usage("-t --temp-dir [temp] 'Temporary directory. Default: $TEMP'")
                 .validator(|s| Options::filesystem_dir_validator(s, "Temporary directory")))
             .arg(Arg::from_usage("--404 [fallback-file] 'Return this file instead of a 404 for a GET. Default: generated response'"))
@@ -144,6 +367,17 @@ impl Options {
             .arg(Arg::from_usage("-l --no-listings 'Never generate dir listings. Default: false'"))
             .arg(Arg::from_usage("-i --no-indices 'Do not automatically use index files. Default: false'"))
             .arg(Arg::from_usage("-e --no-encode 'Do not encode filesystem files. Default: false'"))
+            .arg(Arg::from_usage("--precompressed 'Serve FILE.{br,gz,zst} in place of FILE if present and at least as new. Default: false'"))
+            .arg(Arg::from_usage("--compression-level [ENCODING:LEVEL]... 'Compress with ENCODING (gzip, deflate, br, zstd) at LEVEL'")
+                .number_of_values(1)
+                .use_delimiter(false)
+                .validator(|s| Options::compression_level_parse(&s).map(|_| ())))
+            .arg(Arg::from_usage("--wipe-encoded 'Delete encoded filesystem files on exit instead of reusing them on the next run. Default: false'"))
+            .arg(Arg::from_usage("--encode-streaming [SIZE] 'Encode files larger than SIZE while sending them instead of ahead of time. Default: never'")
+                .validator(|s| Options::size_parse(s.into()).map(|_| ())))
+            .arg(Arg::from_usage("--encoder-threads [THREADS] 'Encode filesystem files on THREADS background threads. Default: CPU count'")
+                .validator(Options::nonzero_usize_validator))
+            .arg(Arg::from_usage("--encode-prewarm 'Encode all files in the hosted directory at startup. Default: false'"))
             .arg(Arg::from_usage("--encoded-filesystem [FS_LIMIT] 'Consume at most FS_LIMIT space for encoded filesystem files.'")
                 .validator(|s| Options::size_parse(s.into()).map(|_| ())))
             .arg(Arg::from_usage("--encoded-generated [GEN_LIMIT] 'Consume at most GEN_LIMIT memory for encoded generated responses.'")
@@ -159,21 +393,40 @@ impl Options {
             .arg(Arg::from_usage("--ssl [TLS_IDENTITY] 'Data for HTTPS, identity file. Password in HTTP_SSL_PASS env var, otherwise empty'")
                 .validator(Options::identity_validator))
             .arg(Arg::from_usage("--gen-ssl 'Generate a one-off TLS certificate'").conflicts_with("ssl"))
-            .arg(Arg::from_usage("--auth [USERNAME[:PASSWORD]] 'Data for global authentication'").validator(Options::credentials_validator))
+            .arg(Arg::from_usage("--auth [USERNAME[:PASSWORD]] 'Data for global authentication. PASSWORD may be hashed. Default: HTTP_AUTH env var'")
+                .validator(Options::credentials_validator))
             .arg(Arg::from_usage("--gen-auth 'Generate a one-off username:password set for global authentication'").conflicts_with("auth"))
-            .arg(Arg::from_usage("--path-auth [PATH=[USERNAME[:PASSWORD]]]... 'Data for authentication under PATH'")
+            .arg(Arg::from_usage("--path-auth [PATH=[USERNAME[:PASSWORD]]]... 'Data for authentication under PATH. PASSWORD may be hashed'")
                 .number_of_values(1)
                 .use_delimiter(false)
                 .validator(Options::path_credentials_validator))
             .arg(Arg::from_usage("--gen-path-auth [PATH]... 'Generate a one-off username:password set for authentication under PATH'")
                 .number_of_values(1)
                 .use_delimiter(false))
-            .arg(Arg::from_usage("--proxy [HEADER-NAME:CIDR]... 'Treat HEADER-NAME as proxy forwarded-for header when request comes from CIDR'")
+            .arg(Arg::from_usage("--htpasswd [PATH=FILE]... 'Also accept the users in htpasswd FILE (bcrypt, SHA-crypt, Argon2, or plain text) under PATH, \
+                                  or everywhere, without PATH='")
+                .number_of_values(1)
+                .use_delimiter(false)
+                .validator(|s| Options::htpasswd_parse(&s).map(|_| ())))
+            .arg(Arg::from_usage("--path-perms [PATH=USERNAME:PERMS]... 'Only let USERNAME (* for everyone else) do PERMS (comma-separated read, write, delete, \
+                                  list, all, or none) under PATH'")
+                .number_of_values(1)
+                .use_delimiter(false)
+                .validator(|s| Options::parse_path_permissions(&s).map(|_| ())))
+            .arg(Arg::from_usage("--auth-writes-only 'Let anyone GET, HEAD, OPTIONS, and PROPFIND without credentials, only requiring them for changes'"))
+            .arg(Arg::from_usage("--audit-log [FILE] 'Append a JSON line to FILE for every authentication success and failure. Default: none'"))
+            .arg(Arg::from_usage("--auth-failures [COUNT] 'Lock clients out with 429 after COUNT invalid credentials in a row, or 0 to never. Default: 5'")
+                .validator(Options::u32_validator))
+            .arg(Arg::from_usage("--auth-lockout [MIN-MAX] 'Lock clients out for MIN, doubling with each further invalid credential up to MAX. \
+                                  Default: 1s-15m'")
+                .validator(|s| Options::auth_lockout_parse(&s).map(|_| ())))
+            .arg(Arg::from_usage("--proxy [HEADER-NAME:CIDR]... 'Treat HEADER-NAME as proxy forwarded-for header when request comes from CIDR, \
+                                  or over a Unix domain socket if CIDR is unix'")
                 .number_of_values(1)
                 .use_delimiter(false)
                 .validator(|s| Options::proxy_parse(s.into()).map(|_| ())))
             .arg(Arg::from_usage("--proxy-redir [HEADER-NAME:CIDR]... 'Treat HEADER-NAME as proxy X-Original-URL header for redirects when request comes \
-                                  from CIDR'")
+                                  from CIDR, or over a Unix domain socket if CIDR is unix'")
                 .number_of_values(1)
                 .use_delimiter(false)
                 .validator(|s| Options::proxy_parse(s.into()).map(|_| ())))
@@ -187,26 +440,121 @@ impl Options {
                 .number_of_values(1)
                 .use_delimiter(false)
                 .validator(|s| Options::header_parse(&s).map(|_| ())))
-            .get_matches();
+            .arg(Arg::from_usage("--config [FILE] 'Load options from the TOML FILE, overridden by the command line'")
+                .validator_os(Options::config_validator))
+            .arg(Arg::from_usage("--print-config 'Print the effective configuration, with passwords redacted, and exit'"))
+    }
 
+    fn parse_matches(matches: ArgMatches) -> Result<Options, ClapError> {
         let dir = matches.value_of("DIR").unwrap_or(".");
         let dir_pb = fs::canonicalize(dir).unwrap();
         let follow_symlinks = !matches.is_present("no-follow-symlinks");
 
         let mut path_auth_data = BTreeMap::new();
-        if let Some(root_auth) = matches.value_of("auth").map(Options::normalise_credentials) {
-            path_auth_data.insert("".to_string(), Some(root_auth));
+        let root_auth = match matches.value_of("auth") {
+            Some(root_auth) => Some(root_auth.to_string()),
+            None if !matches.is_present("gen-auth") => env::var("HTTP_AUTH").ok(),
+            None => None,
+        };
+        if let Some(root_auth) = root_auth {
+            Options::credentials_validator(root_auth.clone()).map_err(Options::config_error)?;
+            path_auth_data.insert("".to_string(), vec![Options::normalise_credentials(&root_auth)]);
+        }
+
+        let mut htpasswd = BTreeMap::new();
+        for (path, file) in matches.values_of("htpasswd").unwrap_or_default().map(|s| Options::htpasswd_parse(s).unwrap()) {
+            match htpasswd.entry(path) {
+                BTreeMapEntry::Occupied(oe) => return Err(Options::path_credentials_dupe(oe.key())),
+                BTreeMapEntry::Vacant(ve) => ve.insert(file),
+            };
+        }
+
+        let mut path_permissions = BTreeMap::new();
+        for (path, user, perms) in matches.values_of("path-perms").unwrap_or_default().map(|s| Options::parse_path_permissions(s).unwrap()) {
+            Options::path_permissions_add(&mut path_permissions, path, user, perms).map_err(|(path, user)| Options::path_permissions_dupe(&format!("/{}", path), &user))?;
         }
 
         if let Some(path_auth) = matches.values_of("path-auth") {
             for (path, auth) in path_auth.map(Options::decode_path_credentials) {
-                match path_auth_data.entry(path) {
-                    BTreeMapEntry::Occupied(oe) => Options::path_credentials_dupe(oe.key()),
-                    BTreeMapEntry::Vacant(ve) => ve.insert(auth.map(Options::normalise_credentials)),
-                };
+                Options::path_credentials_add(&mut path_auth_data, path, auth).map_err(|path| Options::path_credentials_dupe(&path))?;
             }
         }
 
+        let allow_writes = matches.is_present("allow-write");
+        let generate_listings = !matches.is_present("no-listings");
+        let sandbox_symlinks = follow_symlinks && matches.is_present("sandbox-symlinks");
+        let mut mounts = vec![];
+        for mount in matches.values_of("mount").unwrap_or_default() {
+            let mut mount = Options::mount_parse(mount, allow_writes, generate_listings, sandbox_symlinks).unwrap();
+            mount.sandbox_symlinks &= follow_symlinks;
+            if mounts.iter().any(|m: &Mount| m.prefix == mount.prefix) {
+                return Err(Options::mount_dupe(&mount.prefix));
+            }
+            mounts.push(mount);
+        }
+
+        let mut vhosts = BTreeMap::new();
+        for (host, dir) in matches.values_of("vhost").unwrap_or_default().map(|s| Options::vhost_parse(s).unwrap()) {
+            match vhosts.entry(host) {
+                BTreeMapEntry::Occupied(oe) => return Err(Options::vhost_error(format!("Virtual host \"{}\" already present", oe.key()))),
+                BTreeMapEntry::Vacant(ve) => {
+                    ve.insert(VHost {
+                        hosted_directory: (dir.to_string(), fs::canonicalize(dir).unwrap()),
+                        try_404: None,
+                        path_auth_data: BTreeMap::new(),
+                        htpasswd: BTreeMap::new(),
+                        path_permissions: BTreeMap::new(),
+                        additional_headers: vec![],
+                        tls_data: None,
+                    })
+                }
+            };
+        }
+        for (host, file) in matches.values_of("vhost-404").unwrap_or_default().map(|s| Options::vhost_split(s).unwrap()) {
+            Options::vhost_get(&mut vhosts, &host, "--vhost-404")?.try_404 = Some(PathBuf::from(file));
+        }
+        for s in matches.values_of("vhost-path-auth").unwrap_or_default() {
+            let (host, (path, auth)) = Options::parse_vhost_path_credentials(s).unwrap();
+            Options::path_credentials_add(&mut Options::vhost_get(&mut vhosts, &host, "--vhost-path-auth")?.path_auth_data, path, auth)
+                .map_err(|path| Options::vhost_error(format!("Credentials for path \"{}/{}\" already present", host, path)))?;
+        }
+        for (host, (path, file)) in matches.values_of("vhost-htpasswd").unwrap_or_default().map(|s| Options::vhost_htpasswd_parse(s).unwrap()) {
+            match Options::vhost_get(&mut vhosts, &host, "--vhost-htpasswd")?.htpasswd.entry(path) {
+                BTreeMapEntry::Occupied(oe) => {
+                    return Err(Options::vhost_error(format!("htpasswd file for path \"{}/{}\" already present", host, oe.key())))
+                }
+                BTreeMapEntry::Vacant(ve) => ve.insert(file),
+            };
+        }
+        for (host, path, user, perms) in matches.values_of("vhost-path-perms")
+            .unwrap_or_default()
+            .map(|s| Options::parse_vhost_path_permissions(s).unwrap()) {
+            Options::path_permissions_add(&mut Options::vhost_get(&mut vhosts, &host, "--vhost-path-perms")?.path_permissions, path, user, perms)
+                .map_err(|(path, user)| Options::path_permissions_dupe(&format!("{}/{}", host, path), &user))?;
+        }
+        for (host, header) in matches.values_of("vhost-header").unwrap_or_default().map(|s| Options::vhost_split(s).unwrap()) {
+            Options::vhost_get(&mut vhosts, &host, "--vhost-header")?.additional_headers.push(Options::header_parse(header).unwrap());
+        }
+        for (host, id) in matches.values_of("vhost-ssl").unwrap_or_default().map(|s| Options::vhost_split(s).unwrap()) {
+            Options::vhost_get(&mut vhosts, &host, "--vhost-ssl")?.tls_data =
+                Some(((id.to_string(), fs::canonicalize(id).unwrap()), env::var("HTTP_SSL_PASS").unwrap_or_default()));
+        }
+        if vhosts.values().any(|v| v.tls_data.is_some()) && !matches.is_present("ssl") && !matches.is_present("gen-ssl") {
+            return Err(Options::vhost_error("--vhost-ssl needs a default certificate from --ssl or --gen-ssl".to_string()));
+        }
+
+        let tls = matches.is_present("ssl") || matches.is_present("gen-ssl");
+        let mut listen = vec![];
+        for l in matches.values_of("listen").unwrap_or_default().map(|s| Options::listen_parse(s, tls).unwrap()) {
+            if l.tls && !tls {
+                return Err(Options::listen_error(format!("--listen {},tls needs a certificate from --ssl or --gen-ssl", l.address)));
+            }
+            if listen.iter().any(|ol: &Listen| ol.address.to_string() == l.address.to_string()) {
+                return Err(Options::listen_error(format!("Already listening on {}", l.address)));
+            }
+            listen.push(l);
+        }
+
         let mut generate_path_auth = BTreeSet::new();
         if matches.is_present("gen-auth") {
             generate_path_auth.insert("".to_string());
@@ -215,21 +563,30 @@ impl Options {
         if let Some(gen_path_auth) = matches.values_of("gen-path-auth") {
             for path in gen_path_auth.map(Options::normalise_path) {
                 if path_auth_data.contains_key(&path) {
-                    Options::path_credentials_dupe(&path);
+                    return Err(Options::path_credentials_dupe(&path));
                 }
 
                 if let Some(path) = generate_path_auth.replace(path) {
-                    Options::path_credentials_dupe(&path);
+                    return Err(Options::path_credentials_dupe(&path));
                 }
             }
         }
 
-        Options {
+        Ok(Options {
             hosted_directory: (dir.to_string(), dir_pb.clone()),
+            mounts: mounts,
+            vhosts: vhosts,
             port: matches.value_of("port").map(u16::from_str).map(Result::unwrap),
             bind_address: matches.value_of("address").map(IpAddr::from_str).map(Result::unwrap).unwrap_or_else(|| "0.0.0.0".parse().unwrap()),
+            listen: listen,
+            port_range: matches.value_of("port-range").map(Options::port_range_parse).map(Result::unwrap).unwrap_or((PORT_SCAN_LOWEST, PORT_SCAN_HIGHEST)),
+            shutdown_timeout: matches.value_of("shutdown-timeout").map(|s| Options::age_parse(s.into()).unwrap()).unwrap_or(10),
+            user: matches.value_of("user").map(String::from),
+            group: matches.value_of("group").map(String::from),
+            chroot: matches.value_of("chroot").map(|s| (s.to_string(), fs::canonicalize(s).unwrap())),
+            allow_root: matches.is_present("allow-root"),
             follow_symlinks: follow_symlinks,
-            sandbox_symlinks: follow_symlinks && matches.is_present("sandbox-symlinks"),
+            sandbox_symlinks: sandbox_symlinks,
             temp_directory: {
                 let (temp_s, temp_pb) = if let Some(tmpdir) = matches.value_of("temp-dir") {
                     (tmpdir.to_string(), fs::canonicalize(tmpdir).unwrap())
@@ -253,12 +610,26 @@ impl Options {
                          suffix),
                  temp_pb.join(suffix))
             },
-            generate_listings: !matches.is_present("no-listings"),
+            generate_listings: generate_listings,
             check_indices: !matches.is_present("no-indices"),
             strip_extensions: matches.is_present("strip-extensions"),
             try_404: matches.value_of("404").map(PathBuf::from),
-            allow_writes: matches.is_present("allow-write"),
+            allow_writes: allow_writes,
             encode_fs: !matches.is_present("no-encode"),
+            precompressed: matches.is_present("precompressed"),
+            compression_levels: matches.values_of("compression-level").unwrap_or_default().map(Options::compression_level_parse).map(Result::unwrap).fold(
+                CompressionLevels::default(),
+                |mut levels, (enc, level)| {
+                    levels.set(enc, level).unwrap();
+                    levels
+                }),
+            encoded_persist: !matches.is_present("wipe-encoded"),
+            encode_streaming: matches.value_of("encode-streaming").and_then(|s| Options::size_parse(s.into()).ok()),
+            encoder_threads: matches.value_of("encoder-threads")
+                .map(usize::from_str)
+                .map(Result::unwrap)
+                .unwrap_or_else(|| thread::available_parallelism().map(NonZeroUsize::get).unwrap_or(1)),
+            encode_prewarm: matches.is_present("encode-prewarm"),
             encoded_filesystem_limit: matches.value_of("encoded-filesystem").and_then(|s| Options::size_parse(s.into()).ok()),
             encoded_generated_limit: matches.value_of("encoded-generated").and_then(|s| Options::size_parse(s.into()).ok()),
             encoded_prune: matches.value_of("encoded-prune").and_then(|s| Options::age_parse(s.into()).ok()),
@@ -279,6 +650,12 @@ impl Options {
             generate_tls: matches.is_present("gen-ssl"),
             path_auth_data: path_auth_data,
             generate_path_auth: generate_path_auth,
+            htpasswd: htpasswd,
+            path_permissions: path_permissions,
+            auth_writes_only: matches.is_present("auth-writes-only"),
+            audit_log: matches.value_of("audit-log").map(PathBuf::from),
+            auth_failures: matches.value_of("auth-failures").map(|s| s.parse().unwrap()).unwrap_or(5),
+            auth_lockout: matches.value_of("auth-lockout").map(Options::auth_lockout_parse).map(Result::unwrap).unwrap_or((1, 15 * 60)),
             proxies: matches.values_of("proxy").unwrap_or_default().map(Cow::from).map(Options::proxy_parse).map(Result::unwrap).collect(),
             proxy_redirs: matches.values_of("proxy-redir").unwrap_or_default().map(Cow::from).map(Options::proxy_parse).map(Result::unwrap).collect(),
             mime_type_overrides: matches.values_of_os("mime-type")
@@ -293,7 +670,365 @@ impl Options {
                 .map(Options::header_parse)
                 .map(Result::unwrap)
                 .collect(),
+            print_config: matches.is_present("print-config"),
+        })
+    }
+
+    /// Convert the `--config` file into arguments, leaving out ones overridden by the command line.
+    ///
+    /// Each key is validated on its own, with an error pointing at its line if it's invalid.
+    fn config_args(path: &OsStr, cli_matches: &ArgMatches) -> Result<Vec<OsString>, ClapError> {
+        let path = path.to_string_lossy();
+        let config = fs::read_to_string(&*path).map_err(|e| Options::config_error(format!("Failed to read config file {}: {}", path, e)))?;
+        let table: BTreeMap<Spanned<String>, Spanned<TomlValue>> =
+            toml::from_str(&config).map_err(|e| Options::config_error(format!("Config file {}: {}", path, e)))?;
+
+        let mut args = vec![];
+        for (key, value) in table {
+            let line = config[..key.start()].matches('\n').count() + 1;
+            let key = key.into_inner();
+            let key_args = Options::config_key_args(&key, value.get_ref())
+                .and_then(|key_args| Options::config_check(&key_args).map(|_| key_args))
+                .map_err(|message| Options::config_error(format!("Config file {}:{}: {}: {}", path, line, key, message)))?;
+
+            let overridden = if key == "dir" {
+                cli_matches.is_present("DIR")
+            } else {
+                cli_matches.is_present(&key) ||
+                CONFIG_ALTERNATIVES.iter().filter(|alts| alts.contains(&&key[..])).flat_map(|alts| alts.iter()).any(|k| cli_matches.is_present(k))
+            };
+            if !overridden {
+                args.extend(key_args);
+            }
+        }
+        Ok(args)
+    }
+
+    /// Translate a config file `key = value` into the equivalent arguments.
+    ///
+    /// Strings and integers are passed as `--key=value`, or, for integers for flags, as `--key` that many times;
+    /// booleans as `--key` if true; arrays as one of the above for each element.
+    fn config_key_args(key: &str, value: &TomlValue) -> Result<Vec<OsString>, String> {
+        match (key, value) {
+            ("config" | "print-config", _) => Err("only allowed on the command line".to_string()),
+            ("dir", TomlValue::String(dir)) => Ok(vec![dir.into()]),
+            ("dir", _) => Err("not a string".to_string()),
+            (_, TomlValue::String(s)) => Ok(vec![format!("--{}={}", key, s).into()]),
+            (_, &TomlValue::Integer(i)) => {
+                let flag = format!("--{}", key);
+                if i >= 0 && Options::config_check(&[flag.clone().into()]).is_ok() {
+                    Ok(vec![flag.into(); i as usize])
+                } else {
+                    Ok(vec![format!("{}={}", flag, i).into()])
+                }
+            }
+            (_, &TomlValue::Boolean(b)) => {
+                let flag = format!("--{}", key);
+                Options::config_check(&[flag.clone().into()])?;
+                Ok(if b { vec![flag.into()] } else { vec![] })
+            }
+            (_, TomlValue::Array(values)) => {
+                let mut args = vec![];
+                for value in values {
+                    match value {
+                        TomlValue::Array(_) => return Err("nested arrays not supported".to_string()),
+                        value => args.extend(Options::config_key_args(key, value)?),
+                    }
+                }
+                Ok(args)
+            }
+            (_, value) => Err(format!("{} values not supported", value.type_str())),
+        }
+    }
+
+    /// Parse just these arguments, returning the error message if they're invalid
+    fn config_check(args: &[OsString]) -> Result<(), String> {
+        match Options::app().setting(AppSettings::ColorNever).get_matches_from_safe(iter::once(OsString::from("http")).chain(args.iter().cloned())) {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                let message = err.message.lines().next().unwrap_or_default();
+                Err(message.strip_prefix("error: ").unwrap_or(message).to_string())
+            }
+        }
+    }
+
+    fn config_validator(s: &OsStr) -> Result<(), OsString> {
+        match fs::metadata(s) {
+            Ok(m) if !m.is_dir() => Ok(()),
+            Ok(_) => Err(format!("Config file \"{}\" not actually a file", s.to_string_lossy()).into()),
+            Err(_) => Err(format!("Config file \"{}\" not found", s.to_string_lossy()).into()),
+        }
+    }
+
+    fn config_error(message: String) -> ClapError {
+        ClapError {
+            message: message,
+            kind: ClapErrorKind::InvalidValue,
+            info: None,
+        }
+    }
+
+    /// A copy with the paths used while serving relative to the `chroot` directory, if any, as if it was already `chroot()`ed into.
+    ///
+    /// The hosted directories, fallback files, and temp directory have to be under it;
+    /// Unix domain sockets outside it are left out, since they can't be cleaned up from in there.
+    pub fn chrooted(&self) -> Result<Options, String> {
+        let mut new = self.clone();
+        let root = match self.chroot {
+            Some((_, ref root)) => root,
+            None => return Ok(new),
+        };
+        let under = |path: &Path| {
+            let path = env::current_dir().map(|cwd| cwd.join(path)).unwrap_or_else(|_| path.to_path_buf());
+            path.strip_prefix(root)
+                .map(|path| Path::new("/").join(path))
+                .map_err(|_| format!("\"{}\" not under \"{}\"", path.display(), root.display()))
+        };
+
+        new.hosted_directory.1 = under(&self.hosted_directory.1)?;
+        new.temp_directory.1 = under(&self.temp_directory.1)?;
+        if let Some(try_404) = new.try_404.as_mut() {
+            *try_404 = under(try_404)?;
+        }
+        for mount in &mut new.mounts {
+            mount.directory.1 = under(&mount.directory.1)?;
+        }
+        for vhost in new.vhosts.values_mut() {
+            vhost.hosted_directory.1 = under(&vhost.hosted_directory.1)?;
+            if let Some(try_404) = vhost.try_404.as_mut() {
+                *try_404 = under(try_404)?;
+            }
+        }
+        new.listen.retain_mut(|l| match l.address {
+            ListenAddress::Unix(ref mut path, _) => under(path).map(|p| *path = p).is_ok(),
+            ListenAddress::Tcp(_) => true,
+        });
+        Ok(new)
+    }
+
+    /// Names of options that differ between `self` and `new`, but only take effect after a restart
+    pub fn restart_needed(&self, new: &Options) -> Vec<&'static str> {
+        let mut changed = vec![];
+        macro_rules! check {
+            ($($field:ident => $name:expr,)*) => {
+                $(
+                    if self.$field != new.$field {
+                        changed.push($name);
+                    }
+                )*
+            }
+        }
+        check! {
+            hosted_directory => "DIR",
+            mounts => "--mount",
+            port => "--port",
+            bind_address => "--address",
+            listen => "--listen",
+            port_range => "--port-range",
+            shutdown_timeout => "--shutdown-timeout",
+            user => "--user",
+            group => "--group",
+            chroot => "--chroot",
+            allow_root => "--allow-root",
+            follow_symlinks => "--no-follow-symlinks",
+            sandbox_symlinks => "--sandbox-symlinks",
+            temp_directory => "--temp-dir",
+            generate_listings => "--no-listings",
+            check_indices => "--no-indices",
+            strip_extensions => "--strip-extensions",
+            try_404 => "--404",
+            allow_writes => "--allow-write",
+            encode_fs => "--no-encode",
+            precompressed => "--precompressed",
+            compression_levels => "--compression-level",
+            encoded_persist => "--wipe-encoded",
+            encode_streaming => "--encode-streaming",
+            encoder_threads => "--encoder-threads",
+            encode_prewarm => "--encode-prewarm",
+            encoded_filesystem_limit => "--encoded-filesystem",
+            encoded_generated_limit => "--encoded-generated",
+            encoded_prune => "--encoded-prune",
+            loglevel => "--quiet",
+            log_time => "--quiet-time",
+            log_colour => "--no-colour",
+            webdav => "--webdav",
+            generate_tls => "--gen-ssl",
+            audit_log => "--audit-log",
+            auth_failures => "--auth-failures",
+            auth_lockout => "--auth-lockout",
+        }
+        if !self.generate_tls && self.tls_data != new.tls_data {
+            changed.push("--ssl");
+        }
+        // Credentials and headers are live
+        if !self.vhosts.keys().eq(new.vhosts.keys()) ||
+           self.vhosts.values().zip(new.vhosts.values()).any(|(v, nv)| v.hosted_directory != nv.hosted_directory || v.try_404 != nv.try_404) {
+            changed.push("--vhost");
+        }
+        if self.vhosts.values().map(|v| &v.tls_data).ne(new.vhosts.values().map(|v| &v.tls_data)) {
+            changed.push("--vhost-ssl");
         }
+        changed
+    }
+
+    /// Format the effective configuration as a `--config` file, with passwords redacted
+    pub fn config_toml(&self) -> String {
+        fn redact(creds: &str) -> String {
+            match creds.split_once(':') {
+                Some((user, _)) => format!("{}:{}", user, REDACTED),
+                None => creds.to_string(),
+            }
+        }
+        fn strings<I: IntoIterator<Item = String>>(i: I) -> TomlValue {
+            TomlValue::Array(i.into_iter().map(TomlValue::String).collect())
+        }
+
+        let mut cfg = TomlTable::new();
+        cfg.insert("dir".to_string(), TomlValue::String(self.hosted_directory.0.clone()));
+        cfg.insert("mount".to_string(),
+                   strings(self.mounts.iter().map(|m| {
+                       format!("/{}={},{},{},{}",
+                               m.prefix,
+                               m.directory.0,
+                               if m.allow_writes { "rw" } else { "ro" },
+                               if m.generate_listings { "listings" } else { "no-listings" },
+                               if m.sandbox_symlinks { "sandbox" } else { "no-sandbox" })
+                   })));
+        if let Some(port) = self.port {
+            cfg.insert("port".to_string(), TomlValue::Integer(port as i64));
+        }
+        if self.listen.is_empty() {
+            cfg.insert("address".to_string(), TomlValue::String(self.bind_address.to_string()));
+            if self.port.is_none() {
+                cfg.insert("port-range".to_string(), TomlValue::String(format!("{}-{}", self.port_range.0, self.port_range.1)));
+            }
+        }
+        cfg.insert("listen".to_string(),
+                   strings(self.listen.iter().map(|l| match l.address {
+                       ListenAddress::Unix(_, Some(mode)) => format!("{},mode={:o}", l.address, mode),
+                       ListenAddress::Unix(_, None) => l.address.to_string(),
+                       ListenAddress::Tcp(_) => format!("{},{}", l.address, if l.tls { "tls" } else { "plain" }),
+                   })));
+        if let Some(temp_dir) = self.temp_directory.1.parent() {
+            cfg.insert("temp-dir".to_string(), TomlValue::String(temp_dir.display().to_string()));
+        }
+        for &(key, val) in &[("user", &self.user), ("group", &self.group)] {
+            if let Some(val) = val.as_ref() {
+                cfg.insert(key.to_string(), TomlValue::String(val.clone()));
+            }
+        }
+        if let Some((ref root, _)) = self.chroot {
+            cfg.insert("chroot".to_string(), TomlValue::String(root.clone()));
+        }
+        if let Some(try_404) = self.try_404.as_ref() {
+            cfg.insert("404".to_string(), TomlValue::String(try_404.display().to_string()));
+        }
+        if let Some(audit_log) = self.audit_log.as_ref() {
+            cfg.insert("audit-log".to_string(), TomlValue::String(audit_log.display().to_string()));
+        }
+        cfg.insert("auth-failures".to_string(), TomlValue::Integer(self.auth_failures as i64));
+        cfg.insert("auth-lockout".to_string(), TomlValue::String(format!("{}s-{}s", self.auth_lockout.0, self.auth_lockout.1)));
+        for &(key, val) in &[("no-follow-symlinks", !self.follow_symlinks),
+                             ("sandbox-symlinks", self.sandbox_symlinks),
+                             ("allow-root", self.allow_root),
+                             ("allow-write", self.allow_writes),
+                             ("no-listings", !self.generate_listings),
+                             ("no-indices", !self.check_indices),
+                             ("no-encode", !self.encode_fs),
+                             ("precompressed", self.precompressed),
+                             ("wipe-encoded", !self.encoded_persist),
+                             ("encode-prewarm", self.encode_prewarm),
+                             ("strip-extensions", self.strip_extensions),
+                             ("quiet-time", !self.log_time),
+                             ("no-colour", !self.log_colour),
+                             ("webdav", self.webdav == WebDavLevel::All),
+                             ("convenient-webdav", self.webdav == WebDavLevel::MkColMoveOnly),
+                             ("gen-ssl", self.generate_tls),
+                             ("gen-auth", self.generate_path_auth.contains("")),
+                             ("auth-writes-only", self.auth_writes_only)] {
+            cfg.insert(key.to_string(), TomlValue::Boolean(val));
+        }
+        cfg.insert("compression-level".to_string(),
+                   strings(vec![format!("gzip:{}", self.compression_levels.gzip),
+                                format!("deflate:{}", self.compression_levels.deflate),
+                                format!("br:{}", self.compression_levels.brotli),
+                                format!("zstd:{}", self.compression_levels.zstd)]));
+        for &(key, val) in &[("encode-streaming", self.encode_streaming),
+                             ("encoded-filesystem", self.encoded_filesystem_limit),
+                             ("encoded-generated", self.encoded_generated_limit),
+                             ("encoded-prune", self.encoded_prune),
+                             ("request-bandwidth", self.request_bandwidth.map(NonZeroU64::get))] {
+            if let Some(val) = val {
+                cfg.insert(key.to_string(), TomlValue::Integer(val as i64));
+            }
+        }
+        cfg.insert("encoder-threads".to_string(), TomlValue::Integer(self.encoder_threads as i64));
+        cfg.insert("shutdown-timeout".to_string(), TomlValue::Integer(self.shutdown_timeout as i64));
+        cfg.insert("quiet".to_string(), TomlValue::Integer(self.loglevel as i64));
+        if let Some(((ref id, _), _)) = self.tls_data {
+            cfg.insert("ssl".to_string(), TomlValue::String(id.clone()));
+        }
+        if let Some(auth) = self.path_auth_data.get("").and_then(|auths| auths.first()) {
+            cfg.insert("auth".to_string(), TomlValue::String(redact(auth)));
+        }
+        cfg.insert("path-auth".to_string(),
+                   strings(self.path_auth_data.iter().flat_map(|(path, auths)| if auths.is_empty() {
+                       vec![format!("{}=", path)]
+                   } else {
+                       // The first global account is --auth
+                       auths.iter().skip(path.is_empty() as usize).map(|a| format!("{}={}", path, redact(a))).collect()
+                   })));
+        cfg.insert("gen-path-auth".to_string(), strings(self.generate_path_auth.iter().filter(|p| !p.is_empty()).cloned()));
+        cfg.insert("htpasswd".to_string(),
+                   strings(self.htpasswd.iter().map(|(path, ((file, _), _))| if path.is_empty() {
+                       file.clone()
+                   } else {
+                       format!("{}={}", path, file)
+                   })));
+        cfg.insert("path-perms".to_string(),
+                   strings(self.path_permissions
+                       .iter()
+                       .flat_map(|(path, users)| users.iter().map(move |(user, perms)| format!("{}={}:{}", path, user, perms)))));
+        cfg.insert("proxy".to_string(), strings(self.proxies.iter().map(|(source, header)| format!("{}:{}", header, source))));
+        cfg.insert("proxy-redir".to_string(), strings(self.proxy_redirs.iter().map(|(source, header)| format!("{}:{}", header, source))));
+        cfg.insert("mime-type".to_string(),
+                   strings(self.mime_type_overrides.iter().map(|(ext, mt)| format!("{}:{}", ext.to_string_lossy(), mt))));
+        cfg.insert("header".to_string(),
+                   strings(self.additional_headers.iter().map(|(name, val)| format!("{}: {}", name, String::from_utf8_lossy(val)))));
+        cfg.insert("vhost".to_string(),
+                   strings(self.vhosts.iter().map(|(host, v)| format!("{}={}", host, v.hosted_directory.0))));
+        cfg.insert("vhost-404".to_string(),
+                   strings(self.vhosts.iter().flat_map(|(host, v)| v.try_404.as_ref().map(|f| format!("{}={}", host, f.display())))));
+        cfg.insert("vhost-path-auth".to_string(),
+                   strings(self.vhosts.iter().flat_map(|(host, v)| {
+                       v.path_auth_data.iter().flat_map(move |(path, auths)| {
+                           let path = format!("{}{}{}", host, if path.is_empty() { "" } else { "/" }, path);
+                           if auths.is_empty() {
+                               vec![format!("{}=", path)]
+                           } else {
+                               auths.iter().map(|a| format!("{}={}", path, redact(a))).collect()
+                           }
+                       })
+                   })));
+        cfg.insert("vhost-htpasswd".to_string(),
+                   strings(self.vhosts.iter().flat_map(|(host, v)| {
+                       v.htpasswd.iter().map(move |(path, ((file, _), _))| format!("{}{}{}={}", host, if path.is_empty() { "" } else { "/" }, path, file))
+                   })));
+        cfg.insert("vhost-path-perms".to_string(),
+                   strings(self.vhosts.iter().flat_map(|(host, v)| {
+                       v.path_permissions.iter().flat_map(move |(path, users)| {
+                           users.iter()
+                               .map(move |(user, perms)| format!("{}{}{}={}:{}", host, if path.is_empty() { "" } else { "/" }, path, user, perms))
+                       })
+                   })));
+        cfg.insert("vhost-header".to_string(),
+                   strings(self.vhosts.iter().flat_map(|(host, v)| {
+                       v.additional_headers.iter().map(move |(name, val)| format!("{}={}: {}", host, name, String::from_utf8_lossy(val)))
+                   })));
+        cfg.insert("vhost-ssl".to_string(),
+                   strings(self.vhosts.iter().flat_map(|(host, v)| v.tls_data.as_ref().map(|((id, _), _)| format!("{}={}", host, id)))));
+
+        toml::to_string(&TomlValue::Table(cfg)).expect("Failed to serialise config")
     }
 
     fn filesystem_dir_validator(s: String, prefix: &str) -> Result<(), String> {
@@ -352,13 +1087,248 @@ impl Options {
               }))
     }
 
-    fn path_credentials_dupe(path: &str) -> ! {
+    /// Parse `[PATH=]FILE`, reading the htpasswd `FILE`
+    fn htpasswd_parse(s: &str) -> Result<(String, Htpasswd), String> {
+        let (path, file) = s.split_once('=').unwrap_or(("", s));
+        Ok((Options::normalise_path(path), Options::htpasswd_read(file)?))
+    }
+
+    /// Parse `HOST[/PATH]=FILE` into the lower-cased `HOST`, and `PATH` and the htpasswd `FILE` like `htpasswd_parse()`
+    fn vhost_htpasswd_parse(s: &str) -> Result<(String, (String, Htpasswd)), String> {
+        let (path, file) = s.find(['/', '='])
+            .filter(|&host_end| host_end != 0)
+            .and_then(|host_end| s[host_end..].split_once('='))
+            .ok_or_else(|| format!("{} not in HOST[/PATH]=FILE format", s))?;
+        Ok((s[..s.len() - path.len() - 1 - file.len()].to_lowercase(), (Options::normalise_path(path), Options::htpasswd_read(file)?)))
+    }
+
+    fn htpasswd_read(file: &str) -> Result<Htpasswd, String> {
+        let path = fs::canonicalize(file).map_err(|_| format!("htpasswd file \"{}\" not found", file))?;
+        let accounts = fs::read_to_string(&path)
+            .map_err(|err| err.to_string())
+            .and_then(|data| parse_htpasswd(&data))
+            .map_err(|err| format!("htpasswd file \"{}\": {}", file, err))?;
+        Ok(((file.to_string(), path), accounts))
+    }
+
+    /// Add `auth` to the accounts under `path`, or explicitly disable authentication under it, which can't be combined with any;
+    /// returns the conflicting path otherwise
+    fn path_credentials_add(path_auth_data: &mut BTreeMap<String, Vec<String>>, path: String, auth: Option<&str>) -> Result<(), String> {
+        match (path_auth_data.entry(path), auth) {
+            (BTreeMapEntry::Vacant(ve), auth) => {
+                ve.insert(auth.map(Options::normalise_credentials).into_iter().collect());
+            }
+            (BTreeMapEntry::Occupied(mut oe), Some(auth)) if !oe.get().is_empty() => oe.get_mut().push(Options::normalise_credentials(auth)),
+            (BTreeMapEntry::Occupied(oe), _) => return Err(oe.key().clone()),
+        }
+        Ok(())
+    }
+
+    /// Parse `PATH=USERNAME:PERMS`
+    fn parse_path_permissions(s: &str) -> Result<(String, String, Permissions), String> {
+        let (path, user, perms) = s.split_once('=')
+            .and_then(|(path, rest)| rest.rsplit_once(':').map(|(user, perms)| (path, user, perms)))
+            .filter(|&(_, user, _)| !user.is_empty())
+            .ok_or_else(|| format!("{} not in PATH=USERNAME:PERMS format", s))?;
+        Ok((Options::normalise_path(path), user.to_string(), perms.parse()?))
+    }
+
+    /// Parse `HOST[/PATH]=USERNAME:PERMS` into the lower-cased `HOST`, and the rest like `parse_path_permissions()`
+    fn parse_vhost_path_permissions(s: &str) -> Result<(String, String, String, Permissions), String> {
+        match s.find(['/', '=']) {
+            Some(host_end) if host_end != 0 => {
+                let (path, user, perms) = Options::parse_path_permissions(&s[host_end..])?;
+                Ok((s[..host_end].to_lowercase(), path, user, perms))
+            }
+            _ => Err(format!("{} not in HOST[/PATH]=USERNAME:PERMS format", s)),
+        }
+    }
+
+    /// Set `user`'s permissions under `path`, returning both if they're already set
+    fn path_permissions_add(path_permissions: &mut BTreeMap<String, BTreeMap<String, Permissions>>, path: String, user: String, perms: Permissions)
+                            -> Result<(), (String, String)> {
+        match path_permissions.entry(path.clone()).or_default().entry(user) {
+            BTreeMapEntry::Occupied(oe) => Err((path, oe.key().clone())),
+            BTreeMapEntry::Vacant(ve) => {
+                ve.insert(perms);
+                Ok(())
+            }
+        }
+    }
+
+    fn path_permissions_dupe(path: &str, user: &str) -> ClapError {
+        ClapError {
+            message: format!("Permissions for {} under path \"{}\" already present", user, path),
+            kind: ClapErrorKind::ArgumentConflict,
+            info: None,
+        }
+    }
+
+    fn path_credentials_dupe(path: &str) -> ClapError {
         ClapError {
-                message: format!("Credentials for path \"/{}\" already present", path),
-                kind: ClapErrorKind::ArgumentConflict,
-                info: None,
+            message: format!("Credentials for path \"/{}\" already present", path),
+            kind: ClapErrorKind::ArgumentConflict,
+            info: None,
+        }
+    }
+
+    /// Parse `PREFIX=DIR[,FLAG]...`, with the flags overriding the specified defaults
+    fn mount_parse(s: &str, mut allow_writes: bool, mut generate_listings: bool, mut sandbox_symlinks: bool) -> Result<Mount, String> {
+        let (prefix, mut dir) = s.split_once('=').ok_or_else(|| format!("{} not in PREFIX=DIR[,FLAG]... format", s))?;
+        let prefix = Options::normalise_path(prefix);
+        if prefix.is_empty() {
+            return Err(format!("{} would mount over the hosted directory", s));
+        }
+
+        let mut flags = vec![];
+        while let Some((rest, flag)) = dir.rsplit_once(',') {
+            if !["rw", "ro", "listings", "no-listings", "sandbox", "no-sandbox"].contains(&flag) {
+                break;
             }
-            .exit()
+            flags.push(flag);
+            dir = rest;
+        }
+        for flag in flags.into_iter().rev() {
+            match flag {
+                "rw" => allow_writes = true,
+                "ro" => allow_writes = false,
+                "listings" => generate_listings = true,
+                "no-listings" => generate_listings = false,
+                "sandbox" => sandbox_symlinks = true,
+                _ => sandbox_symlinks = false,
+            }
+        }
+
+        Options::filesystem_dir_validator(dir.to_string(), "Mount directory")?;
+        Ok(Mount {
+            prefix: prefix,
+            directory: (dir.to_string(), fs::canonicalize(dir).unwrap()),
+            allow_writes: allow_writes,
+            generate_listings: generate_listings,
+            sandbox_symlinks: sandbox_symlinks,
+        })
+    }
+
+    fn listen_parse(s: &str, default_tls: bool) -> Result<Listen, String> {
+        let mut address = s;
+        let (mut tls, mut mode) = (None, None);
+        while let Some((rest, flag)) = address.rsplit_once(',') {
+            match flag {
+                "tls" => tls = tls.or(Some(true)),
+                "plain" => tls = tls.or(Some(false)),
+                _ => {
+                    match flag.strip_prefix("mode=").map(|m| u32::from_str_radix(m, 8)) {
+                        Some(Ok(m)) if m <= 0o7777 => mode = mode.or(Some(m)),
+                        Some(_) => return Err(format!("{} not a valid mode", flag)),
+                        None if address.starts_with("unix:") => break,
+                        None => return Err(format!("{} not in ADDR:PORT[,FLAG]... format: unknown FLAG {}", s, flag)),
+                    }
+                }
+            }
+            address = rest;
+        }
+
+        if let Some(path) = address.strip_prefix("unix:") {
+            if cfg!(target_os = "windows") {
+                return Err(format!("{}: Unix domain sockets not supported on this platform", s));
+            }
+            if path.is_empty() {
+                return Err(format!("{} not in unix:PATH[,FLAG]... format", s));
+            }
+            if tls == Some(true) {
+                return Err(format!("{}: TLS not supported on Unix domain sockets", s));
+            }
+            Ok(Listen {
+                address: ListenAddress::Unix(PathBuf::from(path), mode),
+                tls: false,
+            })
+        } else {
+            if mode.is_some() {
+                return Err(format!("{}: mode only applies to Unix domain sockets", s));
+            }
+            Ok(Listen {
+                address: ListenAddress::Tcp(SocketAddr::from_str(address)
+                    .map_err(|e| format!("{} not in ADDR:PORT[,FLAG]... format (IPv6 ADDRs in []): {}", s, e))?),
+                tls: tls.unwrap_or(default_tls),
+            })
+        }
+    }
+
+    fn listen_error(message: String) -> ClapError {
+        ClapError {
+            message: message,
+            kind: ClapErrorKind::ArgumentConflict,
+            info: None,
+        }
+    }
+
+    fn port_range_parse(s: &str) -> Result<(u16, u16), String> {
+        let (from, to) = s.split_once('-').ok_or_else(|| format!("{} not in FROM-TO format", s))?;
+        match (u16::from_str(from), u16::from_str(to)) {
+            (Ok(from), Ok(to)) if from <= to => Ok((from, to)),
+            (Ok(_), Ok(_)) => Err(format!("{} ends before it starts", s)),
+            (Err(e), _) | (_, Err(e)) => Err(format!("{} not a valid port range: {}", s, e)),
+        }
+    }
+
+    fn auth_lockout_parse(s: &str) -> Result<(u64, u64), String> {
+        let (min, max) = s.split_once('-').ok_or_else(|| format!("{} not in MIN-MAX format", s))?;
+        match (Options::age_parse(min.into())?, Options::age_parse(max.into())?) {
+            (0, _) => Err(format!("{} starts at nothing; use --auth-failures 0 to never lock clients out", s)),
+            (min, max) if min <= max => Ok((min, max)),
+            _ => Err(format!("{} ends before it starts", s)),
+        }
+    }
+
+    fn mount_dupe(prefix: &str) -> ClapError {
+        ClapError {
+            message: format!("Mount for path \"/{}\" already present", prefix),
+            kind: ClapErrorKind::ArgumentConflict,
+            info: None,
+        }
+    }
+
+    /// Split `HOST=REST` into the lower-cased `HOST` and `REST`
+    fn vhost_split(s: &str) -> Result<(String, &str), String> {
+        match s.split_once('=') {
+            Some((host, rest)) if !host.is_empty() && !host.contains('/') => Ok((host.to_lowercase(), rest)),
+            _ => Err(format!("{} not in HOST=... format", s)),
+        }
+    }
+
+    fn vhost_parse(s: &str) -> Result<(String, &str), String> {
+        let (host, dir) = Options::vhost_split(s)?;
+        Options::filesystem_dir_validator(dir.to_string(), "Virtual host directory")?;
+        Ok((host, dir))
+    }
+
+    fn vhost_get<'v>(vhosts: &'v mut BTreeMap<String, VHost>, host: &str, arg: &str) -> Result<&'v mut VHost, ClapError> {
+        vhosts.get_mut(host).ok_or_else(|| Options::vhost_error(format!("{} for virtual host \"{}\" not specified with --vhost", arg, host)))
+    }
+
+    fn vhost_error(message: String) -> ClapError {
+        ClapError {
+            message: message,
+            kind: ClapErrorKind::ArgumentConflict,
+            info: None,
+        }
+    }
+
+    fn vhost_path_credentials_validator(s: String) -> Result<(), String> {
+        if Options::parse_vhost_path_credentials(&s).is_some() {
+            Ok(())
+        } else {
+            Err(format!("Per-virtual host authentication credentials \"{}\" need be in format \"host[/path]=[username[:password]]\"", s))
+        }
+    }
+
+    /// Parse `HOST[/PATH]=[USERNAME[:PASSWORD]]` into the lower-cased `HOST`, and `PATH` and the credentials like `parse_path_credentials()`
+    fn parse_vhost_path_credentials(s: &str) -> Option<(String, (String, Option<&str>))> {
+        let host_end = s.find(['/', '='])?;
+        if host_end == 0 {
+            return None;
+        }
+        Some((s[..host_end].to_lowercase(), Options::parse_path_credentials(&s[host_end..])?))
     }
 
     fn normalise_path(path: &str) -> String {
@@ -399,6 +1369,14 @@ impl Options {
         u16::from_str(&s).map(|_| ()).map_err(|_| format!("{} is not a valid port number", s))
     }
 
+    fn u32_validator(s: String) -> Result<(), String> {
+        u32::from_str(&s).map(|_| ()).map_err(|_| format!("{} is not a valid count", s))
+    }
+
+    fn nonzero_usize_validator(s: String) -> Result<(), String> {
+        NonZeroUsize::from_str(&s).map(|_| ()).map_err(|_| format!("{} is not a positive integer", s))
+    }
+
     fn size_parse<'s>(s: Cow<'s, str>) -> Result<u64, String> {
         let mut s = &s[..];
         if matches!(s.as_bytes().last(), Some(b'b' | b'B')) {
@@ -420,29 +1398,33 @@ impl Options {
 
     fn age_parse<'s>(s: Cow<'s, str>) -> Result<u64, String> {
         let mut s = &s[..];
-        let mul: u64 = match s.as_bytes().last() {
-            Some(b's') => 1,
-            Some(b'm') => 60,
-            Some(b'h') => 60 * 60,
-            Some(b'd') => 60 * 60 * 24,
-            _ => 1,
+        let mul: Option<u64> = match s.as_bytes().last() {
+            Some(b's') => Some(1),
+            Some(b'm') => Some(60),
+            Some(b'h') => Some(60 * 60),
+            Some(b'd') => Some(60 * 60 * 24),
+            _ => None,
         };
-        if mul != 1 {
+        if mul.is_some() {
             s = &s[..s.len() - 1];
         }
+        let mul = mul.unwrap_or(1);
         s.parse().map(|age: u64| age * mul).map_err(|e| format!("{} not a valid (optionally-s/m/h/d-suffixed) number: {}", s, e))
     }
 
-    fn proxy_parse<'s>(s: Cow<'s, str>) -> Result<(IpCidr, String), String> {
+    fn proxy_parse<'s>(s: Cow<'s, str>) -> Result<(ProxySource, String), String> {
         match s.find(":") {
             None => Err(format!("{} not in HEADER-NAME:CIDR format", s)),
             Some(0) => Err(format!("{} sets invalid zero-length header", s)),
             Some(col_idx) => {
-                let cidr = s[col_idx + 1..].parse().map_err(|e| format!("{} not a valid CIDR: {}", &s[col_idx + 1..], e))?;
+                let source = match &s[col_idx + 1..] {
+                    "unix" => ProxySource::Unix,
+                    cidr => ProxySource::Network(cidr.parse().map_err(|e| format!("{} not a valid CIDR: {}", cidr, e))?),
+                };
 
                 let mut s = s.into_owned();
                 s.truncate(col_idx);
-                Ok((cidr, s))
+                Ok((source, s))
             }
         }
     }
@@ -503,4 +1485,14 @@ impl Options {
             .map(|(hn, hd)| (hn.to_string(), hd.as_bytes().to_vec()))
             .ok_or_else(|| format!("\"{}\" invalid format", s))
     }
+
+    fn compression_level_parse(s: &str) -> Result<(&str, u32), String> {
+        let (enc, level) = s.split_once(':').ok_or_else(|| format!("{} not in ENCODING:LEVEL format", s))?;
+        let level = u32::from_str(level).map_err(|e| format!("\"{}\" not compression level: {}", level, e))?;
+        match CompressionLevels::default().set(enc, level) {
+            Ok(()) => Ok((enc, level)),
+            Err(None) => Err(format!("\"{}\" not gzip, deflate, br, or zstd", enc)),
+            Err(Some(max)) => Err(format!("{} compression level {} out of range (max {})", enc, level, max)),
+        }
+    }
 }
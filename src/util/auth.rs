@@ -0,0 +1,92 @@
+use argon2::{PasswordVerifier, PasswordHash, Argon2};
+use constant_time_eq::constant_time_eq;
+use std::collections::HashSet;
+use std::sync::RwLock;
+use pwhash;
+use blake3;
+
+
+/// bcrypt hash of the empty password, at the usual cost, to check passwords supplied for unknown users against
+pub const DUMMY_PASSWORD_HASH: &str = "$2b$10$vF8vgFBnaL4AsDmpPOxZ2eOk0i0fAH//2bhAYr2Ae7BqmDNZwaDBi";
+
+/// How many correctly-supplied hashed passwords to remember at most, before starting over
+const MAX_VERIFIED_PASSWORDS: usize = 1024;
+
+
+/// Whether `password` is hashed, as opposed to plain text, i.e. whether it starts like a bcrypt (`$2a$`, `$2b$`, `$2y$`),
+/// SHA-crypt (`$5$`, `$6$`), or Argon2 (`$argon2i$`, `$argon2d$`, `$argon2id$`) hash.
+pub fn is_password_hash(password: &str) -> bool {
+    ["$2a$", "$2b$", "$2y$", "$5$", "$6$", "$argon2i$", "$argon2d$", "$argon2id$"].iter().any(|pref| password.starts_with(pref))
+}
+
+/// Check the `supplied` password against the configured `password`, which is either hashed or in plain text,
+/// in constant time for the configured one
+pub fn password_matches(password: &str, supplied: &str) -> bool {
+    if password.starts_with("$argon2") {
+        PasswordHash::new(password).map(|hash| Argon2::default().verify_password(supplied.as_bytes(), &hash).is_ok()).unwrap_or(false)
+    } else if is_password_hash(password) {
+        pwhash::unix::verify(supplied, password)
+    } else {
+        constant_time_eq(password.as_bytes(), supplied.as_bytes())
+    }
+}
+
+/// Hashed passwords supplied correctly before, so each only has to be checked the slow way once
+#[derive(Debug, Default)]
+pub struct VerifiedPasswords(RwLock<HashSet<blake3::Hash>>);
+
+impl VerifiedPasswords {
+    /// `password_matches()`, but remembering a BLAKE3 hash of each hashed `password` and the `supplied` one that matched it
+    pub fn matches(&self, password: &str, supplied: &str) -> bool {
+        if !is_password_hash(password) {
+            return password_matches(password, supplied);
+        }
+
+        let key = blake3::Hasher::new().update(password.as_bytes()).update(b"\0").update(supplied.as_bytes()).finalize();
+        if self.0.read().expect("Verified passwords read lock poisoned").contains(&key) {
+            return true;
+        }
+
+        let matches = password_matches(password, supplied);
+        if matches {
+            let mut verified = self.0.write().expect("Verified passwords write lock poisoned");
+            if verified.len() >= MAX_VERIFIED_PASSWORDS {
+                verified.clear();
+            }
+            verified.insert(key);
+        }
+        matches
+    }
+
+    /// Forget all passwords, e.g. once the configured ones change
+    pub fn clear(&self) {
+        self.0.write().expect("Verified passwords write lock poisoned").clear();
+    }
+}
+
+/// Parse an htpasswd file into `(username, password)` pairs, with the passwords hashed with the schemes from `is_password_hash()`,
+/// or in plain text.
+///
+/// Blank lines and lines starting with `#` are skipped. Other hashes, like Apache's MD5 (`$apr1$`) and SHA-1 (`{SHA}`), are an error.
+pub fn parse_htpasswd(data: &str) -> Result<Vec<(String, String)>, String> {
+    let mut accounts = vec![];
+    for (i, line) in data.lines().enumerate() {
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (username, password) = match line.split_once(':') {
+            Some((username, password)) if !username.is_empty() => (username, password),
+            _ => return Err(format!("line {} not in username:password format", i + 1)),
+        };
+        if !is_password_hash(password) && (password.starts_with('$') || password.starts_with("{SHA}")) {
+            return Err(format!("line {}: unsupported hash for {}; use bcrypt, SHA-crypt, or Argon2", i + 1, username));
+        }
+        if password.starts_with("$argon2") && PasswordHash::new(password).is_err() {
+            return Err(format!("line {}: malformed Argon2 hash for {}", i + 1, username));
+        }
+        accounts.push((username.to_string(), password.to_string()));
+    }
+    Ok(accounts)
+}
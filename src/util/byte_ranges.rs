@@ -0,0 +1,95 @@
+use std::io::{self, SeekFrom, Write, Read, Seek, Take};
+use iron::headers::{ByteRangeSpec, ContentRangeSpec};
+use std::hash::{BuildHasher, RandomState};
+use iron::response::WriteBody;
+use iron::mime::Mime;
+use std::fs::File;
+use std::cmp;
+
+
+/// The maximal amount of ranges (after coalescing) to serve in a single `multipart/byteranges` response.
+pub const MAX_BYTE_RANGES: usize = 64;
+
+
+/// Resolve the requested byte ranges against a file of the specified length into sorted inclusive `(from, to)` pairs.
+///
+/// Unsatisfiable ranges are dropped and overlapping or adjacent ranges are coalesced,
+/// so the result may be empty, or shorter than the request.
+pub fn satisfiable_byte_ranges(brs: &[ByteRangeSpec], flen: u64) -> Vec<(u64, u64)> {
+    let mut ranges = brs.iter()
+        .filter_map(|br| match *br {
+            ByteRangeSpec::FromTo(from, to) if from < flen => Some((from, cmp::min(to, flen - 1))),
+            ByteRangeSpec::AllFrom(from) if from < flen => Some((from, flen - 1)),
+            ByteRangeSpec::Last(len) if len != 0 && flen != 0 => Some((flen.saturating_sub(len), flen - 1)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    ranges.sort_unstable();
+    ranges.dedup_by(|cur, prev| if cur.0 <= prev.1.saturating_add(1) {
+        prev.1 = cmp::max(prev.1, cur.1);
+        true
+    } else {
+        false
+    });
+    ranges
+}
+
+/// The `Content-Range` of the inclusive `(from, to)` range of a file `flen` bytes long.
+pub fn byte_range_content_range((from, to): (u64, u64), flen: u64) -> ContentRangeSpec {
+    ContentRangeSpec::Bytes {
+        range: Some((from, to)),
+        instance_length: Some(flen),
+    }
+}
+
+/// Seek to the start of the inclusive `(from, to)` range, and only read up to its end.
+pub fn seek_byte_range<R: Read + Seek>(mut file: R, (from, to): (u64, u64)) -> io::Result<Take<R>> {
+    file.seek(SeekFrom::Start(from))?;
+    Ok(file.take(to + 1 - from))
+}
+
+/// Generate a random boundary for a multipart response.
+pub fn multipart_boundary() -> String {
+    let rnd = RandomState::new();
+    format!("{:016x}{:016x}", rnd.hash_one(0), rnd.hash_one(1))
+}
+
+
+/// A [`multipart/byteranges`](https://tools.ietf.org/html/rfc7233#appendix-A) response body,
+/// streamed straight from the file.
+pub struct MultipartByteRanges {
+    pub file: File,
+    pub ranges: Vec<(u64, u64)>,
+    pub boundary: String,
+    pub mime_type: Mime,
+    pub file_length: u64,
+}
+
+impl MultipartByteRanges {
+    /// Exact length of the body, for `Content-Length`.
+    pub fn content_length(&self) -> u64 {
+        self.ranges.iter().map(|&r| self.part_header(r).len() as u64 + (r.1 + 1 - r.0)).sum::<u64>() + self.trailer().len() as u64
+    }
+
+    fn part_header(&self, (from, to): (u64, u64)) -> String {
+        format!("\r\n--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+                self.boundary,
+                self.mime_type,
+                byte_range_content_range((from, to), self.file_length))
+    }
+
+    fn trailer(&self) -> String {
+        format!("\r\n--{}--\r\n", self.boundary)
+    }
+}
+
+impl WriteBody for MultipartByteRanges {
+    fn write_body(&mut self, res: &mut dyn Write) -> io::Result<()> {
+        for i in 0..self.ranges.len() {
+            let range = self.ranges[i];
+            res.write_all(self.part_header(range).as_bytes())?;
+            io::copy(&mut seek_byte_range(&mut self.file, range)?, res)?;
+        }
+        res.write_all(self.trailer().as_bytes())
+    }
+}
@@ -1,9 +1,11 @@
 use brotli::enc::backward_references::{BrotliEncoderParams, BrotliEncoderMode};
-use std::io::{self, BufReader, BufWriter, Error as IoError, Write};
+use std::io::{self, BufReader, BufWriter, Error as IoError, Write, Read};
 use iron::headers::{QualityItem, EncodingType, Encoding};
+use iron::response::WriteBody;
 use brotli::enc::BrotliCompress as brotli_compress;
 use flate2::write::{DeflateEncoder, GzEncoder};
 use flate2::Compression as Flate2Compression;
+use zstd::stream::write::Encoder as ZstdEncoder;
 use std::path::Path;
 use std::ffi::OsStr;
 use std::fs::File;
@@ -19,6 +21,56 @@ pub const MAX_ENCODING_SIZE: u64 = 100 * 1024 * 1024;
 /// The minimal size gain at which to preserve encoded filesystem files.
 pub const MIN_ENCODING_GAIN: f64 = 1.1;
 
+/// The maximal amount of filesystem files waiting to be encoded, past which requests are served identity without queueing.
+pub const ENCODING_QUEUE_SIZE: usize = 1024;
+
+/// All encodings we can encode with.
+pub const SUPPORTED_ENCODINGS: &[EncodingType] = &[EncodingType::Gzip, EncodingType::Deflate, EncodingType::Brotli, EncodingType::Zstd];
+
+
+/// Compression level to use for each supported encoding.
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+pub struct CompressionLevels {
+    /// 0-9, default: 6
+    pub gzip: u32,
+    /// 0-9, default: 6
+    pub deflate: u32,
+    /// 0-11, default: 9
+    pub brotli: u32,
+    /// 1-22, default: 3
+    pub zstd: u32,
+}
+
+impl Default for CompressionLevels {
+    fn default() -> CompressionLevels {
+        CompressionLevels {
+            gzip: 6,
+            deflate: 6,
+            brotli: 9,
+            zstd: 3,
+        }
+    }
+}
+
+impl CompressionLevels {
+    /// Set the level for the encoding named as in `Accept-Encoding`, or return the allowed maximum, if out of range
+    /// (or `None` if the encoding is not recognised).
+    pub fn set(&mut self, enc: &str, level: u32) -> Result<(), Option<u32>> {
+        let (lvl, max) = match enc {
+            "gzip" => (&mut self.gzip, 9),
+            "deflate" => (&mut self.deflate, 9),
+            "br" => (&mut self.brotli, 11),
+            "zstd" => (&mut self.zstd, 22),
+            _ => return Err(None),
+        };
+        if level > max || (enc == "zstd" && level == 0) {
+            return Err(Some(max));
+        }
+        *lvl = level;
+        Ok(())
+    }
+}
+
 
 // `true` if we know not to encode the given extension
 // pub fn extension_is_blacklisted(ext: &str) -> bool {
@@ -32,36 +84,82 @@ pub fn response_encoding(requested: &mut [QualityItem<Encoding>]) -> Option<Enco
 }
 
 /// Encode a string slice using a specified encoding or `None` if encoding failed or is not recognised.
-pub fn encode_str(dt: &str, enc: &Encoding) -> Option<Vec<u8>> {
-    type EncodeT = fn(&str) -> Option<Vec<u8>>;
-    const STR_ENCODING_FNS: &[EncodeT] = &[encode_str_gzip, encode_str_deflate, encode_str_brotli];
+pub fn encode_str(dt: &str, enc: &Encoding, levels: &CompressionLevels) -> Option<Vec<u8>> {
+    type EncodeT = fn(&str, &CompressionLevels) -> Option<Vec<u8>>;
+    const STR_ENCODING_FNS: &[EncodeT] = &[encode_str_gzip, encode_str_deflate, encode_str_brotli, encode_str_zstd];
 
-    encoding_idx(enc).and_then(|fi| STR_ENCODING_FNS[fi](dt))
+    encoding_idx(enc).and_then(|fi| STR_ENCODING_FNS[fi](dt, levels))
 }
 
 /// Encode the file denoted by the specified path into the file denoted by the specified path using a specified encoding or
 /// `false` if encoding failed, is not recognised or an I/O error occurred.
-pub fn encode_file(p: &Path, op: &Path, enc: &Encoding) -> bool {
-    type EncodeT = fn(File, File) -> bool;
-    const FILE_ENCODING_FNS: &[EncodeT] = &[encode_file_gzip, encode_file_deflate, encode_file_brotli];
+pub fn encode_file(p: &Path, op: &Path, enc: &Encoding, levels: &CompressionLevels) -> bool {
+    type EncodeT = fn(File, File, &CompressionLevels) -> bool;
+    const FILE_ENCODING_FNS: &[EncodeT] = &[encode_file_gzip, encode_file_deflate, encode_file_brotli, encode_file_zstd];
 
     encoding_idx(enc)
         .map(|fi| {
             let inf = File::open(p);
             let outf = File::create(op);
 
-            inf.is_ok() && outf.is_ok() && FILE_ENCODING_FNS[fi](inf.unwrap(), outf.unwrap())
+            inf.is_ok() && outf.is_ok() && FILE_ENCODING_FNS[fi](inf.unwrap(), outf.unwrap(), levels)
         })
         .unwrap()
 }
 
+/// Encode everything read from the specified reader into the specified writer using a specified encoding.
+pub fn encode_stream(inf: &mut dyn Read, outf: &mut dyn Write, enc: &Encoding, levels: &CompressionLevels) -> io::Result<()> {
+    type EncodeT = fn(&mut dyn Read, &mut dyn Write, &CompressionLevels) -> io::Result<()>;
+    const STREAM_ENCODING_FNS: &[EncodeT] = &[encode_stream_gzip, encode_stream_deflate, encode_stream_brotli, encode_stream_zstd];
+
+    match encoding_idx(enc) {
+        Some(fi) => STREAM_ENCODING_FNS[fi](inf, outf, levels),
+        None => Err(IoError::new(io::ErrorKind::InvalidInput, format!("{} not supported", enc))),
+    }
+}
+
 /// Encoding extension to use for encoded files, for example "gz" for gzip, or `None` if the encoding is not recognised.
 pub fn encoding_extension(enc: &Encoding) -> Option<&'static str> {
-    const ENCODING_EXTS: &[&str] = &["gz", "dflt", "br"];
+    const ENCODING_EXTS: &[&str] = &["gz", "dflt", "br", "zst"];
 
     encoding_idx(enc).map(|ei| ENCODING_EXTS[ei])
 }
 
+/// Encodings that precompressed siblings are served for; deflate has no file extension anyone uses
+const PRECOMPRESSED_ENCODINGS: &[EncodingType] = &[EncodingType::Gzip, EncodingType::Brotli, EncodingType::Zstd];
+
+/// Extension of precompressed siblings served in place of the original file, for example "br" for brotli,
+/// or `None` if the encoding is not recognised.
+pub fn precompressed_extension(enc: &Encoding) -> Option<&'static str> {
+    if PRECOMPRESSED_ENCODINGS.contains(&enc.0) {
+        encoding_extension(enc)
+    } else {
+        None
+    }
+}
+
+/// Check if the specified path is a precompressed sibling of a file, i.e. `FILE.{br,gz,zst}` next to `FILE`.
+pub fn is_precompressed_sibling(p: &Path) -> bool {
+    p.extension()
+        .map(|ext| PRECOMPRESSED_ENCODINGS.iter().any(|&enc| encoding_extension(&Encoding(enc, String::new(), false)).map(|e| ext == e).unwrap_or(false)))
+        .unwrap_or(false) && p.with_extension("").is_file()
+}
+
+/// A filesystem file, encoded on the fly as it's written out, without a known length.
+pub struct EncodedFileStream {
+    pub file: File,
+    pub encoding: Encoding,
+    pub levels: CompressionLevels,
+}
+
+impl WriteBody for EncodedFileStream {
+    fn write_body(&mut self, res: &mut dyn Write) -> io::Result<()> {
+        let mut out = BufWriter::with_capacity(64 * 1024, res);
+        encode_stream(&mut BufReader::with_capacity(1024 * 1024, &mut self.file), &mut out, &self.encoding, &self.levels)?;
+        out.flush()
+    }
+}
+
 /// Return the 256-bit BLAKE3 hash of the file denoted by the specified path.
 pub fn file_hash(p: &Path) -> Result<blake3::Hash, IoError> {
     let mut ctx = blake3::Hasher::new();
@@ -75,46 +173,67 @@ fn encoding_idx(enc: &Encoding) -> Option<usize> {
         EncodingType::Gzip => Some(0),
         EncodingType::Deflate => Some(1),
         EncodingType::Brotli => Some(2),
+        EncodingType::Zstd => Some(3),
         _ => None,
     }
 }
 
 macro_rules! encode_fn {
-    ($str_fn_name:ident, $file_fn_name:ident, $enc_tp:ident, $comp_lvl:expr, $constructor:expr) => {
-        fn $str_fn_name(dt: &str) -> Option<Vec<u8>> {
-            let mut cmp = $constructor(Vec::new());
+    ($str_fn_name:ident, $file_fn_name:ident, $stream_fn_name:ident, constructor $constructor:expr) => {
+        fn $str_fn_name(dt: &str, levels: &CompressionLevels) -> Option<Vec<u8>> {
+            let mut cmp = $constructor(Vec::new(), levels)?;
             cmp.write_all(dt.as_bytes()).ok().and_then(|_| cmp.finish().ok())
         }
 
-        fn $file_fn_name(inf: File, outf: File) -> bool {
-            let mut cmp = $constructor(BufWriter::with_capacity(1024 * 1024, outf));
+        fn $file_fn_name(inf: File, outf: File, levels: &CompressionLevels) -> bool {
+            let mut cmp = match $constructor(BufWriter::with_capacity(1024 * 1024, outf), levels) {
+                Some(cmp) => cmp,
+                None => return false,
+            };
             io::copy(&mut BufReader::with_capacity(1024 * 1024, inf), &mut cmp).and_then(|_| cmp.finish()).is_ok()
         }
+
+        fn $stream_fn_name(inf: &mut dyn Read, outf: &mut dyn Write, levels: &CompressionLevels) -> io::Result<()> {
+            let mut cmp = $constructor(outf, levels).ok_or_else(|| IoError::new(io::ErrorKind::Other, "Failed to create encoder"))?;
+            io::copy(inf, &mut cmp)?;
+            cmp.finish().map(|_| ())
+        }
     };
 
-    ($str_fn_name:ident, $file_fn_name:ident, $enc_tp:ident, $comp_lvl:expr) => {
-        encode_fn!($str_fn_name, $file_fn_name, $enc_tp, $comp_lvl, |into| $enc_tp::new(into, $comp_lvl));
+    ($str_fn_name:ident, $file_fn_name:ident, $stream_fn_name:ident, $enc_tp:ident, $comp_lvl:expr) => {
+        encode_fn!($str_fn_name, $file_fn_name, $stream_fn_name, constructor |into, levels| Some($enc_tp::new(into, $comp_lvl(levels))));
     }
 }
 
-encode_fn!(encode_str_gzip, encode_file_gzip, GzEncoder, Flate2Compression::default());
-encode_fn!(encode_str_deflate, encode_file_deflate, DeflateEncoder, Flate2Compression::default());
+encode_fn!(encode_str_gzip, encode_file_gzip, encode_stream_gzip, GzEncoder, |l: &CompressionLevels| Flate2Compression::new(l.gzip));
+encode_fn!(encode_str_deflate,
+           encode_file_deflate,
+           encode_stream_deflate,
+           DeflateEncoder,
+           |l: &CompressionLevels| Flate2Compression::new(l.deflate));
+encode_fn!(encode_str_zstd,
+           encode_file_zstd,
+           encode_stream_zstd,
+           constructor |into, l: &CompressionLevels| ZstdEncoder::new(into, l.zstd as i32).ok());
 
 /// This should just be a pub const, but the new and default functions aren't const
-pub fn brotli_params() -> BrotliEncoderParams {
+pub fn brotli_params(levels: &CompressionLevels) -> BrotliEncoderParams {
     BrotliEncoderParams {
         mode: BrotliEncoderMode::BROTLI_MODE_TEXT,
-        quality: 9,
+        quality: levels.brotli as i32,
         ..Default::default()
     }
 }
-fn encode_str_brotli(dt: &str) -> Option<Vec<u8>> {
+fn encode_str_brotli(dt: &str, levels: &CompressionLevels) -> Option<Vec<u8>> {
     let mut ret = Vec::new();
-    brotli_compress(&mut dt.as_bytes(), &mut ret, &brotli_params()).ok().map(|_| ret)
+    brotli_compress(&mut dt.as_bytes(), &mut ret, &brotli_params(levels)).ok().map(|_| ret)
 }
-fn encode_file_brotli(inf: File, outf: File) -> bool {
+fn encode_file_brotli(inf: File, outf: File, levels: &CompressionLevels) -> bool {
     brotli_compress(&mut BufReader::with_capacity(1024 * 1024, inf),
                     &mut BufWriter::with_capacity(1024 * 1024, outf),
-                    &brotli_params())
+                    &brotli_params(levels))
         .is_ok()
 }
+fn encode_stream_brotli(mut inf: &mut dyn Read, mut outf: &mut dyn Write, levels: &CompressionLevels) -> io::Result<()> {
+    brotli_compress(&mut inf, &mut outf, &brotli_params(levels)).map(|_| ())
+}
@@ -2,7 +2,9 @@
 
 
 mod os;
+mod auth;
 mod webdav;
+mod byte_ranges;
 mod content_encoding;
 
 use std::path::Path;
@@ -11,6 +13,7 @@ use walkdir::WalkDir;
 use std::borrow::Cow;
 use rfsapi::RawFileData;
 use std::time::SystemTime;
+use std::net::{SocketAddrV4, SocketAddr, Ipv4Addr};
 use iron::{mime, Headers, Url};
 use time::{self, Duration, Tm};
 use std::{cmp, fmt, f64, mem, str};
@@ -23,7 +26,9 @@ use iron::mime::{Mime, SubLevel as MimeSubLevel, TopLevel as MimeTopLevel};
 use std::io::{ErrorKind as IoErrorKind, Result as IoResult, Error as IoError, Write, Read};
 
 pub use self::os::*;
+pub use self::auth::*;
 pub use self::webdav::*;
+pub use self::byte_ranges::*;
 pub use self::content_encoding::*;
 
 
@@ -67,6 +72,10 @@ pub const PORT_SCAN_LOWEST: u16 = 8000;
 /// The port to end scanning at if no ports were given.
 pub const PORT_SCAN_HIGHEST: u16 = 9999;
 
+/// The address connections to Unix domain sockets appear to come from:
+/// localhost port 0, which TCP connections can't come from, so they're only matched by `--proxy HEADER-NAME:unix`.
+pub const UNIX_SOCKET_PEER: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0));
+
 /// The app name and version to use with User-Agent or Server response header.
 pub const USER_AGENT: &str = concat!("http/", env!("CARGO_PKG_VERSION"));
 
@@ -102,6 +111,29 @@ impl HeaderFormat for WwwAuthenticate {
     }
 }
 
+/// The [Retry-After header](https://tools.ietf.org/html/rfc7231#section-7.1.3), in seconds.
+///
+/// No parsing, we only send.
+#[derive(Debug, Copy, Clone, Hash, PartialOrd, Ord, PartialEq, Eq)]
+pub struct RetryAfter(pub u64);
+
+impl Header for RetryAfter {
+    fn header_name() -> &'static str {
+        "Retry-After"
+    }
+
+    /// We only ever send these
+    fn parse_header<T: AsRef<[u8]>>(_: &[T]) -> HyperResult<RetryAfter> {
+        unreachable!()
+    }
+}
+
+impl HeaderFormat for RetryAfter {
+    fn fmt_header(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
 /// The `X-Last-Modified` header: milliseconds since epoch for PUTs.
 ///
 /// Required since XMLHttpRequests can't set `Date:`.
@@ -0,0 +1,105 @@
+use libc::{IN_CLOEXEC, IN_MODIFY, IN_ATTRIB, IN_CLOSE_WRITE, IN_MOVED_FROM, IN_MOVED_TO, IN_CREATE, IN_DELETE, IN_ONLYDIR, IN_ISDIR, IN_Q_OVERFLOW,
+           IN_IGNORED, inotify_init1, inotify_add_watch, inotify_rm_watch, inotify_event};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::collections::HashMap;
+use std::path::{PathBuf, Path};
+use std::io::{self, Read};
+use std::ffi::{CString, OsStr};
+use std::os::raw::c_int;
+use walkdir::WalkDir;
+use std::fs::File;
+use std::{mem, ptr};
+
+
+const WATCH_MASK: u32 = IN_MODIFY | IN_ATTRIB | IN_CLOSE_WRITE | IN_MOVED_FROM | IN_MOVED_TO | IN_CREATE | IN_DELETE | IN_ONLYDIR;
+
+
+/// Watch the directory tree under `root` for changes, calling `changed(path, is_dir)` for each changed path, forever.
+///
+/// A queue overflow reports `root` itself as changed.
+/// The first directory under `root` that can't be watched (e.g. for running out of watches) is reported to `unwatched(dir, err)`,
+/// and further ones are skipped silently.
+///
+/// Returns an error if the watch couldn't be set up or broke.
+pub fn watch_tree<F: FnMut(&Path, bool), U: FnOnce(&Path, io::Error)>(root: &Path, follow_symlinks: bool, mut changed: F, unwatched: U) -> io::Result<()> {
+    let fd = unsafe { inotify_init1(IN_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut inotify = unsafe { File::from_raw_fd(fd) };
+
+    let mut dirs = HashMap::new();
+    let mut unwatched = Some(unwatched);
+    add_watch(&inotify, root, &mut dirs)?;
+    add_watches(&inotify, root, follow_symlinks, &mut dirs, &mut unwatched);
+
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let len = inotify.read(&mut buf)?;
+        let mut off = 0;
+        while off + mem::size_of::<inotify_event>() <= len {
+            let event = unsafe { ptr::read_unaligned(buf[off..].as_ptr() as *const inotify_event) };
+            let name = &buf[off + mem::size_of::<inotify_event>()..off + mem::size_of::<inotify_event>() + event.len as usize];
+            off += mem::size_of::<inotify_event>() + event.len as usize;
+
+            if event.mask & IN_Q_OVERFLOW != 0 {
+                changed(root, true);
+                continue;
+            }
+            if event.mask & IN_IGNORED != 0 {
+                dirs.remove(&event.wd);
+                continue;
+            }
+            let path = match dirs.get(&event.wd) {
+                Some(dir) => dir.join(OsStr::from_bytes(name.split(|&b| b == 0).next().unwrap_or(&[]))),
+                None => continue,
+            };
+
+            let is_dir = event.mask & IN_ISDIR != 0;
+            if is_dir && event.mask & (IN_MOVED_FROM | IN_DELETE) != 0 {
+                dirs.retain(|&wd, dir| if dir.starts_with(&path) {
+                    unsafe { inotify_rm_watch(inotify.as_raw_fd(), wd) };
+                    false
+                } else {
+                    true
+                });
+            }
+            if is_dir && event.mask & (IN_CREATE | IN_MOVED_TO) != 0 {
+                if let Err(err) = add_watch(&inotify, &path, &mut dirs) {
+                    report_unwatched(&path, err, &mut unwatched);
+                }
+                add_watches(&inotify, &path, follow_symlinks, &mut dirs, &mut unwatched);
+            }
+            changed(&path, is_dir);
+        }
+    }
+}
+
+/// Watch all directories under `root`, skipping ones that can't be
+fn add_watches<U: FnOnce(&Path, io::Error)>(inotify: &File, root: &Path, follow_symlinks: bool, dirs: &mut HashMap<c_int, PathBuf>, unwatched: &mut Option<U>) {
+    for dir in WalkDir::new(root).min_depth(1).follow_links(follow_symlinks).into_iter().flatten().filter(|e| e.file_type().is_dir()) {
+        if let Err(err) = add_watch(inotify, dir.path(), dirs) {
+            report_unwatched(dir.path(), err, unwatched);
+        }
+    }
+}
+
+/// Report the first directory that couldn't be watched, unless it's since gone
+fn report_unwatched<U: FnOnce(&Path, io::Error)>(dir: &Path, err: io::Error, unwatched: &mut Option<U>) {
+    if err.kind() != io::ErrorKind::NotFound {
+        if let Some(unwatched) = unwatched.take() {
+            unwatched(dir, err);
+        }
+    }
+}
+
+fn add_watch(inotify: &File, dir: &Path, dirs: &mut HashMap<c_int, PathBuf>) -> io::Result<()> {
+    let dir_c = CString::new(dir.as_os_str().as_bytes()).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    let wd = unsafe { inotify_add_watch(inotify.as_raw_fd(), dir_c.as_ptr(), WATCH_MASK) };
+    if wd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    dirs.insert(wd, dir.to_path_buf());
+    Ok(())
+}
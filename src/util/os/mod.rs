@@ -6,6 +6,10 @@ mod non_windows;
 mod windows_macos;
 #[cfg(not(any(target_os = "windows", target_os = "macos")))]
 mod non_windows_non_macos;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod linux;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+mod non_linux;
 
 #[cfg(target_os = "windows")]
 pub use self::windows::*;
@@ -15,3 +19,7 @@ pub use self::non_windows::*;
 pub use self::windows_macos::*;
 #[cfg(not(any(target_os = "windows", target_os = "macos")))]
 pub use self::non_windows_non_macos::*;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use self::linux::*;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub use self::non_linux::*;
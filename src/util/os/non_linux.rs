@@ -0,0 +1,8 @@
+use std::path::Path;
+use std::io;
+
+
+/// Watching for changes is only supported on Linux; always returns `ErrorKind::Unsupported`
+pub fn watch_tree<F: FnMut(&Path, bool), U: FnOnce(&Path, io::Error)>(_: &Path, _: bool, _: F, _: U) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
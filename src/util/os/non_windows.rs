@@ -1,9 +1,22 @@
-use libc::{AT_SYMLINK_NOFOLLOW, UTIME_OMIT, AT_FDCWD, mode_t, futimens, utimensat, timespec, umask};
-use std::os::unix::fs::{PermissionsExt, MetadataExt};
+use libc::{AT_SYMLINK_NOFOLLOW, UTIME_OMIT, AT_FDCWD, F_SETFD, FD_CLOEXEC, SA_RESTART, SIGHUP, SIGTERM, AF_INET, AF_INET6, AF_UNIX, SOCK_STREAM, SOL_SOCKET, SO_REUSEADDR,
+           SO_TYPE, IPPROTO_IPV6, IPV6_V6ONLY, mode_t, futimens, utimensat, timespec, umask, pipe, fcntl, write, sigaction, sigemptyset, sighandler_t, c_void,
+           socket, setsockopt, getsockopt, getsockname, bind, listen, sockaddr, sockaddr_in6, sockaddr_storage, sa_family_t, socklen_t,
+           passwd, uid_t, gid_t, c_char, geteuid, chroot, chdir, initgroups, setgroups, setgid, setuid, getpwuid_r, getpwnam_r, getgrnam_r};
+use std::os::unix::net::{UnixDatagram, UnixListener};
+use std::net::{SocketAddr, TcpListener};
+use std::time::Duration;
+use std::{env, process};
+use std::sync::atomic::{AtomicI32, Ordering as AtomicOrdering};
+use std::os::unix::fs::{PermissionsExt, MetadataExt, lchown};
+use std::os::fd::{AsRawFd, FromRawFd};
 use self::super::super::is_actually_file;
 use std::fs::{self, Metadata, File};
 use std::os::unix::ffi::OsStrExt;
-use std::os::fd::AsRawFd;
+use std::ffi::{CString, CStr};
+use walkdir::WalkDir;
+use std::io::{self, Read};
+use std::os::raw::c_int;
+use std::{mem, ptr, thread};
 use std::path::Path;
 
 
@@ -125,3 +138,259 @@ fn ms_to_timespec(ms: u64) -> timespec {
         tv_nsec: ((ms % 1000) * 1000_000) as _,
     }
 }
+
+
+static SIGHUP_PIPE: AtomicI32 = AtomicI32::new(-1);
+static SIGTERM_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn signal_handler(sig: c_int) {
+    let pipe = if sig == SIGHUP { &SIGHUP_PIPE } else { &SIGTERM_PIPE };
+    let _ = unsafe { write(pipe.load(AtomicOrdering::Relaxed), [0u8].as_ptr() as *const c_void, 1) };
+}
+
+/// Call `f` on a background thread each time SIGHUP is received
+pub fn on_sighup<F: FnMut() + Send + 'static>(f: F) -> io::Result<()> {
+    on_signal(SIGHUP, &SIGHUP_PIPE, "sighup", f)
+}
+
+/// Call `f` on a background thread each time SIGTERM is received
+pub fn on_sigterm<F: FnMut() + Send + 'static>(f: F) -> io::Result<()> {
+    on_signal(SIGTERM, &SIGTERM_PIPE, "sigterm", f)
+}
+
+fn on_signal<F: FnMut() + Send + 'static>(sig: c_int, pipe_fd: &AtomicI32, thread_name: &str, mut f: F) -> io::Result<()> {
+    let mut fds = [-1; 2];
+    if unsafe { pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    for &fd in &fds {
+        unsafe { fcntl(fd, F_SETFD, FD_CLOEXEC) };
+    }
+    let mut rx = unsafe { File::from_raw_fd(fds[0]) };
+    pipe_fd.store(fds[1], AtomicOrdering::Relaxed);
+
+    unsafe {
+        let mut action: sigaction = mem::zeroed();
+        action.sa_sigaction = signal_handler as extern "C" fn(c_int) as sighandler_t;
+        action.sa_flags = SA_RESTART;
+        sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(sig, &action, ptr::null_mut()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    thread::Builder::new()
+        .name(thread_name.to_string())
+        .spawn(move || {
+            let mut buf = [0u8; 64];
+            while let Ok(1..) = rx.read(&mut buf) {
+                f();
+            }
+        })
+        .map(|_| ())
+}
+
+
+/// Bind a listening TCP socket to `addr`, like `TcpListener::bind()`,
+/// except IPv6 sockets only accept IPv6 connections, so IPv4 ones can be bound to the same port alongside them
+pub fn tcp_listener(addr: SocketAddr) -> io::Result<TcpListener> {
+    let addr = match addr {
+        SocketAddr::V4(_) => return TcpListener::bind(addr),
+        SocketAddr::V6(addr) => addr,
+    };
+
+    unsafe {
+        let fd = socket(AF_INET6, SOCK_STREAM, 0);
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let listener = TcpListener::from_raw_fd(fd);
+        fcntl(fd, F_SETFD, FD_CLOEXEC);
+
+        let one: c_int = 1;
+        for &(level, name) in &[(SOL_SOCKET, SO_REUSEADDR), (IPPROTO_IPV6, IPV6_V6ONLY)] {
+            if setsockopt(fd, level, name, &one as *const c_int as *const c_void, mem::size_of::<c_int>() as socklen_t) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        let mut sa: sockaddr_in6 = mem::zeroed();
+        sa.sin6_family = AF_INET6 as sa_family_t;
+        sa.sin6_port = addr.port().to_be();
+        sa.sin6_flowinfo = addr.flowinfo();
+        sa.sin6_addr.s6_addr = addr.ip().octets();
+        sa.sin6_scope_id = addr.scope_id();
+        if bind(fd, &sa as *const sockaddr_in6 as *const sockaddr, mem::size_of::<sockaddr_in6>() as socklen_t) != 0 || listen(fd, 128) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(listener)
+    }
+}
+
+
+/// A listening socket passed in by the service manager
+pub enum ActivatedSocket {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// Take the listening sockets passed in with systemd-style socket activation, i.e. `$LISTEN_FDS` of them for `$LISTEN_PID`,
+/// and their names from `$LISTEN_FDNAMES`, if any.
+///
+/// The variables are removed, so they don't get passed on to children.
+pub fn activated_sockets() -> io::Result<Vec<(ActivatedSocket, Option<String>)>> {
+    const SD_LISTEN_FDS_START: c_int = 3;
+
+    let pid = env::var("LISTEN_PID").ok();
+    let fds = env::var("LISTEN_FDS").ok();
+    let names = env::var("LISTEN_FDNAMES").ok();
+    for var in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(var);
+    }
+    if pid.and_then(|pid| pid.parse().ok()) != Some(process::id()) {
+        return Ok(vec![]);
+    }
+
+    let fds = fds.and_then(|fds| fds.parse().ok()).unwrap_or(0);
+    let mut names = names.iter().flat_map(|names| names.split(':')).map(|name| Some(name.to_string()).filter(|name| !name.is_empty()));
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + fds)
+        .map(|fd| unsafe {
+            fcntl(fd, F_SETFD, FD_CLOEXEC);
+
+            let mut tp: c_int = 0;
+            let mut tp_len = mem::size_of::<c_int>() as socklen_t;
+            let mut addr: sockaddr_storage = mem::zeroed();
+            let mut addr_len = mem::size_of::<sockaddr_storage>() as socklen_t;
+            if getsockopt(fd, SOL_SOCKET, SO_TYPE, &mut tp as *mut c_int as *mut c_void, &mut tp_len) != 0 ||
+               getsockname(fd, &mut addr as *mut sockaddr_storage as *mut sockaddr, &mut addr_len) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let socket = match (tp, addr.ss_family as c_int) {
+                (SOCK_STREAM, AF_INET | AF_INET6) => ActivatedSocket::Tcp(TcpListener::from_raw_fd(fd)),
+                (SOCK_STREAM, AF_UNIX) => ActivatedSocket::Unix(UnixListener::from_raw_fd(fd)),
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("file descriptor {} not a TCP or Unix domain stream socket", fd))),
+            };
+            Ok((socket, names.next().flatten()))
+        })
+        .collect()
+}
+
+/// Tell the service manager listening on `$NOTIFY_SOCKET`, if any, about `state`, like `sd_notify()`
+pub fn sd_notify(state: &str) -> io::Result<()> {
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+
+    let socket = UnixDatagram::unbound()?;
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::linux::net::SocketAddrExt;
+        use std::os::unix::net::SocketAddr as UnixSocketAddr;
+
+        if let Some(name) = path.as_bytes().strip_prefix(b"@") {
+            return socket.send_to_addr(state.as_bytes(), &UnixSocketAddr::from_abstract_name(name)?).map(|_| ());
+        }
+    }
+    socket.send_to(state.as_bytes(), path).map(|_| ())
+}
+
+/// How often the service manager expects `WATCHDOG=1`, if at all, per `$WATCHDOG_USEC` for `$WATCHDOG_PID`, like `sd_watchdog_enabled()`
+pub fn sd_watchdog_interval() -> Option<Duration> {
+    if env::var("WATCHDOG_PID").ok().map(|pid| pid.parse().ok() != Some(process::id())).unwrap_or(false) {
+        return None;
+    }
+    env::var("WATCHDOG_USEC").ok().and_then(|usec| usec.parse().ok()).filter(|&usec| usec != 0).map(Duration::from_micros)
+}
+
+
+/// Whether we're running as root
+pub fn running_as_root() -> bool {
+    unsafe { geteuid() == 0 }
+}
+
+/// `chroot()` into `root`, if any, hand `owned` and everything under it over to `user` and `group`, and switch to them.
+///
+/// `user` and `group` are names or IDs; `user` without `group` switches to its primary and supplementary groups, too.
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>, root: Option<&Path>, owned: &Path) -> io::Result<()> {
+    // Look everything up before chroot() hides /etc/passwd and /etc/group
+    let user = user.map(lookup_user).transpose()?;
+    let gid = match (group, user.as_ref()) {
+        (Some(group), _) => Some(lookup_group(group)?),
+        (None, Some(&(_, Some((gid, _))))) => Some(gid),
+        (None, Some(&(uid, None))) => return Err(io::Error::new(io::ErrorKind::NotFound, format!("user {} has no passwd entry to take the group from", uid))),
+        (None, None) => None,
+    };
+    let uid = user.as_ref().map(|&(uid, _)| uid);
+
+    if let Some(root) = root {
+        let root = CString::new(root.as_os_str().as_bytes())?;
+        if unsafe { chroot(root.as_ptr()) } != 0 || unsafe { chdir(b"/\0".as_ptr() as *const c_char) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    if uid.is_some() || gid.is_some() {
+        for entry in WalkDir::new(owned).into_iter().flatten() {
+            lchown(entry.path(), uid, gid)?;
+        }
+    }
+
+    if let Some(gid) = gid {
+        let groups_set = match user {
+            Some((_, Some((_, ref name)))) if group.is_none() => unsafe { initgroups(name.as_ptr(), gid as _) },
+            _ => unsafe { setgroups(1, &gid) },
+        };
+        if groups_set != 0 || unsafe { setgid(gid) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    if let Some(uid) = uid {
+        if unsafe { setuid(uid) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// The UID of `user`, and its primary GID and name, if it has a passwd entry; IDs don't have to
+fn lookup_user(user: &str) -> io::Result<(uid_t, Option<(gid_t, CString)>)> {
+    let name = CString::new(user)?;
+    let id = user.parse::<uid_t>().ok();
+
+    let mut entry: passwd = unsafe { mem::zeroed() };
+    let mut buf = vec![0 as c_char; 64 * 1024];
+    let mut result = ptr::null_mut();
+    let err = match id {
+        Some(id) => unsafe { getpwuid_r(id, &mut entry, buf.as_mut_ptr(), buf.len(), &mut result) },
+        None => unsafe { getpwnam_r(name.as_ptr(), &mut entry, buf.as_mut_ptr(), buf.len(), &mut result) },
+    };
+    match (result.is_null(), id) {
+        (false, _) => Ok((entry.pw_uid, Some((entry.pw_gid, unsafe { CStr::from_ptr(entry.pw_name) }.to_owned())))),
+        (true, Some(id)) => Ok((id, None)),
+        (true, None) if err != 0 => Err(io::Error::from_raw_os_error(err)),
+        (true, None) => Err(io::Error::new(io::ErrorKind::NotFound, format!("no user {}", user))),
+    }
+}
+
+/// The GID of `group`
+fn lookup_group(group: &str) -> io::Result<gid_t> {
+    if let Ok(id) = group.parse() {
+        return Ok(id);
+    }
+    let name = CString::new(group)?;
+
+    let mut entry: libc::group = unsafe { mem::zeroed() };
+    let mut buf = vec![0 as c_char; 64 * 1024];
+    let mut result = ptr::null_mut();
+    let err = unsafe { getgrnam_r(name.as_ptr(), &mut entry, buf.as_mut_ptr(), buf.len(), &mut result) };
+    if !result.is_null() {
+        Ok(entry.gr_gid)
+    } else if err != 0 {
+        Err(io::Error::from_raw_os_error(err))
+    } else {
+        Err(io::Error::new(io::ErrorKind::NotFound, format!("no group {}", group)))
+    }
+}
@@ -4,7 +4,10 @@ use std::os::windows::io::AsRawHandle;
 use std::os::windows::fs::MetadataExt;
 use std::os::windows::ffi::OsStrExt;
 use std::fs::{Metadata, File};
+use std::net::{SocketAddr, TcpListener};
+use std::time::Duration;
 use std::path::Path;
+use std::io;
 
 
 /// Get windows-style attributes for the specified file
@@ -79,3 +82,56 @@ fn ms_to_FILETIME(ms: u64) -> FILETIME {
         dwHighDateTime: (ft >> 32) as u32,
     }
 }
+
+
+/// There's no SIGHUP on Windows, so `f` is never called
+pub fn on_sighup<F: FnMut() + Send + 'static>(_: F) -> io::Result<()> {
+    Ok(())
+}
+
+/// There's no SIGTERM on Windows, so `f` is never called
+pub fn on_sigterm<F: FnMut() + Send + 'static>(_: F) -> io::Result<()> {
+    Ok(())
+}
+
+
+/// Bind a listening TCP socket to `addr`;
+/// IPv6 sockets only accept IPv6 connections by default here, so IPv4 ones can be bound to the same port alongside them
+pub fn tcp_listener(addr: SocketAddr) -> io::Result<TcpListener> {
+    TcpListener::bind(addr)
+}
+
+
+/// A listening socket passed in by the service manager
+pub enum ActivatedSocket {
+    Tcp(TcpListener),
+}
+
+/// There's no socket activation on Windows, so there are never any
+pub fn activated_sockets() -> io::Result<Vec<(ActivatedSocket, Option<String>)>> {
+    Ok(vec![])
+}
+
+/// There's no `$NOTIFY_SOCKET` on Windows, so this does nothing
+pub fn sd_notify(_: &str) -> io::Result<()> {
+    Ok(())
+}
+
+/// There's no service manager watchdog on Windows
+pub fn sd_watchdog_interval() -> Option<Duration> {
+    None
+}
+
+
+/// There's no root on Windows
+pub fn running_as_root() -> bool {
+    false
+}
+
+/// Switching users isn't supported on Windows, so this fails if there's anything to do
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>, root: Option<&Path>, _: &Path) -> io::Result<()> {
+    if user.is_some() || group.is_some() || root.is_some() {
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "not supported on this platform"));
+    }
+    Ok(())
+}